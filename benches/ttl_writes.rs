@@ -0,0 +1,53 @@
+//! Measures the throughput of `set` with an expiry time for 1, 2, 4 and more
+//! writer threads. Run with `cargo bench --bench ttl_writes`.
+//!
+//! Writers only contend for locks when they run in parallel, so the numbers
+//! say nothing about how TTL writes scale for thread counts above the number
+//! of cores. Those rows are marked, and on a single core every row past the
+//! first only measures the cost of switching between threads.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cornerstore::CornerStore;
+
+const WRITES_PER_THREAD: usize = 200_000;
+
+fn run(threads: usize) -> f64 {
+    let store = CornerStore::new();
+    let start = Instant::now();
+
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let expiry = Instant::now() + Duration::from_secs(60);
+                for i in 0..WRITES_PER_THREAD {
+                    let key = ((t * WRITES_PER_THREAD + i) as u64).to_le_bytes();
+                    // vary the expiry so that writes don't all land on one entry of the index
+                    let expiry = expiry + Duration::from_micros(i as u64);
+                    store.set(&key, b"value", Some(expiry)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    (threads * WRITES_PER_THREAD) as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let cores = num_cpus::get();
+    let max_threads = (cores * 2).max(8);
+
+    println!("{} cores", cores);
+    println!("{:>8} {:>16}", "threads", "writes/sec");
+    let mut threads = 1;
+    while threads <= max_threads {
+        let note = if threads > cores { "  (more threads than cores)" } else { "" };
+        println!("{:>8} {:>16.0}{}", threads, run(threads), note);
+        threads *= 2;
+    }
+}
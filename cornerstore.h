@@ -0,0 +1,102 @@
+#ifndef CORNERSTORE_H
+#define CORNERSTORE_H
+
+/* Generated by cbindgen from src/ffi.rs. Do not edit by hand. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+// Indicates that the function returned successfully
+#define CNR_OK 0
+
+// A thread-safe store for perishable items.
+//
+// Keys and values are untyped byte-streams of arbitrary length
+//
+// A CornerStore is a handle. Cloning it is cheap and every clone refers
+// to the same underlying data, so clones can be handed out to threads
+// that read and write concurrently.
+typedef struct CornerStore CornerStore;
+
+// Create an empty, in-process cache. Returns a pointer
+// to the new instance, which must be released with [`cnr_free`].
+struct CornerStore *cnr_init(void);
+
+// Release a cache created by [`cnr_init`]. Passing a null pointer is a no-op.
+//
+// # Safety
+//
+// `store` must be null or a pointer returned by [`cnr_init`] that has not
+// already been freed.
+void cnr_free(struct CornerStore *store);
+
+// Sets key to value, overwriting any previous value.
+//
+// If `ttl_ms` is not null, it points to the number of milliseconds that the
+// key/value pair remains fresh for. A null `ttl_ms` stores an item that does
+// not expire.
+//
+// # Safety
+//
+// `store` must be a live pointer returned by [`cnr_init`]. `key` must be valid
+// for reads of `key_len` bytes, and `val` for reads of `val_len` bytes. `val`
+// may only be null when `val_len` is 0.
+ptrdiff_t cnr_set(struct CornerStore *store,
+                  const uint8_t *key,
+                  size_t key_len,
+                  const uint8_t *val,
+                  size_t val_len,
+                  const int64_t *ttl_ms);
+
+// Retrieve the value stored at key, but only if it has not expired.
+//
+// On success, `*val_out` points to a newly allocated copy of the value and
+// `*val_len_out` holds its length. The buffer must be released with
+// [`cnr_buf_free`]. When the key is absent, `ENOENT` is returned and the
+// output parameters are left untouched.
+//
+// # Safety
+//
+// `store` must be a live pointer returned by [`cnr_init`]. `key` must be valid
+// for reads of `key_len` bytes. `val_out` and `val_len_out` must be valid for
+// writes.
+ptrdiff_t cnr_get(const struct CornerStore *store,
+                  const uint8_t *key,
+                  size_t key_len,
+                  uint8_t **val_out,
+                  size_t *val_len_out);
+
+// Release a buffer returned by [`cnr_get`]. Passing a null pointer is a no-op.
+//
+// # Safety
+//
+// `buf` must be null or a buffer returned by [`cnr_get`] that has not already
+// been freed, and `len` must be the length reported alongside it.
+void cnr_buf_free(uint8_t *buf, size_t len);
+
+// Removes the key/value pair from the store. Removing a key that is
+// not present is not an error.
+//
+// # Safety
+//
+// `store` must be a live pointer returned by [`cnr_init`]. `key` must be valid
+// for reads of `key_len` bytes.
+ptrdiff_t cnr_remove(struct CornerStore *store, const uint8_t *key, size_t key_len);
+
+// Remove any expired perishable items from the store
+//
+// # Safety
+//
+// `store` must be a live pointer returned by [`cnr_init`].
+ptrdiff_t cnr_evict(struct CornerStore *store);
+
+// Makes the store usable again after a thread panicked while writing to it,
+// discarding any data that may have been left half-written. When the
+// discarded data can't be logged as removed, `ENOTRECOVERABLE` is returned.
+//
+// # Safety
+//
+// `store` must be a live pointer returned by [`cnr_init`].
+ptrdiff_t cnr_recover(struct CornerStore *store);
+
+#endif  /* CORNERSTORE_H */
@@ -0,0 +1,675 @@
+//! Logging writes as they happen, so that they survive a restart
+//!
+//! The log is a header followed by a record for each write. Integers are
+//! little-endian.
+//!
+//! ```text
+//! header = "CNRLOG\0\0" version:u32
+//! record = op:u8 len:u64 payload crc:u32
+//! ```
+//!
+//! The CRC-32 covers the op, length and payload. Sets carry the pair as a
+//! snapshot record, schedules carry a pair's new lifetimes the same way but
+//! with an empty value, and removes carry the key.
+//!
+//! Every record says what a key's value or lifetimes became, rather than how
+//! they changed, so replaying a log on top of a snapshot that already
+//! includes some of its records gives the same result. Compaction relies on
+//! that: it starts collecting new records, saves a snapshot, then replaces
+//! the log with the records collected while the snapshot was saved.
+
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::clock::Epoch;
+use crate::snapshot::{self, Crc32, Record};
+use crate::{BoH, CornerStore, Error, Result};
+
+/// Identifies log files
+const MAGIC: &[u8; 8] = b"CNRLOG\0\0";
+
+/// Changes whenever the format does. Logs in other formats are refused.
+const VERSION: u32 = 1;
+
+const HEADER_LEN: u64 = 12;
+
+/// Bytes in a record besides its payload
+const RECORD_OVERHEAD: u64 = 13;
+
+/// Record ops
+const SET: u8 = 1;
+const REMOVE: u8 = 2;
+const SCHEDULE: u8 = 3;
+
+/// Logs are compacted once they pass this size, unless
+/// [`Builder::compact_log_after`](crate::Builder::compact_log_after) says otherwise
+pub(crate) const DEFAULT_COMPACT_AFTER: u64 = 64 * 1024 * 1024;
+
+/// When writes to the append-only log are flushed to disk. See
+/// [`Builder::append_log`](crate::Builder::append_log).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fsync {
+    /// After every write, before the write returns. The slowest, and the only
+    /// policy that keeps every write through a power cut.
+    Always,
+
+    /// Once a second, from a background thread. A power cut loses about the
+    /// last second of writes.
+    #[default]
+    EverySec,
+
+    /// Whenever the operating system gets round to it. Writes survive the
+    /// process crashing, but not the machine.
+    No,
+}
+
+/// How a store's append-only log is set up
+#[derive(Debug, Clone)]
+pub(crate) struct LogConfig {
+    pub(crate) path: PathBuf,
+    pub(crate) fsync: Fsync,
+    pub(crate) compact_after: u64,
+}
+
+impl LogConfig {
+    /// Where compaction saves the snapshot that the log is replayed on top of
+    fn snapshot_path(&self) -> PathBuf {
+        snapshot::with_suffix(&self.path, ".snapshot")
+    }
+}
+
+/// Handle to the open log, and to the thread that syncs and compacts it.
+/// Like the reaper, the thread only holds a weak reference to the store.
+#[derive(Debug)]
+pub(crate) struct Aof {
+    config: LogConfig,
+    log: Mutex<Log>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Debug)]
+struct Log {
+    /// Opened for appending
+    file: File,
+    /// Length of the file
+    len: u64,
+    /// Length of the file just after it was last compacted
+    base: u64,
+    /// Whether there are writes that haven't been synced yet
+    dirty: bool,
+    /// Whether a compaction has been asked for, or is running
+    compacting: bool,
+    /// Records written since a compaction started, to begin the new log with
+    pending: Option<Vec<u8>>,
+    /// Asks the thread to compact the log. Dropping it lets the thread exit.
+    compact: Option<Sender<()>>,
+}
+
+impl Aof {
+    /// Loads the snapshot and replays the log into `store`, which must not
+    /// have a log yet, then starts logging its writes.
+    pub(crate) fn open(store: &CornerStore, config: LogConfig) -> Result<()> {
+        match store.load_snapshot(config.snapshot_path()) {
+            Err(Error::Io(err)) if err.kind() == io::ErrorKind::NotFound => {}
+            loaded => {
+                loaded?;
+            }
+        }
+
+        let len = match replay(store, &config.path)? {
+            Some(len) => {
+                let file = OpenOptions::new().append(true).open(&config.path)?;
+                // drops a record that was only partly written before a crash
+                if file.metadata()?.len() != len {
+                    file.set_len(len)?;
+                    file.sync_all()?;
+                }
+                len
+            }
+            None => {
+                create(&config.path, &[])?;
+                HEADER_LEN
+            }
+        };
+        let file = OpenOptions::new().append(true).open(&config.path)?;
+
+        let (compact, requests) = mpsc::channel();
+        let thread = {
+            let boh = Arc::downgrade(&store.0);
+            thread::Builder::new()
+                .name("cornerstore-aof".to_string())
+                .spawn(move || run(boh, requests))
+                .expect("failed to spawn append-only log thread")
+        };
+
+        let aof = Aof {
+            config,
+            log: Mutex::new(Log {
+                file,
+                len,
+                base: len,
+                dirty: false,
+                compacting: false,
+                pending: None,
+                compact: Some(compact),
+            }),
+            thread: Mutex::new(Some(thread)),
+        };
+        let _ = store.0.aof.set(aof);
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Log> {
+        self.log.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Logs that a pair was stored
+    pub(crate) fn set(&self, record: &Record) -> Result<()> {
+        self.append(SET, |payload| record.write(payload))
+    }
+
+    /// Logs that a pair's lifetimes changed. `record` comes from [`Record::schedule`].
+    pub(crate) fn schedule(&self, record: &Record) -> Result<()> {
+        self.append(SCHEDULE, |payload| record.write(payload))
+    }
+
+    /// Logs that the pair at key was removed
+    pub(crate) fn remove(&self, key: &[u8]) -> Result<()> {
+        self.append(REMOVE, |payload| payload.write_all(key))
+    }
+
+    fn append(&self, op: u8, payload: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> Result<()> {
+        let mut record = vec![op; 9];
+        payload(&mut record)?;
+        let len = (record.len() - 9) as u64;
+        record[1..9].copy_from_slice(&len.to_le_bytes());
+        let mut crc = Crc32::new();
+        crc.update(&record);
+        record.extend_from_slice(&crc.finish().to_le_bytes());
+
+        let mut log = self.lock();
+        if let Err(err) = log.file.write_all(&record) {
+            // so that the next record doesn't follow a partial one
+            let _ = log.file.set_len(log.len);
+            return Err(err.into());
+        }
+        log.len += record.len() as u64;
+        if let Some(pending) = &mut log.pending {
+            pending.extend_from_slice(&record);
+        }
+
+        match self.config.fsync {
+            Fsync::Always => log.file.sync_data()?,
+            Fsync::EverySec => log.dirty = true,
+            Fsync::No => {}
+        }
+
+        if !log.compacting && log.len > self.config.compact_after && log.len >= 2 * log.base {
+            if let Some(compact) = &log.compact {
+                let _ = compact.send(());
+                log.compacting = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes writes to disk, without blocking writers while it does
+    fn sync(&self) -> io::Result<()> {
+        let file = {
+            let mut log = self.lock();
+            if !log.dirty {
+                return Ok(());
+            }
+            log.dirty = false;
+            log.file.try_clone()?
+        };
+        file.sync_data()
+    }
+
+    /// Saves a snapshot, then replaces the log with the records written
+    /// while the snapshot was being saved
+    fn compact(&self, boh: &BoH) -> Result<()> {
+        self.lock().pending = Some(Vec::new());
+        let saved = snapshot::save(boh, &self.config.snapshot_path());
+
+        let mut log = self.lock();
+        let pending = log.pending.take().unwrap_or_default();
+        log.compacting = false;
+        // if compaction fails, wait for the log to double before trying again
+        log.base = log.len;
+        saved?;
+
+        create(&self.config.path, &pending)?;
+        log.file = OpenOptions::new().append(true).open(&self.config.path)?;
+        log.len = HEADER_LEN + pending.len() as u64;
+        log.base = log.len;
+        log.dirty = false;
+        Ok(())
+    }
+
+    /// Stops the thread, after it finishes any compaction that it is running.
+    /// The thread holds the store while it compacts, so this is called when
+    /// the last [`CornerStore`] handle is dropped, rather than waiting for the
+    /// store itself to be dropped, which could happen on the thread.
+    pub(crate) fn close(&self) {
+        self.lock().compact.take();
+        let thread = self.thread.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(thread) = thread {
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Drop for Aof {
+    fn drop(&mut self) {
+        self.close();
+        if self.config.fsync == Fsync::EverySec {
+            let _ = self.sync();
+        }
+    }
+}
+
+fn run(boh: Weak<BoH>, requests: Receiver<()>) {
+    loop {
+        let compact = match requests.recv_timeout(Duration::from_secs(1)) {
+            Ok(()) => true,
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let boh = match boh.upgrade() {
+            Some(boh) => boh,
+            None => break,
+        };
+        if let Some(aof) = boh.aof.get() {
+            // errors leave the log as it was, and there is nobody to report them to
+            if compact {
+                let _ = aof.compact(&boh);
+            }
+            if aof.config.fsync == Fsync::EverySec {
+                let _ = aof.sync();
+            }
+        }
+    }
+}
+
+/// Writes a new log holding `records`, then moves it into place
+fn create(path: &Path, records: &[u8]) -> io::Result<()> {
+    let temporary = snapshot::with_suffix(path, ".tmp");
+    let created = (|| {
+        let mut file = File::create(&temporary)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&VERSION.to_le_bytes())?;
+        file.write_all(records)?;
+        file.sync_all()?;
+        fs::rename(&temporary, path)?;
+        snapshot::sync_parent(path)
+    })();
+    if created.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    created
+}
+
+/// Applies the records in the log at `path` to `store`. Returns the length of
+/// the log up to the end of its last whole record, or `None` if there's no
+/// log yet.
+///
+/// A crash can leave the last record partly written. It is ignored, and
+/// recovery continues as if the write had never been made. Damage anywhere
+/// else is reported as [`Error::Corrupt`].
+fn replay(store: &CornerStore, path: &Path) -> Result<Option<u64>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let file_len = file.metadata()?.len();
+    if file_len < HEADER_LEN {
+        // the process stopped while creating the log
+        return Ok(None);
+    }
+
+    let mut input = BufReader::new(file);
+    let mut header = [0; HEADER_LEN as usize];
+    input.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+        return Err(Error::Corrupt("not an append-only log"));
+    }
+    if header[8..] != VERSION.to_le_bytes() {
+        return Err(Error::Corrupt("unsupported append-only log version"));
+    }
+
+    let epoch = Epoch::of(&*store.0.clock);
+    let mut valid = HEADER_LEN;
+    while file_len - valid >= RECORD_OVERHEAD {
+        let mut head = [0; 9];
+        input.read_exact(&mut head)?;
+        let len = u64::from_le_bytes(<[u8; 8]>::try_from(&head[1..]).unwrap());
+        if len > file_len - valid - RECORD_OVERHEAD {
+            // a torn write only leaves part of the last record, so if a whole
+            // record follows, it's the length that was damaged
+            let mut rest = head.to_vec();
+            input.read_to_end(&mut rest)?;
+            if (1..rest.len()).any(|start| is_record(&rest[start..])) {
+                return Err(Error::Corrupt("append-only log is damaged"));
+            }
+            break;
+        }
+
+        let mut payload = Vec::new();
+        (&mut input).take(len).read_to_end(&mut payload)?;
+        let mut crc = [0; 4];
+        input.read_exact(&mut crc)?;
+
+        let mut expected = Crc32::new();
+        expected.update(&head);
+        expected.update(&payload);
+        let end = valid + RECORD_OVERHEAD + len;
+        if expected.finish() != u32::from_le_bytes(crc) {
+            if end == file_len {
+                break;
+            }
+            return Err(Error::Corrupt("append-only log is damaged"));
+        }
+
+        apply(store, head[0], &payload, epoch)?;
+        valid = end;
+    }
+    Ok(Some(valid))
+}
+
+/// Whether `bytes` starts with a whole record whose checksum matches
+fn is_record(bytes: &[u8]) -> bool {
+    if bytes.len() < RECORD_OVERHEAD as usize || ![SET, REMOVE, SCHEDULE].contains(&bytes[0]) {
+        return false;
+    }
+    let len = u64::from_le_bytes(<[u8; 8]>::try_from(&bytes[1..9]).unwrap());
+    if len > (bytes.len() - RECORD_OVERHEAD as usize) as u64 {
+        return false;
+    }
+    let end = 9 + len as usize;
+    let mut crc = Crc32::new();
+    crc.update(&bytes[..end]);
+    crc.finish() == u32::from_le_bytes(<[u8; 4]>::try_from(&bytes[end..end + 4]).unwrap())
+}
+
+fn apply(store: &CornerStore, op: u8, mut payload: &[u8], epoch: Epoch) -> Result<()> {
+    if op == REMOVE {
+        return store.remove(payload);
+    }
+
+    let mut kind = [0];
+    let record = payload
+        .read_exact(&mut kind)
+        .map_err(Error::from)
+        .and_then(|()| Record::read(kind[0], &mut payload))
+        .map_err(|_| Error::Corrupt("append-only log record is malformed"))?;
+    let key = record.key.clone();
+    match (op, record.into_pair(epoch)) {
+        (SET, Some(kv_pair)) => store.insert_pair(kv_pair).map(drop),
+        (SCHEDULE, Some(scheduled)) => {
+            store.reschedule(&key, |kv_pair, _| {
+                kv_pair.expiry = scheduled.expiry;
+                kv_pair.ttl = scheduled.ttl;
+                kv_pair.stale_at = scheduled.stale_at;
+                kv_pair.soft_ttl = scheduled.soft_ttl;
+            })?;
+            Ok(())
+        }
+        // the pair expired while the store was closed
+        (SET, None) | (SCHEDULE, None) => store.remove(&key),
+        _ => Err(Error::Corrupt("unknown append-only log record")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::time::Instant;
+
+    /// An empty directory in the temporary directory that no other test uses
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cornerstore-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn open(path: &Path) -> Result<CornerStore> {
+        CornerStore::builder()
+            .shards(4)
+            .append_log(path)
+            .fsync(Fsync::No)
+            .open()
+    }
+
+    /// Every live pair in the store
+    fn contents(store: &CornerStore) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let (_, keys) = store.scan(0, usize::MAX).unwrap();
+        keys.iter()
+            .filter_map(|key| Some((key.to_vec(), store.get(key).unwrap()?.to_vec())))
+            .collect()
+    }
+
+    #[test]
+    fn test_writes_survive_reopening() {
+        let dir = temp_dir("reopen");
+        let path = dir.join("store.aof");
+        let minute = Duration::from_secs(60);
+
+        let store = CornerStore::builder()
+            .append_log(&path)
+            .fsync(Fsync::Always)
+            .open()
+            .unwrap();
+        store.set(b"greeting", b"hello", None).unwrap();
+        store.set(b"farewell", b"goodbye", None).unwrap();
+        store
+            .set_with_soft_ttl(b"question", b"why?", minute, 2 * minute)
+            .unwrap();
+        store
+            .set(b"answer", b"because", Some(Instant::now() + minute))
+            .unwrap();
+        store.remove(b"farewell").unwrap();
+        store.expire(b"greeting", minute).unwrap();
+        store.persist(b"answer").unwrap();
+        store.invalidate(b"question").unwrap();
+        store
+            .set_with_ttl(b"brief", b"gone soon", Duration::from_millis(50))
+            .unwrap();
+        drop(store);
+
+        std::thread::sleep(Duration::from_millis(100));
+        let store = open(&path).unwrap();
+        assert_eq!(store.len().unwrap(), 3);
+        assert_eq!(&*store.get(b"greeting").unwrap().unwrap(), b"hello");
+        assert!(store.ttl(b"greeting").unwrap().unwrap() > Duration::from_secs(58));
+        assert_eq!(store.ttl(b"answer").unwrap(), None);
+        let entry = store.get_entry(b"question").unwrap().unwrap();
+        assert_eq!(entry.freshness, crate::Freshness::Stale);
+        store.debug_assert_invariants();
+
+        store.clear().unwrap();
+        drop(store);
+        assert!(open(&path).unwrap().is_empty().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_from_logs_cut_short_at_any_point() {
+        let dir = temp_dir("truncated");
+        let path = dir.join("store.aof");
+
+        // the log's length and the store's contents after each write
+        let store = open(&path).unwrap();
+        let mut expected = BTreeMap::new();
+        let mut history = vec![(fs::metadata(&path).unwrap().len(), expected.clone())];
+        for i in 0..60u32 {
+            let key = format!("key:{}", i % 7).into_bytes();
+            if i % 5 == 4 && expected.contains_key(&key) {
+                store.remove(&key).unwrap();
+                expected.remove(&key);
+            } else if i % 5 == 2 && expected.contains_key(&key) {
+                store.expire(&key, Duration::from_secs(600)).unwrap();
+            } else {
+                let value = format!("value:{}", i)
+                    .repeat(i as usize % 4 + 1)
+                    .into_bytes();
+                store.set(&key, &value, None).unwrap();
+                expected.insert(key, value);
+            }
+            history.push((fs::metadata(&path).unwrap().len(), expected.clone()));
+        }
+        drop(store);
+        let log = fs::read(&path).unwrap();
+        assert_eq!(log.len() as u64, history.last().unwrap().0);
+
+        // every offset in the first few records, and pseudo-random ones after that
+        let mut offsets: Vec<usize> = (0..history[3].0 as usize).collect();
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        for _ in 0..40 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            offsets.push(seed as usize % log.len());
+        }
+
+        let cut = dir.join("cut.aof");
+        for offset in offsets {
+            fs::write(&cut, &log[..offset]).unwrap();
+            let store = open(&cut).unwrap();
+            let (_, expected) = history
+                .iter()
+                .rev()
+                .find(|(len, _)| *len <= offset as u64)
+                .unwrap_or(&history[0]);
+            assert_eq!(&contents(&store), expected, "log cut at {}", offset);
+
+            // later writes follow the last whole record
+            store.set(b"after", b"recovery", None).unwrap();
+            drop(store);
+            let mut expected = expected.clone();
+            expected.insert(b"after".to_vec(), b"recovery".to_vec());
+            assert_eq!(
+                contents(&open(&cut).unwrap()),
+                expected,
+                "log cut at {}",
+                offset
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_damage_before_the_last_record_is_refused() {
+        let dir = temp_dir("damaged");
+        let path = dir.join("store.aof");
+        let store = open(&path).unwrap();
+        store.set(b"greeting", b"hello", None).unwrap();
+        store.set(b"farewell", b"goodbye", None).unwrap();
+        drop(store);
+
+        let mut log = fs::read(&path).unwrap();
+        log[HEADER_LEN as usize + 12] ^= 0x10;
+        fs::write(&path, &log).unwrap();
+        assert!(matches!(open(&path), Err(Error::Corrupt(_))));
+
+        fs::write(&path, b"CNRSNAP\0\x01\0\0\0").unwrap();
+        assert!(matches!(open(&path), Err(Error::Corrupt(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_damaged_lengths_before_the_last_record_are_refused() {
+        let dir = temp_dir("damaged-length");
+        let path = dir.join("store.aof");
+        let store = open(&path).unwrap();
+        store.set(b"greeting", b"hello", None).unwrap();
+        store.set(b"farewell", b"goodbye", None).unwrap();
+        store.set(b"question", b"why?", None).unwrap();
+        drop(store);
+
+        // the first record's length now runs past the end of the log
+        let mut log = fs::read(&path).unwrap();
+        log[HEADER_LEN as usize + 8] ^= 0x80;
+        fs::write(&path, &log).unwrap();
+        assert!(matches!(open(&path), Err(Error::Corrupt(_))));
+        assert_eq!(fs::read(&path).unwrap(), log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compaction_keeps_every_write() {
+        let dir = temp_dir("compaction");
+        let path = dir.join("store.aof");
+        let store = CornerStore::builder()
+            .append_log(&path)
+            .fsync(Fsync::No)
+            .compact_log_after(4096)
+            .open()
+            .unwrap();
+
+        let mut expected = BTreeMap::new();
+        let mut write = |i: u32| {
+            let key = format!("key:{}", i % 10).into_bytes();
+            let value = format!("value:{}", i).into_bytes();
+            store.set(&key, &value, None).unwrap();
+            expected.insert(key, value);
+        };
+        for i in 0..200 {
+            write(i);
+        }
+        // dropping the last handle waits for the compaction that the writes
+        // asked for, so the store can be reopened straight away
+        drop(store);
+        let mut files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["store.aof", "store.aof.snapshot"]);
+
+        let store = open(&path).unwrap();
+        assert_eq!(contents(&store), expected);
+        store.set(b"after", b"compaction", None).unwrap();
+        expected.insert(b"after".to_vec(), b"compaction".to_vec());
+        drop(store);
+
+        assert_eq!(contents(&open(&path).unwrap()), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pairs_discarded_by_recovery_stay_removed() {
+        let dir = temp_dir("recover");
+        let path = dir.join("store.aof");
+        let store = open(&path).unwrap();
+        for i in 0..20u32 {
+            store.set(&i.to_le_bytes(), b"value", None).unwrap();
+        }
+
+        let shard = store.0.shard(store.0.hidden_key(&0u32.to_le_bytes()));
+        let handle = store.clone();
+        let _ = std::thread::spawn(move || {
+            let _lock = handle.0.data[shard].write().unwrap();
+            panic!("writer panicked while holding the lock");
+        })
+        .join();
+        assert_eq!(store.recover().unwrap(), 1);
+        let kept = contents(&store);
+        assert!(kept.len() < 20);
+        drop(store);
+
+        assert_eq!(contents(&open(&path).unwrap()), kept);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,675 @@
+//! The memcached binary protocol, which older clients use
+//!
+//! See https://github.com/memcached/memcached/wiki/BinaryProtocolRevamped
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::time::Duration;
+
+use cornerstore::Error;
+
+use crate::memcache::{stat_lines, MAX_KEY_LEN};
+use crate::server::{Counted, Deletion, Expiry, Item, Mode, Server, Stats, Storage};
+
+/// First byte of every request
+pub const REQUEST: u8 = 0x80;
+
+/// First byte of every response
+const RESPONSE: u8 = 0x81;
+
+const HEADER_LEN: usize = 24;
+
+/// Room for extras and a key in a request body, on top of the largest value
+const MAX_OVERHEAD: usize = u8::MAX as usize + MAX_KEY_LEN;
+
+mod opcode {
+    pub const GET: u8 = 0x00;
+    pub const SET: u8 = 0x01;
+    pub const ADD: u8 = 0x02;
+    pub const REPLACE: u8 = 0x03;
+    pub const DELETE: u8 = 0x04;
+    pub const INCREMENT: u8 = 0x05;
+    pub const DECREMENT: u8 = 0x06;
+    pub const QUIT: u8 = 0x07;
+    pub const FLUSH: u8 = 0x08;
+    pub const GETQ: u8 = 0x09;
+    pub const NOOP: u8 = 0x0a;
+    pub const VERSION: u8 = 0x0b;
+    pub const GETK: u8 = 0x0c;
+    pub const GETKQ: u8 = 0x0d;
+    pub const APPEND: u8 = 0x0e;
+    pub const PREPEND: u8 = 0x0f;
+    pub const STAT: u8 = 0x10;
+    pub const SETQ: u8 = 0x11;
+    pub const ADDQ: u8 = 0x12;
+    pub const REPLACEQ: u8 = 0x13;
+    pub const DELETEQ: u8 = 0x14;
+    pub const INCREMENTQ: u8 = 0x15;
+    pub const DECREMENTQ: u8 = 0x16;
+    pub const QUITQ: u8 = 0x17;
+    pub const FLUSHQ: u8 = 0x18;
+    pub const APPENDQ: u8 = 0x19;
+    pub const PREPENDQ: u8 = 0x1a;
+    pub const TOUCH: u8 = 0x1c;
+    pub const GAT: u8 = 0x1d;
+    pub const GATQ: u8 = 0x1e;
+    pub const GATK: u8 = 0x23;
+    pub const GATKQ: u8 = 0x24;
+
+    /// The command that `opcode` is the quiet version of, if it is one
+    pub fn loud(opcode: u8) -> Option<u8> {
+        Some(match opcode {
+            GETQ => GET,
+            GETKQ => GETK,
+            SETQ => SET,
+            ADDQ => ADD,
+            REPLACEQ => REPLACE,
+            DELETEQ => DELETE,
+            INCREMENTQ => INCREMENT,
+            DECREMENTQ => DECREMENT,
+            QUITQ => QUIT,
+            FLUSHQ => FLUSH,
+            APPENDQ => APPEND,
+            PREPENDQ => PREPEND,
+            GATQ => GAT,
+            GATKQ => GATK,
+            _ => return None,
+        })
+    }
+}
+
+/// Response statuses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    NoError = 0x0000,
+    KeyNotFound = 0x0001,
+    KeyExists = 0x0002,
+    ValueTooLarge = 0x0003,
+    InvalidArguments = 0x0004,
+    NotStored = 0x0005,
+    NonNumeric = 0x0006,
+    UnknownCommand = 0x0081,
+    OutOfMemory = 0x0082,
+    InternalError = 0x0084,
+}
+
+impl Status {
+    /// Sent as the value of error responses, as memcached does
+    fn message(self) -> &'static str {
+        match self {
+            Status::NoError => "",
+            Status::KeyNotFound => "Not found",
+            Status::KeyExists => "Data exists for key.",
+            Status::ValueTooLarge => "Too large.",
+            Status::InvalidArguments => "Invalid arguments",
+            Status::NotStored => "Not stored.",
+            Status::NonNumeric => "Non-numeric server-side value for incr or decr",
+            Status::UnknownCommand => "Unknown command",
+            Status::OutOfMemory => "Out of memory",
+            Status::InternalError => "Internal error",
+        }
+    }
+}
+
+impl From<&Error> for Status {
+    fn from(err: &Error) -> Status {
+        match err {
+            Error::OutOfMemory | Error::CapacityExceeded => Status::OutOfMemory,
+            Error::ValueTooLarge => Status::ValueTooLarge,
+            _ => Status::InternalError,
+        }
+    }
+}
+
+struct Request {
+    opcode: u8,
+    opaque: u32,
+    cas: u64,
+    extras: Vec<u8>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl Request {
+    /// Checks that the request carries what its command needs: exactly
+    /// `extras` bytes of extras, a key if and only if `key`, and a value only
+    /// if `value`.
+    fn has(&self, extras: usize, key: bool, value: bool) -> bool {
+        self.extras.len() == extras
+            && self.key.is_empty() != key
+            && self.key.len() <= MAX_KEY_LEN
+            && (value || self.value.is_empty())
+    }
+
+    fn extra_u32(&self, at: usize) -> u32 {
+        u32::from_be_bytes([
+            self.extras[at],
+            self.extras[at + 1],
+            self.extras[at + 2],
+            self.extras[at + 3],
+        ])
+    }
+
+    fn extra_u64(&self, at: usize) -> u64 {
+        (u64::from(self.extra_u32(at)) << 32) | u64::from(self.extra_u32(at + 4))
+    }
+
+    fn reply(&self, status: Status) -> Response {
+        Response {
+            opcode: self.opcode,
+            status,
+            opaque: self.opaque,
+            cas: 0,
+            extras: Vec::new(),
+            key: Vec::new(),
+            value: status.message().as_bytes().to_vec(),
+        }
+    }
+}
+
+struct Response {
+    opcode: u8,
+    status: Status,
+    opaque: u32,
+    cas: u64,
+    extras: Vec<u8>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl Response {
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let body_len = self.extras.len() + self.key.len() + self.value.len();
+        let mut header = [0; HEADER_LEN];
+        header[0] = RESPONSE;
+        header[1] = self.opcode;
+        header[2..4].copy_from_slice(&(self.key.len() as u16).to_be_bytes());
+        header[4] = self.extras.len() as u8;
+        header[6..8].copy_from_slice(&(self.status as u16).to_be_bytes());
+        header[8..12].copy_from_slice(&(body_len as u32).to_be_bytes());
+        header[12..16].copy_from_slice(&self.opaque.to_be_bytes());
+        header[16..24].copy_from_slice(&self.cas.to_be_bytes());
+
+        writer.write_all(&header)?;
+        writer.write_all(&self.extras)?;
+        writer.write_all(&self.key)?;
+        writer.write_all(&self.value)
+    }
+}
+
+/// Answers requests from `reader` on `writer` until the client quits or
+/// disconnects. Like the text protocol, replies are flushed whenever there
+/// are no more pipelined requests waiting to be read.
+pub fn serve(
+    server: &Server,
+    mut reader: BufReader<impl Read>,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    loop {
+        if reader.fill_buf()?.is_empty() {
+            return writer.flush();
+        }
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        if header[0] != REQUEST {
+            // there's no telling where the next request starts
+            return writer.flush();
+        }
+
+        let key_len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let extras_len = header[4] as usize;
+        let body_len = u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize;
+        let mut request = Request {
+            opcode: header[1],
+            opaque: u32::from_be_bytes([header[12], header[13], header[14], header[15]]),
+            cas: u64::from_be_bytes([
+                header[16], header[17], header[18], header[19], header[20], header[21], header[22],
+                header[23],
+            ]),
+            extras: Vec::new(),
+            key: Vec::new(),
+            value: Vec::new(),
+        };
+
+        let (responses, next) = if body_len > server.max_item_size + MAX_OVERHEAD {
+            let skipped = io::copy(&mut (&mut reader).take(body_len as u64), &mut io::sink())?;
+            if skipped < body_len as u64 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            (vec![request.reply(Status::ValueTooLarge)], Next::Continue)
+        } else {
+            let mut body = vec![0; body_len];
+            reader.read_exact(&mut body)?;
+            if extras_len + key_len > body_len {
+                (
+                    vec![request.reply(Status::InvalidArguments)],
+                    Next::Continue,
+                )
+            } else {
+                request.value = body.split_off(extras_len + key_len);
+                request.key = body.split_off(extras_len);
+                request.extras = body;
+                execute(server, &request)
+            }
+        };
+
+        for response in responses {
+            response.write(&mut writer)?;
+        }
+        if next == Next::Quit {
+            return writer.flush();
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+/// Whether the connection should stay open after a request
+#[derive(Debug, PartialEq, Eq)]
+enum Next {
+    Continue,
+    Quit,
+}
+
+fn execute(server: &Server, request: &Request) -> (Vec<Response>, Next) {
+    let (opcode, quiet) = match opcode::loud(request.opcode) {
+        Some(opcode) => (opcode, true),
+        None => (request.opcode, false),
+    };
+    let (response, next) = match run(server, opcode, request) {
+        Ok((response, next)) => (response, next),
+        Err(err) => {
+            let mut response = request.reply(Status::from(&err));
+            if response.status == Status::InternalError {
+                response.value = err.to_string().into_bytes();
+            }
+            (response, Next::Continue)
+        }
+    };
+
+    // quiet gets only answer hits, other quiet commands only answer failures
+    let silent = match opcode {
+        opcode::GET | opcode::GETK | opcode::GAT | opcode::GATK => {
+            response.status == Status::KeyNotFound
+        }
+        _ => response.status == Status::NoError,
+    };
+    if quiet && silent {
+        return (Vec::new(), next);
+    }
+
+    if opcode == opcode::STAT && response.status == Status::NoError {
+        let mut responses = Vec::new();
+        if let Ok(lines) = stat_lines(server) {
+            for (name, value) in lines {
+                responses.push(Response {
+                    key: name.as_bytes().to_vec(),
+                    value: value.into_bytes(),
+                    ..request.reply(Status::NoError)
+                });
+            }
+        }
+        // an empty stat ends the list
+        responses.push(response);
+        return (responses, next);
+    }
+    (vec![response], next)
+}
+
+/// Carries out one command, given its loud opcode
+fn run(server: &Server, opcode: u8, request: &Request) -> Result<(Response, Next), Error> {
+    let response = match opcode {
+        opcode::GET | opcode::GETK if request.has(0, true, false) => get(server, request, opcode)?,
+        opcode::GAT | opcode::GATK if request.has(4, true, false) => {
+            let expiry = Expiry::from_exptime(i64::from(request.extra_u32(0)));
+            server.touch(&request.key, expiry)?;
+            get(server, request, opcode)?
+        }
+        opcode::TOUCH if request.has(4, true, false) => {
+            let expiry = Expiry::from_exptime(i64::from(request.extra_u32(0)));
+            match server.touch(&request.key, expiry)? {
+                true => request.reply(Status::NoError),
+                false => request.reply(Status::KeyNotFound),
+            }
+        }
+        opcode::SET | opcode::ADD | opcode::REPLACE if request.has(8, true, true) => {
+            let mode = match (opcode, request.cas) {
+                (opcode::ADD, 0) => Mode::Add,
+                (opcode::ADD, _) => {
+                    return Ok((request.reply(Status::InvalidArguments), Next::Continue))
+                }
+                (opcode::REPLACE, 0) => Mode::Replace,
+                (_, 0) => Mode::Set,
+                (_, cas) => Mode::Cas(cas),
+            };
+            let flags = request.extra_u32(0);
+            let expiry = Expiry::from_exptime(i64::from(request.extra_u32(4)));
+            store(server, request, mode, flags, expiry)?
+        }
+        opcode::APPEND | opcode::PREPEND if request.has(0, true, true) => {
+            let mode = if opcode == opcode::APPEND {
+                Mode::Append
+            } else {
+                Mode::Prepend
+            };
+            store(server, request, mode, 0, Expiry::Never)?
+        }
+        opcode::DELETE if request.has(0, true, false) => {
+            let cas = Some(request.cas).filter(|&cas| cas != 0);
+            match server.delete(&request.key, cas)? {
+                Deletion::Deleted => request.reply(Status::NoError),
+                Deletion::NotFound => request.reply(Status::KeyNotFound),
+                Deletion::Exists => request.reply(Status::KeyExists),
+            }
+        }
+        opcode::INCREMENT | opcode::DECREMENT if request.has(20, true, false) => {
+            let delta = request.extra_u64(0);
+            let initial = request.extra_u64(8);
+            // an expiration of all ones means that missing counters aren't created
+            let create = match request.extra_u32(16) {
+                u32::MAX => None,
+                exptime => Some((initial, Expiry::from_exptime(i64::from(exptime)))),
+            };
+            match server.count(&request.key, delta, opcode == opcode::INCREMENT, create)? {
+                Counted::Value { value, cas } => Response {
+                    cas,
+                    value: value.to_be_bytes().to_vec(),
+                    ..request.reply(Status::NoError)
+                },
+                Counted::NotFound => request.reply(Status::KeyNotFound),
+                Counted::NotANumber => request.reply(Status::NonNumeric),
+            }
+        }
+        opcode::QUIT if request.has(0, false, false) => {
+            return Ok((request.reply(Status::NoError), Next::Quit));
+        }
+        opcode::FLUSH if request.has(0, false, false) || request.has(4, false, false) => {
+            let delay = if request.extras.is_empty() {
+                0
+            } else {
+                request.extra_u32(0)
+            };
+            server.flush_all(Duration::from_secs(u64::from(delay)))?;
+            request.reply(Status::NoError)
+        }
+        opcode::NOOP if request.has(0, false, false) => request.reply(Status::NoError),
+        opcode::VERSION if request.has(0, false, false) => Response {
+            value: env!("CARGO_PKG_VERSION").as_bytes().to_vec(),
+            ..request.reply(Status::NoError)
+        },
+        // memcached's stat groups describe its slab allocator, which has no counterpart here
+        opcode::STAT if request.has(0, false, false) => request.reply(Status::NoError),
+        opcode::STAT => request.reply(Status::KeyNotFound),
+        opcode::GET
+        | opcode::GETK
+        | opcode::GAT
+        | opcode::GATK
+        | opcode::TOUCH
+        | opcode::SET
+        | opcode::ADD
+        | opcode::REPLACE
+        | opcode::APPEND
+        | opcode::PREPEND
+        | opcode::DELETE
+        | opcode::INCREMENT
+        | opcode::DECREMENT
+        | opcode::QUIT
+        | opcode::FLUSH
+        | opcode::NOOP
+        | opcode::VERSION => request.reply(Status::InvalidArguments),
+        _ => request.reply(Status::UnknownCommand),
+    };
+    Ok((response, Next::Continue))
+}
+
+fn get(server: &Server, request: &Request, opcode: u8) -> Result<Response, Error> {
+    Stats::bump(&server.stats.cmd_get);
+    let with_key = opcode == opcode::GETK || opcode == opcode::GATK;
+    let key = if with_key {
+        request.key.clone()
+    } else {
+        Vec::new()
+    };
+
+    let Item { flags, cas, data } = match server.get(&request.key)? {
+        Some(item) => item,
+        None => {
+            Stats::bump(&server.stats.get_misses);
+            return Ok(Response {
+                key,
+                ..request.reply(Status::KeyNotFound)
+            });
+        }
+    };
+    Stats::bump(&server.stats.get_hits);
+    Ok(Response {
+        cas,
+        extras: flags.to_be_bytes().to_vec(),
+        key,
+        value: data,
+        ..request.reply(Status::NoError)
+    })
+}
+
+fn store(
+    server: &Server,
+    request: &Request,
+    mode: Mode,
+    flags: u32,
+    expiry: Expiry,
+) -> Result<Response, Error> {
+    if request.value.len() > server.max_item_size {
+        return Ok(request.reply(Status::ValueTooLarge));
+    }
+    Ok(
+        match server.set(mode, &request.key, flags, expiry, request.value.clone())? {
+            Storage::Stored(cas) => Response {
+                cas,
+                ..request.reply(Status::NoError)
+            },
+            Storage::NotStored if mode == Mode::Add => request.reply(Status::KeyExists),
+            Storage::NotStored if mode == Mode::Replace => request.reply(Status::KeyNotFound),
+            Storage::NotStored => request.reply(Status::NotStored),
+            Storage::Exists => request.reply(Status::KeyExists),
+            Storage::NotFound => request.reply(Status::KeyNotFound),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcache::tests::{exchange, server};
+
+    fn request(opcode: u8, cas: u64, extras: &[u8], key: &[u8], value: &[u8]) -> Vec<u8> {
+        let body_len = extras.len() + key.len() + value.len();
+        let mut out = vec![REQUEST, opcode];
+        out.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        out.extend_from_slice(&[extras.len() as u8, 0, 0, 0]);
+        out.extend_from_slice(&(body_len as u32).to_be_bytes());
+        out.extend_from_slice(&0xcafe_u32.to_be_bytes());
+        out.extend_from_slice(&cas.to_be_bytes());
+        out.extend_from_slice(extras);
+        out.extend_from_slice(key);
+        out.extend_from_slice(value);
+        out
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Parsed {
+        opcode: u8,
+        status: u16,
+        cas: u64,
+        extras: Vec<u8>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    }
+
+    fn responses(mut bytes: &[u8]) -> Vec<Parsed> {
+        let mut parsed = Vec::new();
+        while !bytes.is_empty() {
+            let (header, rest) = bytes.split_at(HEADER_LEN);
+            assert_eq!(header[0], RESPONSE);
+            assert_eq!(&header[12..16], &0xcafe_u32.to_be_bytes());
+            let key_len = u16::from_be_bytes([header[2], header[3]]) as usize;
+            let extras_len = header[4] as usize;
+            let body_len =
+                u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize;
+            let (body, rest) = rest.split_at(body_len);
+            let mut cas = [0; 8];
+            cas.copy_from_slice(&header[16..24]);
+            parsed.push(Parsed {
+                opcode: header[1],
+                status: u16::from_be_bytes([header[6], header[7]]),
+                cas: u64::from_be_bytes(cas),
+                extras: body[..extras_len].to_vec(),
+                key: body[extras_len..extras_len + key_len].to_vec(),
+                value: body[extras_len + key_len..].to_vec(),
+            });
+            bytes = rest;
+        }
+        parsed
+    }
+
+    fn set_extras(flags: u32, exptime: u32) -> Vec<u8> {
+        let mut extras = flags.to_be_bytes().to_vec();
+        extras.extend_from_slice(&exptime.to_be_bytes());
+        extras
+    }
+
+    #[test]
+    fn test_storage_and_retrieval() {
+        let server = server();
+        let input = [
+            request(opcode::SET, 0, &set_extras(42, 0), b"greeting", b"hello"),
+            request(opcode::ADD, 0, &set_extras(0, 0), b"greeting", b"hey"),
+            request(opcode::APPEND, 0, &[], b"greeting", b" world"),
+            request(opcode::GETK, 0, &[], b"greeting", b""),
+            request(opcode::GET, 0, &[], b"missing", b""),
+        ]
+        .concat();
+        let replies = responses(&exchange(&server, &input));
+        assert_eq!(replies.len(), 5);
+
+        assert_eq!(replies[0].status, Status::NoError as u16);
+        assert_eq!(replies[1].status, Status::KeyExists as u16);
+        let cas = server.get(b"greeting").unwrap().unwrap().cas;
+        assert_eq!(replies[2].cas, cas);
+        assert_eq!(
+            replies[3],
+            Parsed {
+                opcode: opcode::GETK,
+                status: 0,
+                cas,
+                extras: 42_u32.to_be_bytes().to_vec(),
+                key: b"greeting".to_vec(),
+                value: b"hello world".to_vec(),
+            }
+        );
+        assert_eq!(replies[4].status, Status::KeyNotFound as u16);
+        assert_eq!(replies[4].value, b"Not found");
+    }
+
+    #[test]
+    fn test_cas_guards_writes_and_deletes() {
+        let server = server();
+        let set = request(opcode::SET, 0, &set_extras(0, 0), b"greeting", b"hello");
+        let cas = responses(&exchange(&server, &set))[0].cas;
+
+        let input = [
+            request(opcode::SET, cas + 1, &set_extras(0, 0), b"greeting", b"hey"),
+            request(opcode::DELETE, cas + 1, &[], b"greeting", b""),
+            request(opcode::SET, cas, &set_extras(0, 0), b"greeting", b"hey"),
+            request(opcode::DELETE, 0, &[], b"greeting", b""),
+            request(opcode::DELETE, 0, &[], b"greeting", b""),
+        ]
+        .concat();
+        let statuses: Vec<_> = responses(&exchange(&server, &input))
+            .iter()
+            .map(|reply| reply.status)
+            .collect();
+        assert_eq!(statuses, [2, 2, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_counters_are_created_unless_told_not_to() {
+        let server = server();
+        let extras = |delta: u64, initial: u64, exptime: u32| {
+            [
+                &delta.to_be_bytes()[..],
+                &initial.to_be_bytes(),
+                &exptime.to_be_bytes(),
+            ]
+            .concat()
+        };
+        let input = [
+            request(opcode::INCREMENT, 0, &extras(1, 5, u32::MAX), b"n", b""),
+            request(opcode::INCREMENT, 0, &extras(1, 5, 0), b"n", b""),
+            request(opcode::INCREMENT, 0, &extras(10, 5, 0), b"n", b""),
+            request(opcode::DECREMENT, 0, &extras(100, 0, 0), b"n", b""),
+        ]
+        .concat();
+        let replies = responses(&exchange(&server, &input));
+        assert_eq!(replies[0].status, Status::KeyNotFound as u16);
+        let values: Vec<_> = replies[1..]
+            .iter()
+            .map(|reply| reply.value.clone())
+            .collect();
+        assert_eq!(
+            values,
+            [
+                5_u64.to_be_bytes(),
+                15_u64.to_be_bytes(),
+                0_u64.to_be_bytes()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quiet_commands_only_answer_when_they_must() {
+        let server = server();
+        let input = [
+            request(opcode::SETQ, 0, &set_extras(0, 0), b"greeting", b"hello"),
+            request(opcode::ADDQ, 0, &set_extras(0, 0), b"greeting", b"hey"),
+            request(opcode::GETQ, 0, &[], b"missing", b""),
+            request(opcode::GETKQ, 0, &[], b"greeting", b""),
+            request(opcode::NOOP, 0, &[], b"", b""),
+            request(opcode::QUITQ, 0, &[], b"", b""),
+            request(opcode::NOOP, 0, &[], b"", b""),
+        ]
+        .concat();
+        let replies: Vec<_> = responses(&exchange(&server, &input))
+            .into_iter()
+            .map(|reply| (reply.opcode, reply.status, reply.value))
+            .collect();
+        assert_eq!(
+            replies,
+            [
+                (opcode::ADDQ, 2, b"Data exists for key.".to_vec()),
+                (opcode::GETKQ, 0, b"hello".to_vec()),
+                (opcode::NOOP, 0, Vec::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_requests_are_answered() {
+        let server = server();
+        let input = [
+            request(0x55, 0, &[], b"", b""),
+            request(opcode::GET, 0, &[0; 4], b"greeting", b""),
+            request(opcode::SET, 0, &set_extras(0, 0), b"big", &[0; 2048]),
+            request(opcode::STAT, 0, &[], b"", b""),
+        ]
+        .concat();
+        let replies = responses(&exchange(&server, &input));
+        assert_eq!(replies[0].status, Status::UnknownCommand as u16);
+        assert_eq!(replies[1].status, Status::InvalidArguments as u16);
+        assert_eq!(replies[2].status, Status::ValueTooLarge as u16);
+
+        // stats end with an empty one
+        let stats = &replies[3..];
+        assert!(stats.iter().any(|stat| stat.key == b"cmd_get"));
+        assert_eq!(stats.last().unwrap().key, b"");
+    }
+}
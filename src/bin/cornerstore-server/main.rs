@@ -0,0 +1,219 @@
+//! A memcached- and Redis-compatible server backed by CornerStore
+//!
+//! Speaks the memcached text, meta and binary protocols, or RESP with
+//! `--protocol resp`, over TCP, so that services written in other languages can
+//! use any memcached or Redis client.
+
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+
+use cornerstore::{CornerStore, EvictionPolicy};
+
+mod binary;
+mod memcache;
+mod meta;
+mod resp;
+mod server;
+
+use server::{Server, Stats};
+
+const USAGE: &str = "\
+usage: cornerstore-server [options]
+
+options:
+      --protocol <name>       memcached or resp, for Redis clients (default: memcached)
+  -p, --port <num>            TCP port to listen on (default: 11211 for memcached, 6379
+                              for resp, 0 picks a free port)
+  -l, --listen <addr>         interface to listen on (default: 127.0.0.1)
+  -m, --memory-limit <mb>     evict items to stay within this many megabytes (default: unlimited)
+  -I, --max-item-size <bytes> largest value that can be stored (default: 1048576)
+  -h, --help                  print this message
+";
+
+/// Which protocol clients speak
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Memcached,
+    Resp,
+}
+
+impl Protocol {
+    fn default_port(self) -> u16 {
+        match self {
+            Protocol::Memcached => 11211,
+            Protocol::Resp => 6379,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Config {
+    protocol: Protocol,
+    listen: String,
+    /// Defaults to the protocol's usual port
+    port: Option<u16>,
+    memory_limit: Option<usize>,
+    max_item_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            protocol: Protocol::Memcached,
+            listen: "127.0.0.1".into(),
+            port: None,
+            memory_limit: None,
+            max_item_size: 1024 * 1024,
+        }
+    }
+}
+
+impl Config {
+    /// Parses command-line arguments. `Ok(None)` means that help was asked for.
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Option<Config>, String> {
+        let mut config = Config::default();
+        while let Some(arg) = args.next() {
+            let mut value =
+                |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+            match arg.as_str() {
+                "--protocol" => {
+                    config.protocol = match value(&arg)?.as_str() {
+                        "memcached" => Protocol::Memcached,
+                        "resp" => Protocol::Resp,
+                        other => return Err(format!("unknown protocol {}", other)),
+                    }
+                }
+                "-p" | "--port" => config.port = Some(parse(&arg, &value(&arg)?)?),
+                "-l" | "--listen" => config.listen = value(&arg)?,
+                "-m" | "--memory-limit" => {
+                    let megabytes: usize = parse(&arg, &value(&arg)?)?;
+                    config.memory_limit = Some(megabytes * 1024 * 1024);
+                }
+                "-I" | "--max-item-size" => config.max_item_size = parse(&arg, &value(&arg)?)?,
+                "-h" | "--help" => return Ok(None),
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+        Ok(Some(config))
+    }
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", name, value))
+}
+
+fn main() {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return;
+        }
+        Err(err) => {
+            eprint!("cornerstore-server: {}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let mut builder = CornerStore::builder().reaper(std::time::Duration::from_secs(1));
+    if let Some(limit) = config.memory_limit {
+        builder = builder
+            .max_memory(limit)
+            .eviction_policy(EvictionPolicy::Lru);
+    }
+    let server = Arc::new(Server::new(
+        builder.build(),
+        config.max_item_size,
+        config.memory_limit,
+    ));
+
+    let port = config
+        .port
+        .unwrap_or_else(|| config.protocol.default_port());
+    let listener = match TcpListener::bind((config.listen.as_str(), port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!(
+                "cornerstore-server: can't listen on {}:{}: {}",
+                config.listen, port, err
+            );
+            process::exit(1);
+        }
+    };
+    // with --port 0, this is the only way to find out which port was picked
+    if let Ok(addr) = listener.local_addr() {
+        println!("listening on {}", addr);
+        let _ = std::io::stdout().flush();
+    }
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("cornerstore-server: failed to accept connection: {}", err);
+                continue;
+            }
+        };
+        let server = server.clone();
+        let protocol = config.protocol;
+        thread::spawn(move || connect(&server, protocol, stream));
+    }
+}
+
+fn connect(server: &Server, protocol: Protocol, stream: TcpStream) {
+    Stats::bump(&server.stats.total_connections);
+    server
+        .stats
+        .curr_connections
+        .fetch_add(1, Ordering::Relaxed);
+
+    let _ = stream.set_nodelay(true);
+    if let Ok(read_half) = stream.try_clone() {
+        // disconnects show up as errors, which need no further handling
+        let (reader, writer) = (BufReader::new(read_half), BufWriter::new(&stream));
+        let _ = match protocol {
+            Protocol::Memcached => memcache::serve(server, reader, writer),
+            Protocol::Resp => resp::serve(server, reader, writer),
+        };
+    }
+
+    server
+        .stats
+        .curr_connections
+        .fetch_sub(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> impl Iterator<Item = String> + '_ {
+        args.split_whitespace().map(String::from)
+    }
+
+    #[test]
+    fn test_arguments_override_defaults() {
+        assert_eq!(Config::from_args(args("")), Ok(Some(Config::default())));
+        assert_eq!(
+            Config::from_args(args("--protocol resp -p 0 --listen 0.0.0.0 -m 64 -I 2048")),
+            Ok(Some(Config {
+                protocol: Protocol::Resp,
+                listen: "0.0.0.0".into(),
+                port: Some(0),
+                memory_limit: Some(64 * 1024 * 1024),
+                max_item_size: 2048,
+            }))
+        );
+        assert_eq!(Config::from_args(args("--help")), Ok(None));
+        assert!(Config::from_args(args("-p")).is_err());
+        assert!(Config::from_args(args("-p many")).is_err());
+        assert!(Config::from_args(args("--verbose")).is_err());
+        assert!(Config::from_args(args("--protocol http")).is_err());
+    }
+}
@@ -0,0 +1,539 @@
+//! The memcached text protocol
+//!
+//! See https://github.com/memcached/memcached/blob/master/doc/protocol.txt
+//!
+//! Meta commands are handled by [`crate::meta`]. Connections that start with
+//! a binary protocol request are handed to [`crate::binary`].
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cornerstore::Error;
+
+use crate::server::{Counted, Deletion, Expiry, Mode, Server, Stats, Storage};
+use crate::{binary, meta};
+
+/// Longest key that memcached accepts
+pub const MAX_KEY_LEN: usize = 250;
+
+/// Longest command line, not counting the data block of storage commands
+const MAX_LINE_LEN: usize = 2048;
+
+/// Whether the connection should stay open after a command
+#[derive(Debug, PartialEq, Eq)]
+enum Next {
+    Continue,
+    Quit,
+}
+
+/// A command that the client got wrong. The connection stays open.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The command isn't one that the server knows
+    Unknown,
+    /// The command is known, but its arguments don't make sense
+    Format(&'static str),
+}
+
+impl From<ClientError> for Reply {
+    fn from(err: ClientError) -> Reply {
+        match err {
+            ClientError::Unknown => Reply::Line("ERROR".into()),
+            ClientError::Format(reason) => Reply::Line(format!("CLIENT_ERROR {}", reason)),
+        }
+    }
+}
+
+/// What a command sends back
+pub enum Reply {
+    Line(String),
+    Raw(Vec<u8>),
+    Nothing,
+}
+
+/// Answers commands from `reader` on `writer` until the client quits or
+/// disconnects. Replies are flushed whenever there are no more pipelined
+/// commands waiting to be read.
+pub fn serve(
+    server: &Server,
+    mut reader: BufReader<impl Read>,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    // like memcached, decide on the protocol from the first byte that arrives
+    if reader.fill_buf()?.first() == Some(&binary::REQUEST) {
+        return binary::serve(server, reader, writer);
+    }
+
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let n = (&mut reader)
+            .take(MAX_LINE_LEN as u64 + 1)
+            .read_until(b'\n', &mut line)?;
+        if n == 0 {
+            return writer.flush();
+        }
+        if !line.ends_with(b"\n") {
+            // the rest of the line can't be told apart from the next command
+            writer.write_all(b"CLIENT_ERROR line too long\r\n")?;
+            return writer.flush();
+        }
+
+        let (reply, next) = match execute(server, trim_line(&line), &mut reader) {
+            Ok((reply, next)) => (reply, next),
+            Err(Failure::Client(err)) => (err.into(), Next::Continue),
+            Err(Failure::Store(err)) => (server_error(&err), Next::Continue),
+            Err(Failure::Io(err)) => return Err(err),
+        };
+        match reply {
+            Reply::Line(line) => {
+                writer.write_all(line.as_bytes())?;
+                writer.write_all(b"\r\n")?;
+            }
+            Reply::Raw(bytes) => writer.write_all(&bytes)?,
+            Reply::Nothing => {}
+        }
+
+        if next == Next::Quit {
+            return writer.flush();
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+fn trim_line(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+fn server_error(err: &Error) -> Reply {
+    match err {
+        Error::OutOfMemory | Error::CapacityExceeded => {
+            Reply::Line("SERVER_ERROR out of memory storing object".into())
+        }
+        Error::ValueTooLarge => Reply::Line("SERVER_ERROR object too large for cache".into()),
+        err => Reply::Line(format!("SERVER_ERROR {}", err)),
+    }
+}
+
+/// Why a command could not be carried out
+pub enum Failure {
+    Client(ClientError),
+    Store(Error),
+    Io(io::Error),
+}
+
+impl From<ClientError> for Failure {
+    fn from(err: ClientError) -> Failure {
+        Failure::Client(err)
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Failure {
+        Failure::Store(err)
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Failure {
+        Failure::Io(err)
+    }
+}
+
+pub const BAD_FORMAT: ClientError = ClientError::Format("bad command line format");
+
+fn execute(
+    server: &Server,
+    line: &[u8],
+    reader: &mut impl BufRead,
+) -> Result<(Reply, Next), Failure> {
+    let mut args: Vec<&[u8]> = line
+        .split(|&b| b == b' ')
+        .filter(|arg| !arg.is_empty())
+        .collect();
+    if args.is_empty() {
+        return Err(ClientError::Unknown.into());
+    }
+    let command = args.remove(0);
+
+    // commands that can be told not to reply end with "noreply"
+    let noreply = args.last() == Some(&&b"noreply"[..]);
+    let quiet = |reply: Reply| if noreply { Reply::Nothing } else { reply };
+
+    let reply = match command {
+        b"mg" | b"ms" | b"md" | b"ma" | b"mn" => meta::execute(server, command, &args, reader)?,
+        b"get" => get(server, &args, false)?,
+        b"gets" => get(server, &args, true)?,
+        b"set" | b"add" | b"replace" | b"append" | b"prepend" | b"cas" => {
+            let cas = command == b"cas";
+            let expected = if noreply { args.len() - 1 } else { args.len() };
+            if expected != if cas { 5 } else { 4 } {
+                return Err(BAD_FORMAT.into());
+            }
+            let key = key(args[0])?;
+            let flags: u32 = number(args[1])?;
+            let exptime: i64 = number(args[2])?;
+            let len: usize = number(args[3])?;
+            let mode = match command {
+                b"set" => Mode::Set,
+                b"add" => Mode::Add,
+                b"replace" => Mode::Replace,
+                b"append" => Mode::Append,
+                b"prepend" => Mode::Prepend,
+                _ => Mode::Cas(number(args[4])?),
+            };
+
+            let data = match read_data(reader, len, server.max_item_size)? {
+                Some(data) => data,
+                None => {
+                    return Ok((
+                        Reply::Line("SERVER_ERROR object too large for cache".into()),
+                        Next::Continue,
+                    ))
+                }
+            };
+            let stored = server.set(mode, key, flags, Expiry::from_exptime(exptime), data)?;
+            quiet(Reply::Line(
+                match stored {
+                    Storage::Stored(_) => "STORED",
+                    Storage::NotStored => "NOT_STORED",
+                    Storage::Exists => "EXISTS",
+                    Storage::NotFound => "NOT_FOUND",
+                }
+                .into(),
+            ))
+        }
+        b"delete" => {
+            // a zero "time" argument is still accepted by memcached for old clients
+            let key = match args.as_slice() {
+                [key] | [key, b"noreply"] | [key, b"0"] | [key, b"0", b"noreply"] => key,
+                _ => {
+                    return Err(ClientError::Format(
+                        "bad command line format.  Usage: delete <key> [noreply]",
+                    )
+                    .into())
+                }
+            };
+            let deleted = server.delete(self::key(key)?, None)?;
+            quiet(Reply::Line(
+                if deleted == Deletion::Deleted {
+                    "DELETED"
+                } else {
+                    "NOT_FOUND"
+                }
+                .into(),
+            ))
+        }
+        b"incr" | b"decr" => {
+            let (key, delta) = match args.as_slice() {
+                [key, delta] | [key, delta, b"noreply"] => (self::key(key)?, delta),
+                _ => return Err(BAD_FORMAT.into()),
+            };
+            let delta: u64 =
+                number(delta).map_err(|_| ClientError::Format("invalid numeric delta argument"))?;
+            match server.count(key, delta, command == b"incr", None)? {
+                Counted::Value { value, .. } => quiet(Reply::Line(value.to_string())),
+                Counted::NotFound => quiet(Reply::Line("NOT_FOUND".into())),
+                Counted::NotANumber => {
+                    return Err(ClientError::Format(
+                        "cannot increment or decrement non-numeric value",
+                    )
+                    .into())
+                }
+            }
+        }
+        b"touch" => {
+            let (key, exptime) = match args.as_slice() {
+                [key, exptime] | [key, exptime, b"noreply"] => {
+                    (self::key(key)?, number::<i64>(exptime)?)
+                }
+                _ => return Err(BAD_FORMAT.into()),
+            };
+            let touched = server.touch(key, Expiry::from_exptime(exptime))?;
+            quiet(Reply::Line(
+                if touched { "TOUCHED" } else { "NOT_FOUND" }.into(),
+            ))
+        }
+        b"flush_all" => {
+            let delay = match args.as_slice() {
+                [] | [b"noreply"] => 0,
+                [delay] | [delay, b"noreply"] => number::<u64>(delay)?,
+                _ => return Err(BAD_FORMAT.into()),
+            };
+            server.flush_all(Duration::from_secs(delay))?;
+            quiet(Reply::Line("OK".into()))
+        }
+        b"stats" => {
+            if !args.is_empty() {
+                // memcached's stats subcommands describe its slab allocator, which has no counterpart here
+                return Err(ClientError::Unknown.into());
+            }
+            stats(server)?
+        }
+        b"version" => Reply::Line(format!("VERSION {}", env!("CARGO_PKG_VERSION"))),
+        b"verbosity" => quiet(Reply::Line("OK".into())),
+        b"quit" => return Ok((Reply::Nothing, Next::Quit)),
+        _ => return Err(ClientError::Unknown.into()),
+    };
+    Ok((reply, Next::Continue))
+}
+
+pub fn key(arg: &[u8]) -> Result<&[u8], ClientError> {
+    if arg.len() > MAX_KEY_LEN || arg.iter().any(u8::is_ascii_control) {
+        return Err(BAD_FORMAT);
+    }
+    Ok(arg)
+}
+
+pub fn number<T: std::str::FromStr>(arg: &[u8]) -> Result<T, ClientError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or(BAD_FORMAT)
+}
+
+/// Reads the data block of a storage command. Returns `None`, having skipped
+/// the block, if it is larger than the server accepts.
+pub fn read_data(
+    reader: &mut impl BufRead,
+    len: usize,
+    max_len: usize,
+) -> Result<Option<Vec<u8>>, Failure> {
+    // refuse lengths too large to add the line ending to, rather than overflow
+    let block = (len as u64).checked_add(2).ok_or(BAD_FORMAT)?;
+    if len > max_len {
+        let skipped = io::copy(&mut reader.take(block), &mut io::sink())?;
+        if skipped < block {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        return Ok(None);
+    }
+
+    let mut data = vec![0; len + 2];
+    reader.read_exact(&mut data)?;
+    if !data.ends_with(b"\r\n") {
+        return Err(ClientError::Format("bad data chunk").into());
+    }
+    data.truncate(len);
+    Ok(Some(data))
+}
+
+fn get(server: &Server, keys: &[&[u8]], with_cas: bool) -> Result<Reply, Failure> {
+    if keys.is_empty() {
+        return Err(ClientError::Unknown.into());
+    }
+    let mut out = Vec::new();
+    for &key in keys {
+        let key = self::key(key)?;
+        Stats::bump(&server.stats.cmd_get);
+        let item = match server.get(key)? {
+            Some(item) => item,
+            None => {
+                Stats::bump(&server.stats.get_misses);
+                continue;
+            }
+        };
+        Stats::bump(&server.stats.get_hits);
+
+        out.extend_from_slice(b"VALUE ");
+        out.extend_from_slice(key);
+        if with_cas {
+            write!(out, " {} {} {}\r\n", item.flags, item.data.len(), item.cas)?;
+        } else {
+            write!(out, " {} {}\r\n", item.flags, item.data.len())?;
+        }
+        out.extend_from_slice(&item.data);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"END\r\n");
+    Ok(Reply::Raw(out))
+}
+
+fn stats(server: &Server) -> Result<Reply, Failure> {
+    let mut out = Vec::new();
+    for (name, value) in &stat_lines(server)? {
+        write!(out, "STAT {} {}\r\n", name, value)?;
+    }
+    out.extend_from_slice(b"END\r\n");
+    Ok(Reply::Raw(out))
+}
+
+/// What the `stats` command reports, in both protocols
+pub fn stat_lines(server: &Server) -> Result<Vec<(&'static str, String)>, Error> {
+    let stats = &server.stats;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let counter =
+        |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed).to_string();
+
+    Ok(vec![
+        ("pid", std::process::id().to_string()),
+        ("uptime", server.started.elapsed().as_secs().to_string()),
+        ("time", now.as_secs().to_string()),
+        ("version", env!("CARGO_PKG_VERSION").to_string()),
+        (
+            "pointer_size",
+            (8 * std::mem::size_of::<usize>()).to_string(),
+        ),
+        ("curr_connections", counter(&stats.curr_connections)),
+        ("total_connections", counter(&stats.total_connections)),
+        ("cmd_get", counter(&stats.cmd_get)),
+        ("cmd_set", counter(&stats.cmd_set)),
+        ("cmd_flush", counter(&stats.cmd_flush)),
+        ("cmd_touch", counter(&stats.cmd_touch)),
+        ("get_hits", counter(&stats.get_hits)),
+        ("get_misses", counter(&stats.get_misses)),
+        ("delete_misses", counter(&stats.delete_misses)),
+        ("delete_hits", counter(&stats.delete_hits)),
+        ("incr_misses", counter(&stats.incr_misses)),
+        ("incr_hits", counter(&stats.incr_hits)),
+        ("decr_misses", counter(&stats.decr_misses)),
+        ("decr_hits", counter(&stats.decr_hits)),
+        ("cas_misses", counter(&stats.cas_misses)),
+        ("cas_hits", counter(&stats.cas_hits)),
+        ("cas_badval", counter(&stats.cas_badval)),
+        ("touch_hits", counter(&stats.touch_hits)),
+        ("touch_misses", counter(&stats.touch_misses)),
+        ("curr_items", server.store().len()?.to_string()),
+        ("total_items", counter(&stats.total_items)),
+        ("bytes", server.store().memory_usage()?.to_string()),
+        (
+            "limit_maxbytes",
+            server.memory_limit.unwrap_or(0).to_string(),
+        ),
+    ])
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use cornerstore::CornerStore;
+
+    /// Sends `input` as one pipelined batch, and returns everything sent back
+    pub fn session(server: &Server, input: &str) -> String {
+        String::from_utf8(exchange(server, input.as_bytes())).unwrap()
+    }
+
+    /// Like `session`, for binary input
+    pub fn exchange(server: &Server, input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        serve(server, BufReader::new(input), &mut output).unwrap();
+        output
+    }
+
+    pub fn server() -> Server {
+        Server::new(CornerStore::new(), 1024, None)
+    }
+
+    #[test]
+    fn test_storage_commands() {
+        let server = server();
+        assert_eq!(
+            session(
+                &server,
+                "set greeting 5 0 5\r\nhello\r\n\
+                 add greeting 0 0 3\r\nhey\r\n\
+                 replace missing 0 0 3\r\nhey\r\n\
+                 append greeting 0 0 6\r\n world\r\n\
+                 prepend greeting 0 0 2\r\n> \r\n\
+                 get greeting missing\r\n"
+            ),
+            "STORED\r\nNOT_STORED\r\nNOT_STORED\r\nSTORED\r\nSTORED\r\n\
+             VALUE greeting 5 13\r\n> hello world\r\nEND\r\n"
+        );
+    }
+
+    #[test]
+    fn test_cas_only_stores_over_the_version_that_was_read() {
+        let server = server();
+        session(&server, "set greeting 0 0 5\r\nhello\r\n");
+        let cas = server.get(b"greeting").unwrap().unwrap().cas;
+
+        assert_eq!(
+            session(
+                &server,
+                &format!(
+                    "gets greeting\r\ncas greeting 0 0 7 {}\r\nkia ora\r\ncas greeting 0 0 3 {}\r\nhey\r\ncas missing 0 0 3 1\r\nhey\r\n",
+                    cas, cas
+                )
+            ),
+            format!(
+                "VALUE greeting 0 5 {}\r\nhello\r\nEND\r\nSTORED\r\nEXISTS\r\nNOT_FOUND\r\n",
+                cas
+            )
+        );
+    }
+
+    #[test]
+    fn test_counters_deletes_and_touches() {
+        let server = server();
+        assert_eq!(
+            session(
+                &server,
+                "set n 0 0 2\r\n10\r\nincr n 5\r\ndecr n 100\r\nincr missing 1\r\n\
+                 set s 0 0 1\r\nx\r\nincr s 1\r\n\
+                 touch n 60\r\ntouch missing 60\r\ndelete n\r\ndelete n\r\n"
+            ),
+            "STORED\r\n15\r\n0\r\nNOT_FOUND\r\nSTORED\r\n\
+             CLIENT_ERROR cannot increment or decrement non-numeric value\r\n\
+             TOUCHED\r\nNOT_FOUND\r\nDELETED\r\nNOT_FOUND\r\n"
+        );
+        assert!(server.store().ttl(b"n").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_noreply_suppresses_replies() {
+        let server = server();
+        assert_eq!(
+            session(
+                &server,
+                "set greeting 0 0 5 noreply\r\nhello\r\ndelete missing noreply\r\nflush_all noreply\r\nget greeting\r\n"
+            ),
+            "END\r\n"
+        );
+    }
+
+    #[test]
+    fn test_expired_items_are_not_returned() {
+        let server = server();
+        assert_eq!(
+            session(&server, "set greeting 0 -1 5\r\nhello\r\nget greeting\r\n"),
+            "STORED\r\nEND\r\n"
+        );
+        session(&server, "set greeting 0 60 5\r\nhello\r\n");
+        assert!(server.store().ttl(b"greeting").unwrap().unwrap() > Duration::from_secs(59));
+    }
+
+    #[test]
+    fn test_bad_input_is_reported_without_closing_the_connection() {
+        let server = server();
+        let big = "x".repeat(2048);
+        assert_eq!(
+            session(
+                &server,
+                &format!(
+                    "bogus\r\nset greeting 0 0\r\nset greeting 0 0 5\r\nhello!!\r\nset big 0 0 2048\r\n{}\r\n\
+                     set huge 0 0 18446744073709551615\r\nversion\r\n",
+                    big
+                )
+            ),
+            format!(
+                "ERROR\r\nCLIENT_ERROR bad command line format\r\nCLIENT_ERROR bad data chunk\r\n\
+                 ERROR\r\nSERVER_ERROR object too large for cache\r\n\
+                 CLIENT_ERROR bad command line format\r\nVERSION {}\r\n",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+    }
+
+    #[test]
+    fn test_quit_closes_the_connection() {
+        let server = server();
+        assert_eq!(session(&server, "quit\r\nversion\r\n"), "");
+    }
+}
@@ -0,0 +1,429 @@
+//! memcached's meta commands, which share a connection with the text protocol
+//!
+//! See https://github.com/memcached/memcached/wiki/MetaCommands
+//!
+//! Supported flags:
+//!
+//! - `mg`: `c` `f` `k` `O` `q` `s` `t` `v`, `T` to update the TTL, `N` to
+//!   create a missing item and `R` to ask for a refresh of an item that is
+//!   about to expire
+//! - `ms`: `c` `C` `F` `k` `O` `q` `T`, and `M` with modes `E` `A` `P` `R` `S`
+//! - `md`: `C` `k` `O` `q`, and `I` to mark the item stale, with `T` to
+//!   update its TTL
+//! - `ma`: `c` `D` `J` `k` `N` `O` `q` `t` `T` `v`, and `M` with modes `I` `+` `D` `-`
+//!
+//! Readers of a stale item get the `X` flag. The first reader of a stale item,
+//! or of one that `N` or `R` asked about, gets `W` to say that it should fetch
+//! a new value. The others get `Z` until a new value is stored.
+
+use std::io::{BufRead, Write};
+use std::time::Duration;
+
+use cornerstore::Freshness;
+
+use crate::memcache::{key, number, read_data, ClientError, Failure, Reply, BAD_FORMAT};
+use crate::server::{Counted, Deletion, Expiry, Mode, Server, Stats, Storage};
+
+const INVALID_FLAG: ClientError = ClientError::Format("invalid flag");
+const BAD_TOKEN: ClientError = ClientError::Format("bad token in command line format");
+
+/// A meta command's flags. Each is a letter, which some follow with a token.
+struct Flags<'a>(Vec<(u8, &'a [u8])>);
+
+impl<'a> Flags<'a> {
+    /// Parses `args`, rejecting any flag that isn't one of `allowed`
+    fn parse(args: &[&'a [u8]], allowed: &[u8]) -> Result<Flags<'a>, ClientError> {
+        args.iter()
+            .map(|arg| match arg.split_first() {
+                Some((flag, token)) if allowed.contains(flag) => Ok((*flag, token)),
+                _ => Err(INVALID_FLAG),
+            })
+            .collect::<Result<_, _>>()
+            .map(Flags)
+    }
+
+    fn has(&self, flag: u8) -> bool {
+        self.0.iter().any(|&(f, _)| f == flag)
+    }
+
+    fn token(&self, flag: u8) -> Option<&'a [u8]> {
+        self.0
+            .iter()
+            .find(|&&(f, _)| f == flag)
+            .map(|&(_, token)| token)
+    }
+
+    fn number<T: std::str::FromStr>(&self, flag: u8) -> Result<Option<T>, ClientError> {
+        self.token(flag)
+            .map(|token| number(token).map_err(|_| BAD_TOKEN))
+            .transpose()
+    }
+
+    fn expiry(&self, flag: u8) -> Result<Option<Expiry>, ClientError> {
+        Ok(self.number::<i64>(flag)?.map(Expiry::from_exptime))
+    }
+
+    /// Starts a reply with `code`, followed by the flags that the client asked
+    /// to have returned. `k` and `O` are echoed, and `describe` answers the
+    /// rest, or leaves them out by returning `None`.
+    fn reply(&self, code: &str, key: &[u8], describe: impl Fn(u8) -> Option<String>) -> Vec<u8> {
+        let mut out = code.as_bytes().to_vec();
+        for &(flag, token) in &self.0 {
+            let value = match flag {
+                b'k' => key.to_vec(),
+                b'O' => token.to_vec(),
+                flag => match describe(flag) {
+                    Some(value) => value.into_bytes(),
+                    None => continue,
+                },
+            };
+            out.push(b' ');
+            out.push(flag);
+            out.extend_from_slice(&value);
+        }
+        out
+    }
+}
+
+/// Seconds left before an item expires, or -1 if it never does
+fn seconds(ttl: Option<Duration>) -> String {
+    ttl.map_or(-1, |ttl| ((ttl.as_millis() + 500) / 1000) as i64)
+        .to_string()
+}
+
+/// Carries out `mg`, `ms`, `md`, `ma` or `mn`. Only `ms` reads from `reader`,
+/// for its data block.
+pub fn execute(
+    server: &Server,
+    command: &[u8],
+    args: &[&[u8]],
+    reader: &mut impl BufRead,
+) -> Result<Reply, Failure> {
+    if command == b"mn" {
+        return Ok(Reply::Line("MN".into()));
+    }
+    let (key, args) = match args.split_first() {
+        Some((key, args)) => (self::key(key)?, args),
+        None => return Err(BAD_FORMAT.into()),
+    };
+    match command {
+        b"mg" => get(server, key, &Flags::parse(args, b"cfkOqstvTNR")?),
+        b"ms" => set(server, key, args, reader),
+        b"md" => delete(server, key, &Flags::parse(args, b"CkOqIT")?),
+        b"ma" => arithmetic(server, key, &Flags::parse(args, b"ckOqtvDJNTM")?),
+        _ => Err(ClientError::Unknown.into()),
+    }
+}
+
+fn get(server: &Server, key: &[u8], flags: &Flags) -> Result<Reply, Failure> {
+    Stats::bump(&server.stats.cmd_get);
+    if let Some(expiry) = flags.expiry(b'T')? {
+        server.touch(key, expiry)?;
+    }
+
+    let mut found = server.entry(key)?;
+    let mut created = false;
+    if let (None, Some(expiry)) = (&found, flags.expiry(b'N')?) {
+        // an empty placeholder, for the client that made it to fill in
+        let stored = server.set(Mode::Add, key, 0, expiry, Vec::new())?;
+        created = matches!(stored, Storage::Stored(_));
+        found = server.entry(key)?;
+    }
+    let (item, entry) = match found {
+        Some(found) => found,
+        None => {
+            Stats::bump(&server.stats.get_misses);
+            if flags.has(b'q') {
+                return Ok(Reply::Nothing);
+            }
+            let mut out = flags.reply("EN", key, |_| None);
+            out.extend_from_slice(b"\r\n");
+            return Ok(Reply::Raw(out));
+        }
+    };
+    Stats::bump(if created {
+        &server.stats.get_misses
+    } else {
+        &server.stats.get_hits
+    });
+
+    let stale = entry.freshness == Freshness::Stale;
+    let expiring = match flags.number::<u64>(b'R')? {
+        Some(secs) => entry.ttl.is_some_and(|ttl| ttl < Duration::from_secs(secs)),
+        None => false,
+    };
+    let won = (created || stale || expiring) && server.store().claim_refresh(key, entry.version)?;
+
+    let code = if flags.has(b'v') {
+        format!("VA {}", item.data.len())
+    } else {
+        "HD".to_string()
+    };
+    let mut out = flags.reply(&code, key, |flag| match flag {
+        b'c' => Some(item.cas.to_string()),
+        b'f' => Some(item.flags.to_string()),
+        b's' => Some(item.data.len().to_string()),
+        b't' => Some(seconds(entry.ttl)),
+        _ => None,
+    });
+    if won {
+        out.extend_from_slice(b" W");
+    } else if entry.claimed || created || stale || expiring {
+        out.extend_from_slice(b" Z");
+    }
+    if stale {
+        out.extend_from_slice(b" X");
+    }
+    out.extend_from_slice(b"\r\n");
+    if flags.has(b'v') {
+        out.extend_from_slice(&item.data);
+        out.extend_from_slice(b"\r\n");
+    }
+    Ok(Reply::Raw(out))
+}
+
+fn set(
+    server: &Server,
+    key: &[u8],
+    args: &[&[u8]],
+    reader: &mut impl BufRead,
+) -> Result<Reply, Failure> {
+    let (len, flags) = match args.split_first() {
+        Some((len, flags)) => (number::<usize>(len)?, flags),
+        None => return Err(BAD_FORMAT.into()),
+    };
+    // read the data block first, so that bad flags don't leave it to be read as a command
+    let data = read_data(reader, len, server.max_item_size)?;
+    let flags = Flags::parse(flags, b"cCFkOqTM")?;
+    let data = match data {
+        Some(data) => data,
+        None => {
+            return Ok(Reply::Line(
+                "SERVER_ERROR object too large for cache".into(),
+            ))
+        }
+    };
+
+    let mode = match flags.token(b'M') {
+        None | Some(b"S") | Some(b"s") => Mode::Set,
+        Some(b"E") | Some(b"e") => Mode::Add,
+        Some(b"A") | Some(b"a") => Mode::Append,
+        Some(b"P") | Some(b"p") => Mode::Prepend,
+        Some(b"R") | Some(b"r") => Mode::Replace,
+        Some(_) => return Err(ClientError::Format("invalid mode for ms").into()),
+    };
+    let mode = match (mode, flags.number::<u64>(b'C')?) {
+        (mode, None) => mode,
+        (Mode::Set, Some(cas)) => Mode::Cas(cas),
+        (_, Some(_)) => return Err(ClientError::Format("CAS is only supported in set mode").into()),
+    };
+    let client_flags = flags.number::<u32>(b'F')?.unwrap_or(0);
+    let expiry = flags.expiry(b'T')?.unwrap_or(Expiry::Never);
+
+    let (code, cas) = match server.set(mode, key, client_flags, expiry, data)? {
+        Storage::Stored(_) if flags.has(b'q') => return Ok(Reply::Nothing),
+        Storage::Stored(cas) => ("HD", Some(cas)),
+        Storage::NotStored => ("NS", None),
+        Storage::Exists => ("EX", None),
+        Storage::NotFound => ("NF", None),
+    };
+    let mut out = flags.reply(code, key, |flag| match (flag, cas) {
+        (b'c', Some(cas)) => Some(cas.to_string()),
+        _ => None,
+    });
+    out.extend_from_slice(b"\r\n");
+    Ok(Reply::Raw(out))
+}
+
+fn delete(server: &Server, key: &[u8], flags: &Flags) -> Result<Reply, Failure> {
+    let cas = flags.number::<u64>(b'C')?;
+    let deleted = if flags.has(b'I') {
+        server.invalidate(key, cas, flags.expiry(b'T')?)?
+    } else {
+        server.delete(key, cas)?
+    };
+
+    let code = match deleted {
+        Deletion::Deleted | Deletion::NotFound if flags.has(b'q') => return Ok(Reply::Nothing),
+        Deletion::Deleted => "HD",
+        Deletion::NotFound => "NF",
+        Deletion::Exists => "EX",
+    };
+    let mut out = flags.reply(code, key, |_| None);
+    out.extend_from_slice(b"\r\n");
+    Ok(Reply::Raw(out))
+}
+
+fn arithmetic(server: &Server, key: &[u8], flags: &Flags) -> Result<Reply, Failure> {
+    let increment = match flags.token(b'M') {
+        None | Some(b"I") | Some(b"i") | Some(b"+") => true,
+        Some(b"D") | Some(b"d") | Some(b"-") => false,
+        Some(_) => return Err(ClientError::Format("invalid mode for ma").into()),
+    };
+    let delta = flags.number::<u64>(b'D')?.unwrap_or(1);
+    let create = match flags.expiry(b'N')? {
+        Some(expiry) => Some((flags.number::<u64>(b'J')?.unwrap_or(0), expiry)),
+        None => None,
+    };
+
+    let (value, cas) = match server.count(key, delta, increment, create)? {
+        Counted::Value { value, cas } => (value, cas),
+        Counted::NotFound if flags.has(b'q') => return Ok(Reply::Nothing),
+        Counted::NotFound => {
+            let mut out = flags.reply("NF", key, |_| None);
+            out.extend_from_slice(b"\r\n");
+            return Ok(Reply::Raw(out));
+        }
+        Counted::NotANumber => {
+            return Err(
+                ClientError::Format("cannot increment or decrement non-numeric value").into(),
+            )
+        }
+    };
+    if let Some(expiry) = flags.expiry(b'T')? {
+        server.touch(key, expiry)?;
+    }
+    if flags.has(b'q') && !flags.has(b'v') {
+        return Ok(Reply::Nothing);
+    }
+
+    let value = value.to_string();
+    let ttl = if flags.has(b't') {
+        server.store().ttl(key)?
+    } else {
+        None
+    };
+    let code = if flags.has(b'v') {
+        format!("VA {}", value.len())
+    } else {
+        "HD".to_string()
+    };
+    let mut out = flags.reply(&code, key, |flag| match flag {
+        b'c' => Some(cas.to_string()),
+        b't' => Some(seconds(ttl)),
+        _ => None,
+    });
+    out.extend_from_slice(b"\r\n");
+    if flags.has(b'v') {
+        write!(out, "{}\r\n", value)?;
+    }
+    Ok(Reply::Raw(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memcache::tests::{server, session};
+
+    #[test]
+    fn test_meta_storage_and_retrieval() {
+        let server = server();
+        assert_eq!(
+            session(
+                &server,
+                "ms greeting 5 F42 T60 k O123\r\nhello\r\n\
+                 mg greeting v f t s k\r\n\
+                 ms greeting 3 ME\r\nhey\r\n\
+                 ms greeting 6 MA\r\n world\r\n\
+                 mg greeting v\r\n\
+                 mg missing v O9\r\n\
+                 mg missing v q\r\n\
+                 md greeting q\r\n\
+                 md greeting\r\n\
+                 mn\r\n"
+            ),
+            "HD kgreeting O123\r\n\
+             VA 5 f42 t60 s5 kgreeting\r\nhello\r\n\
+             NS\r\n\
+             HD\r\n\
+             VA 11\r\nhello world\r\n\
+             EN O9\r\n\
+             NF\r\n\
+             MN\r\n"
+        );
+    }
+
+    #[test]
+    fn test_meta_cas_tokens_guard_writes() {
+        let server = server();
+        session(&server, "ms greeting 5\r\nhello\r\n");
+        let cas = server.get(b"greeting").unwrap().unwrap().cas;
+
+        assert_eq!(
+            session(&server, "mg greeting c\r\n"),
+            format!("HD c{}\r\n", cas)
+        );
+        assert_eq!(
+            session(
+                &server,
+                &format!(
+                    "ms greeting 3 C{}\r\nhey\r\nms greeting 3 C{}\r\nhey\r\nmd greeting C{}\r\n",
+                    cas + 1,
+                    cas,
+                    cas
+                )
+            ),
+            "EX\r\nHD\r\nEX\r\n"
+        );
+    }
+
+    #[test]
+    fn test_only_the_first_reader_of_a_stale_item_wins() {
+        let server = server();
+        assert_eq!(
+            session(
+                &server,
+                "ms greeting 5 T60\r\nhello\r\n\
+                 md greeting I T30\r\n\
+                 mg greeting v\r\n\
+                 mg greeting v\r\n\
+                 ms greeting 7\r\nkia ora\r\n\
+                 mg greeting v\r\n"
+            ),
+            "HD\r\nHD\r\n\
+             VA 5 W X\r\nhello\r\n\
+             VA 5 Z X\r\nhello\r\n\
+             HD\r\n\
+             VA 7\r\nkia ora\r\n"
+        );
+    }
+
+    #[test]
+    fn test_misses_can_create_placeholders() {
+        let server = server();
+        assert_eq!(
+            session(
+                &server,
+                "mg greeting s N30\r\nmg greeting s N30\r\nmg expiring R30\r\n\
+                 ms expiring 1 T10\r\nx\r\nmg expiring R30\r\nmg expiring R30\r\n"
+            ),
+            "HD s0 W\r\nHD s0 Z\r\nEN\r\nHD\r\nHD W\r\nHD Z\r\n"
+        );
+    }
+
+    #[test]
+    fn test_meta_arithmetic() {
+        let server = server();
+        assert_eq!(
+            session(
+                &server,
+                "ma counter\r\n\
+                 ma counter N0 J10 v\r\n\
+                 ma counter D5 v t\r\n\
+                 ma counter MD D100 v\r\n\
+                 ma counter q\r\n\
+                 ms s 1\r\nx\r\nma s\r\n"
+            ),
+            "NF\r\nVA 2\r\n10\r\nVA 2 t-1\r\n15\r\nVA 1\r\n0\r\nHD\r\n\
+             CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
+        );
+    }
+
+    #[test]
+    fn test_unknown_flags_are_rejected() {
+        let server = server();
+        assert_eq!(
+            session(&server, "mg greeting z\r\nms greeting 2 z\r\nhi\r\nmn\r\n"),
+            "CLIENT_ERROR invalid flag\r\nCLIENT_ERROR invalid flag\r\nMN\r\n"
+        );
+    }
+}
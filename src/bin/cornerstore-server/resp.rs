@@ -0,0 +1,837 @@
+//! The Redis serialization protocol, RESP2 and RESP3
+//!
+//! Covers the string commands, so that Redis clients can use the server as a
+//! cache. See https://redis.io/docs/latest/develop/reference/protocol-spec/
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use cornerstore::{Error, Value};
+
+use crate::server::{Server, Stats, MAX_TTL};
+
+/// Most arguments that a single command may have
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// Longest line of a request, not counting bulk strings
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+const SYNTAX_ERROR: &str = "ERR syntax error";
+const NOT_AN_INTEGER: &str = "ERR value is not an integer or out of range";
+
+/// The protocol version that a connection has chosen with `HELLO`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    Resp2,
+    Resp3,
+}
+
+/// A reply, before it is encoded for the connection's protocol version
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Frame {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Value),
+    Null,
+    Array(Vec<Frame>),
+    Map(Vec<(Frame, Frame)>),
+}
+
+impl Frame {
+    fn error(message: impl Into<String>) -> Frame {
+        Frame::Error(message.into())
+    }
+
+    fn text(text: impl AsRef<str>) -> Frame {
+        Frame::Bulk(Value::from(text.as_ref().as_bytes()))
+    }
+
+    fn write(&self, version: Version, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Frame::Simple(text) => write!(out, "+{}\r\n", text),
+            Frame::Error(message) => write!(out, "-{}\r\n", message),
+            Frame::Integer(n) => write!(out, ":{}\r\n", n),
+            Frame::Bulk(bytes) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Frame::Null => match version {
+                Version::Resp2 => out.write_all(b"$-1\r\n"),
+                Version::Resp3 => out.write_all(b"_\r\n"),
+            },
+            Frame::Array(frames) => {
+                write!(out, "*{}\r\n", frames.len())?;
+                frames
+                    .iter()
+                    .try_for_each(|frame| frame.write(version, out))
+            }
+            Frame::Map(pairs) => {
+                match version {
+                    // RESP2 has no maps, so they are sent as flat arrays of keys and values
+                    Version::Resp2 => write!(out, "*{}\r\n", pairs.len() * 2)?,
+                    Version::Resp3 => write!(out, "%{}\r\n", pairs.len())?,
+                }
+                pairs.iter().try_for_each(|(key, value)| {
+                    key.write(version, out)?;
+                    value.write(version, out)
+                })
+            }
+        }
+    }
+}
+
+impl From<Error> for Frame {
+    fn from(err: Error) -> Frame {
+        match err {
+            Error::OutOfMemory | Error::CapacityExceeded => {
+                Frame::error("OOM command not allowed when used memory > 'maxmemory'.")
+            }
+            err => Frame::error(format!("ERR {}", err)),
+        }
+    }
+}
+
+/// Why a connection has to be closed
+enum Failure {
+    /// The request couldn't be parsed, so the next one can't be found
+    Protocol(&'static str),
+    Io(io::Error),
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Failure {
+        Failure::Io(err)
+    }
+}
+
+/// Answers commands from `reader` on `writer` until the client quits or
+/// disconnects. Connections start out speaking RESP2. Replies are flushed
+/// whenever there are no more pipelined commands waiting to be read.
+pub fn serve(
+    server: &Server,
+    mut reader: BufReader<impl Read>,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    let mut version = Version::Resp2;
+    loop {
+        let args = match read_command(&mut reader, server.max_item_size) {
+            Ok(Some(args)) => args,
+            Ok(None) => return writer.flush(),
+            Err(Failure::Protocol(reason)) => {
+                write!(writer, "-ERR Protocol error: {}\r\n", reason)?;
+                return writer.flush();
+            }
+            Err(Failure::Io(err)) => return Err(err),
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let quit = args[0].eq_ignore_ascii_case(b"quit");
+        let reply = if quit {
+            Frame::Simple("OK")
+        } else {
+            execute(server, &mut version, &args).unwrap_or_else(Frame::from)
+        };
+        reply.write(version, &mut writer)?;
+
+        if quit {
+            return writer.flush();
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+/// Reads one command, as an array of bulk strings or as an inline command.
+/// Returns `None` once the client has closed the connection.
+fn read_command(
+    reader: &mut BufReader<impl Read>,
+    max_bulk_len: usize,
+) -> Result<Option<Vec<Vec<u8>>>, Failure> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let count = match line.strip_prefix(b"*") {
+        Some(count) => count,
+        None => {
+            // inline commands, as typed into telnet
+            let args = line
+                .split(u8::is_ascii_whitespace)
+                .filter(|arg| !arg.is_empty());
+            return Ok(Some(args.map(<[u8]>::to_vec).collect()));
+        }
+    };
+    let count: usize = parse(count)
+        .filter(|&n| n <= MAX_ARGS)
+        .ok_or(Failure::Protocol("invalid multibulk length"))?;
+
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or(Failure::Protocol("unexpected end of stream"))?;
+        let len = match line.strip_prefix(b"$") {
+            Some(len) => len,
+            None => return Err(Failure::Protocol("expected '$'")),
+        };
+        // keys and other arguments are allowed a little over the largest value
+        let len: usize = parse(len)
+            .filter(|&len| len <= max_bulk_len + MAX_LINE_LEN)
+            .ok_or(Failure::Protocol("invalid bulk length"))?;
+
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(Failure::Protocol("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn read_line(reader: &mut BufReader<impl Read>) -> Result<Option<Vec<u8>>, Failure> {
+    let mut line = Vec::new();
+    let n = reader
+        .by_ref()
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(Failure::Protocol("too big request"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse<T: std::str::FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+fn integer(arg: &[u8]) -> Result<i64, Frame> {
+    parse(arg).ok_or_else(|| Frame::error(NOT_AN_INTEGER))
+}
+
+fn wrong_arity(command: &str) -> Frame {
+    Frame::error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        command
+    ))
+}
+
+fn execute(server: &Server, version: &mut Version, args: &[Vec<u8>]) -> Result<Frame, Error> {
+    let command = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let args = &args[1..];
+    let store = server.store();
+
+    let reply = match (command.as_str(), args) {
+        ("ping", []) => Frame::Simple("PONG"),
+        ("ping", [message]) => Frame::Bulk(Value::from(&message[..])),
+        ("get", [key]) => {
+            Stats::bump(&server.stats.cmd_get);
+            lookup(server, key)?
+        }
+        ("mget", keys) if !keys.is_empty() => {
+            let values = keys.iter().map(|key| {
+                Stats::bump(&server.stats.cmd_get);
+                lookup(server, key)
+            });
+            Frame::Array(values.collect::<Result<_, _>>()?)
+        }
+        ("set", [key, value, options @ ..]) => match SetOptions::parse(options) {
+            Ok(options) => set(server, key, value, options)?,
+            Err(err) => err,
+        },
+        ("mset", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            for pair in pairs.chunks(2) {
+                set(server, &pair[0], &pair[1], SetOptions::default())?;
+            }
+            Frame::Simple("OK")
+        }
+        ("del", keys) if !keys.is_empty() => {
+            let mut deleted = 0;
+            for key in keys {
+                deleted += store.take(key)?.is_some() as i64;
+            }
+            Frame::Integer(deleted)
+        }
+        ("exists", keys) if !keys.is_empty() => {
+            let mut found = 0;
+            for key in keys {
+                found += store.get(key)?.is_some() as i64;
+            }
+            Frame::Integer(found)
+        }
+        ("expire", [key, seconds]) => match integer(seconds) {
+            Ok(seconds) if seconds <= 0 => {
+                // like Redis, an expiry in the past deletes the key
+                Frame::Integer(store.take(key)?.is_some() as i64)
+            }
+            Ok(seconds) if Duration::from_secs(seconds as u64) > MAX_TTL => {
+                Frame::error("ERR invalid expire time in 'expire' command")
+            }
+            Ok(seconds) => {
+                Frame::Integer(store.expire(key, Duration::from_secs(seconds as u64))? as i64)
+            }
+            Err(err) => err,
+        },
+        ("ttl", [key]) => match (store.get(key)?, store.ttl(key)?) {
+            (None, _) => Frame::Integer(-2),
+            (Some(_), None) => Frame::Integer(-1),
+            // rounded to the nearest second, as Redis does
+            (Some(_), Some(ttl)) => Frame::Integer(((ttl.as_millis() + 500) / 1000) as i64),
+        },
+        ("persist", [key]) => Frame::Integer(store.persist(key)? as i64),
+        ("incr", [key]) => increment(server, key, 1)?,
+        ("decr", [key]) => increment(server, key, -1)?,
+        ("incrby", [key, delta]) => match integer(delta) {
+            Ok(delta) => increment(server, key, delta)?,
+            Err(err) => err,
+        },
+        ("decrby", [key, delta]) => match integer(delta) {
+            Ok(delta) if delta != i64::MIN => increment(server, key, -delta)?,
+            Ok(_) => Frame::error("ERR decrement would overflow"),
+            Err(err) => err,
+        },
+        ("scan", [cursor, options @ ..]) => match parse::<usize>(cursor) {
+            Some(cursor) => scan(server, cursor, options)?,
+            None => Frame::error("ERR invalid cursor"),
+        },
+        // like Redis, counts keys that have expired but not yet been evicted
+        ("dbsize", []) => Frame::Integer(store.len()? as i64),
+        ("flushdb", []) | ("flushall", []) => {
+            store.clear()?;
+            Frame::Simple("OK")
+        }
+        ("flushdb", [mode]) | ("flushall", [mode])
+            if mode.eq_ignore_ascii_case(b"sync") || mode.eq_ignore_ascii_case(b"async") =>
+        {
+            store.clear()?;
+            Frame::Simple("OK")
+        }
+        ("info", sections) => info(server, sections)?,
+        ("hello", []) => hello(server, *version),
+        ("hello", [protover, ..]) => match parse::<u8>(protover) {
+            Some(2) => {
+                *version = Version::Resp2;
+                hello(server, *version)
+            }
+            Some(3) => {
+                *version = Version::Resp3;
+                hello(server, *version)
+            }
+            _ => Frame::error("NOPROTO unsupported protocol version"),
+        },
+        ("select", [index]) => match parse::<u64>(index) {
+            Some(0) => Frame::Simple("OK"),
+            Some(_) => Frame::error("ERR DB index is out of range"),
+            None => Frame::error(NOT_AN_INTEGER),
+        },
+        // client libraries send these while connecting
+        ("client", [subcommand, ..])
+            if subcommand.eq_ignore_ascii_case(b"setname")
+                || subcommand.eq_ignore_ascii_case(b"setinfo") =>
+        {
+            Frame::Simple("OK")
+        }
+        ("command", _) => Frame::Array(Vec::new()),
+        (
+            "ping" | "get" | "mget" | "set" | "mset" | "del" | "exists" | "expire" | "ttl"
+            | "persist" | "incr" | "decr" | "incrby" | "decrby" | "scan" | "dbsize" | "flushdb"
+            | "flushall" | "select" | "client",
+            _,
+        ) => wrong_arity(&command),
+        _ => Frame::error(format!(
+            "ERR unknown command '{}', with args beginning with: {}",
+            command,
+            args.iter()
+                .map(|arg| format!("'{}' ", String::from_utf8_lossy(arg)))
+                .collect::<String>()
+        )),
+    };
+    Ok(reply)
+}
+
+fn lookup(server: &Server, key: &[u8]) -> Result<Frame, Error> {
+    Ok(match server.store().get(key)? {
+        Some(value) => {
+            Stats::bump(&server.stats.get_hits);
+            Frame::Bulk(value)
+        }
+        None => {
+            Stats::bump(&server.stats.get_misses);
+            Frame::Null
+        }
+    })
+}
+
+/// The options of `SET`
+#[derive(Debug, Default, PartialEq, Eq)]
+struct SetOptions {
+    ttl: Option<Duration>,
+    /// Only set the key if it is missing
+    nx: bool,
+    /// Only set the key if it is present
+    xx: bool,
+}
+
+impl SetOptions {
+    fn parse(mut args: &[Vec<u8>]) -> Result<SetOptions, Frame> {
+        let mut options = SetOptions::default();
+        while let Some((option, rest)) = args.split_first() {
+            let option = option.to_ascii_uppercase();
+            args = rest;
+            match option.as_slice() {
+                b"NX" if !options.xx => options.nx = true,
+                b"XX" if !options.nx => options.xx = true,
+                b"EX" | b"PX" if options.ttl.is_none() => {
+                    let (amount, rest) = args
+                        .split_first()
+                        .ok_or_else(|| Frame::error(SYNTAX_ERROR))?;
+                    args = rest;
+                    let amount = integer(amount)?;
+                    let ttl = match option.as_slice() {
+                        b"EX" => Duration::from_secs(amount.max(0) as u64),
+                        _ => Duration::from_millis(amount.max(0) as u64),
+                    };
+                    if amount <= 0 || ttl > MAX_TTL {
+                        return Err(Frame::error("ERR invalid expire time in 'set' command"));
+                    }
+                    options.ttl = Some(ttl);
+                }
+                _ => return Err(Frame::error(SYNTAX_ERROR)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn set(server: &Server, key: &[u8], value: &[u8], options: SetOptions) -> Result<Frame, Error> {
+    let store = server.store();
+    let expiry = options.ttl.map(|ttl| Instant::now() + ttl);
+    let stored = if options.nx {
+        store.set_if_absent(key, value, expiry)?
+    } else if options.xx {
+        store.set_if_present(key, value, expiry)?
+    } else {
+        Some(store.set(key, value, expiry)?)
+    };
+    Ok(if stored.is_some() { Frame::Simple("OK") } else { Frame::Null })
+}
+
+/// Adds `delta` to the integer stored at key, which counts as 0 if missing.
+/// The key keeps its TTL.
+fn increment(server: &Server, key: &[u8], delta: i64) -> Result<Frame, Error> {
+    match server.store().incr_by(key, delta) {
+        Ok(value) => Ok(Frame::Integer(value)),
+        Err(Error::NotANumber) => Ok(Frame::error(NOT_AN_INTEGER)),
+        Err(Error::Overflow) => Ok(Frame::error("ERR increment or decrement would overflow")),
+        Err(err) => Err(err),
+    }
+}
+
+fn scan(server: &Server, cursor: usize, mut options: &[Vec<u8>]) -> Result<Frame, Error> {
+    let mut pattern = None;
+    let mut count = 10;
+    while let [option, value, rest @ ..] = options {
+        options = rest;
+        if option.eq_ignore_ascii_case(b"match") {
+            pattern = Some(value);
+        } else if option.eq_ignore_ascii_case(b"count") {
+            match parse::<usize>(value) {
+                Some(n) if n > 0 => count = n,
+                Some(_) => return Ok(Frame::error(SYNTAX_ERROR)),
+                None => return Ok(Frame::error(NOT_AN_INTEGER)),
+            }
+        } else {
+            return Ok(Frame::error(SYNTAX_ERROR));
+        }
+    }
+    if !options.is_empty() {
+        return Ok(Frame::error(SYNTAX_ERROR));
+    }
+
+    let (next, keys) = server.store().scan(cursor, count)?;
+    let keys = keys
+        .into_iter()
+        .filter(|key| pattern.is_none_or(|pattern| glob(pattern, key)))
+        .map(Frame::Bulk);
+    Ok(Frame::Array(vec![
+        Frame::text(next.to_string()),
+        Frame::Array(keys.collect()),
+    ]))
+}
+
+fn hello(server: &Server, version: Version) -> Frame {
+    let proto = match version {
+        Version::Resp2 => 2,
+        Version::Resp3 => 3,
+    };
+    Frame::Map(vec![
+        (Frame::text("server"), Frame::text("cornerstore")),
+        (
+            Frame::text("version"),
+            Frame::text(env!("CARGO_PKG_VERSION")),
+        ),
+        (Frame::text("proto"), Frame::Integer(proto)),
+        (
+            Frame::text("id"),
+            Frame::Integer(server.stats.total_connections.load(Ordering::Relaxed) as i64),
+        ),
+        (Frame::text("mode"), Frame::text("standalone")),
+        (Frame::text("role"), Frame::text("master")),
+        (Frame::text("modules"), Frame::Array(Vec::new())),
+    ])
+}
+
+fn info(server: &Server, sections: &[Vec<u8>]) -> Result<Frame, Error> {
+    let store = server.store();
+    let stats = &server.stats;
+    let counter = |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed);
+
+    let all = [
+        (
+            "server",
+            vec![
+                ("cornerstore_version", env!("CARGO_PKG_VERSION").to_string()),
+                ("process_id", std::process::id().to_string()),
+                (
+                    "uptime_in_seconds",
+                    server.started.elapsed().as_secs().to_string(),
+                ),
+            ],
+        ),
+        (
+            "clients",
+            vec![(
+                "connected_clients",
+                counter(&stats.curr_connections).to_string(),
+            )],
+        ),
+        (
+            "memory",
+            vec![
+                ("used_memory", store.memory_usage()?.to_string()),
+                ("maxmemory", server.memory_limit.unwrap_or(0).to_string()),
+            ],
+        ),
+        (
+            "stats",
+            vec![
+                (
+                    "total_connections_received",
+                    counter(&stats.total_connections).to_string(),
+                ),
+                ("keyspace_hits", counter(&stats.get_hits).to_string()),
+                ("keyspace_misses", counter(&stats.get_misses).to_string()),
+            ],
+        ),
+        ("keyspace", vec![("db0", format!("keys={}", store.len()?))]),
+    ];
+
+    let wanted = |name: &str| {
+        sections.is_empty()
+            || sections.iter().any(|section| {
+                section.eq_ignore_ascii_case(name.as_bytes())
+                    || section.eq_ignore_ascii_case(b"all")
+                    || section.eq_ignore_ascii_case(b"default")
+                    || section.eq_ignore_ascii_case(b"everything")
+            })
+    };
+
+    let mut text = String::new();
+    for (name, fields) in all.iter().filter(|(name, _)| wanted(name)) {
+        if !text.is_empty() {
+            text.push_str("\r\n");
+        }
+        let mut title = name.to_string();
+        title[..1].make_ascii_uppercase();
+        text.push_str(&format!("# {}\r\n", title));
+        for (field, value) in fields {
+            text.push_str(&format!("{}:{}\r\n", field, value));
+        }
+    }
+    Ok(Frame::text(text))
+}
+
+/// Matches `text` against a Redis glob pattern, which may contain `*`, `?`,
+/// `[...]` character classes, and `\` escapes
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // where to resume after a mismatch: the last `*`, and how much it has consumed
+    let mut star = None;
+
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(Some(1)),
+            Some(b'[') => class(&pattern[p..], text[t])
+                .map(|(matched, len)| if matched { Some(len) } else { None }),
+            Some(b'\\') if p + 1 < pattern.len() => Some(if pattern[p + 1] == text[t] {
+                Some(2)
+            } else {
+                None
+            }),
+            Some(&c) => Some(if c == text[t] { Some(1) } else { None }),
+            None => Some(None),
+        };
+        // unterminated classes match a literal '['
+        let step = step.unwrap_or(if text[t] == b'[' { Some(1) } else { None });
+
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                t += 1;
+            }
+            (None, Some((star_p, star_t))) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the character class at the start of `pattern`.
+/// Returns whether it matched and the length of the class, or `None` if the
+/// class is never closed.
+fn class(pattern: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = pattern.get(i) == Some(&b'^');
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while let Some(&first) = pattern.get(i) {
+        match first {
+            b']' => return Some((matched != negated, i + 1)),
+            b'\\' => {
+                matched |= pattern.get(i + 1) == Some(&c);
+                i += 2;
+            }
+            _ if pattern.get(i + 1) == Some(&b'-')
+                && pattern.get(i + 2).is_some_and(|&last| last != b']') =>
+            {
+                let (low, high) = (first.min(pattern[i + 2]), first.max(pattern[i + 2]));
+                matched |= (low..=high).contains(&c);
+                i += 3;
+            }
+            _ => {
+                matched |= first == c;
+                i += 1;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cornerstore::CornerStore;
+
+    /// Sends `input` as one pipelined batch, and returns everything sent back
+    fn session(server: &Server, input: &str) -> String {
+        let mut output = Vec::new();
+        serve(server, BufReader::new(input.as_bytes()), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    fn server() -> Server {
+        Server::new(CornerStore::new(), 1024, None)
+    }
+
+    /// Encodes a command the way client libraries do
+    fn command(args: &[&str]) -> String {
+        let mut out = format!("*{}\r\n", args.len());
+        for arg in args {
+            out.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        out
+    }
+
+    #[test]
+    fn test_set_options() {
+        let server = server();
+        let input = [
+            command(&["SET", "greeting", "hello", "NX"]),
+            command(&["SET", "greeting", "hey", "NX"]),
+            command(&["set", "missing", "hey", "xx"]),
+            command(&["SET", "greeting", "kia ora", "XX", "EX", "60"]),
+            command(&["GET", "greeting"]),
+            command(&["TTL", "greeting"]),
+            command(&["SET", "greeting", "hello", "PX", "0"]),
+            command(&["SET", "greeting", "hello", "NX", "XX"]),
+        ]
+        .concat();
+        assert_eq!(
+            session(&server, &input),
+            "+OK\r\n$-1\r\n$-1\r\n+OK\r\n$7\r\nkia ora\r\n:60\r\n\
+             -ERR invalid expire time in 'set' command\r\n-ERR syntax error\r\n"
+        );
+    }
+
+    #[test]
+    fn test_key_commands() {
+        let server = server();
+        let input = [
+            command(&["MSET", "a", "1", "b", "2", "c", "3"]),
+            command(&["MGET", "a", "missing", "c"]),
+            command(&["EXISTS", "a", "b", "a", "missing"]),
+            command(&["EXPIRE", "a", "100"]),
+            command(&["EXPIRE", "missing", "100"]),
+            command(&["PERSIST", "a"]),
+            command(&["TTL", "a"]),
+            command(&["TTL", "missing"]),
+            command(&["EXPIRE", "b", "-1"]),
+            command(&["DEL", "a", "b", "missing"]),
+            command(&["DBSIZE"]),
+        ]
+        .concat();
+        assert_eq!(
+            session(&server, &input),
+            "+OK\r\n*3\r\n$1\r\n1\r\n$-1\r\n$1\r\n3\r\n:3\r\n:1\r\n:0\r\n:1\r\n:-1\r\n:-2\r\n:1\r\n:1\r\n:1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_key_counts_leave_eviction_to_the_reaper() {
+        let server = server();
+        let past = Instant::now() - Duration::from_secs(1);
+        server.store().set(b"expired", b"x", Some(past)).unwrap();
+        let input = [command(&["DBSIZE"]), command(&["INFO", "keyspace"])].concat();
+        assert!(session(&server, &input).starts_with(":1\r\n"));
+        assert!(server.store().get_unchecked(b"expired").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_increments() {
+        let server = server();
+        let input = [
+            command(&["INCRBY", "n", "5"]),
+            command(&["INCR", "n"]),
+            command(&["DECRBY", "n", "10"]),
+            command(&["SET", "s", "hello"]),
+            command(&["INCR", "s"]),
+            command(&["SET", "max", &i64::MAX.to_string()]),
+            command(&["INCR", "max"]),
+            command(&["INCRBY", "n", "many"]),
+        ]
+        .concat();
+        assert_eq!(
+            session(&server, &input),
+            ":5\r\n:6\r\n:-4\r\n+OK\r\n-ERR value is not an integer or out of range\r\n+OK\r\n\
+             -ERR increment or decrement would overflow\r\n-ERR value is not an integer or out of range\r\n"
+        );
+    }
+
+    #[test]
+    fn test_hello_switches_to_resp3() {
+        let server = server();
+        let input = [
+            command(&["GET", "missing"]),
+            command(&["HELLO", "3"]),
+            command(&["GET", "missing"]),
+        ]
+        .concat();
+        let output = session(&server, &input);
+        assert!(output.starts_with("$-1\r\n%7\r\n"), "{:?}", output);
+        assert!(output.ends_with("_\r\n"), "{:?}", output);
+        assert_eq!(
+            session(&server, &command(&["HELLO", "4"])),
+            "-NOPROTO unsupported protocol version\r\n"
+        );
+    }
+
+    #[test]
+    fn test_inline_commands_and_errors() {
+        let server = server();
+        assert_eq!(
+            session(
+                &server,
+                "PING\r\nping hello\r\n\r\nGET\r\nBOGUS a\r\nQUIT\r\nPING\r\n"
+            ),
+            "+PONG\r\n$5\r\nhello\r\n-ERR wrong number of arguments for 'get' command\r\n\
+             -ERR unknown command 'bogus', with args beginning with: 'a' \r\n+OK\r\n"
+        );
+        assert_eq!(
+            session(&server, "*1\r\n+PING\r\n"),
+            "-ERR Protocol error: expected '$'\r\n"
+        );
+    }
+
+    #[test]
+    fn test_scan_matches_patterns() {
+        let server = server();
+        for i in 0..100 {
+            server
+                .store()
+                .set(format!("user:{}", i).as_bytes(), b"x", None)
+                .unwrap();
+            server
+                .store()
+                .set(format!("session:{}", i).as_bytes(), b"x", None)
+                .unwrap();
+        }
+
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let options = [
+                b"MATCH".to_vec(),
+                b"user:*".to_vec(),
+                b"COUNT".to_vec(),
+                b"20".to_vec(),
+            ];
+            let reply = scan(&server, cursor, &options).unwrap();
+            let (next, batch) = match reply {
+                Frame::Array(mut parts) => match (parts.remove(0), parts.remove(0)) {
+                    (Frame::Bulk(next), Frame::Array(batch)) => (next, batch),
+                    other => panic!("{:?}", other),
+                },
+                other => panic!("{:?}", other),
+            };
+            keys.extend(batch);
+            cursor = parse(&next).unwrap();
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert_eq!(keys.len(), 100);
+        assert!(keys
+            .iter()
+            .all(|key| matches!(key, Frame::Bulk(key) if key.starts_with(b"user:"))));
+    }
+
+    #[test]
+    fn test_glob_patterns() {
+        assert!(glob(b"*", b""));
+        assert!(glob(b"h?llo", b"hello"));
+        assert!(glob(b"h*o", b"hello"));
+        assert!(glob(b"h*l*o", b"hellllo"));
+        assert!(!glob(b"h*x", b"hello"));
+        assert!(glob(b"h[ae]llo", b"hallo"));
+        assert!(!glob(b"h[^e]llo", b"hello"));
+        assert!(glob(b"h[a-f]llo", b"hello"));
+        assert!(glob(b"h\\*llo", b"h*llo"));
+        assert!(!glob(b"h\\*llo", b"hello"));
+        assert!(glob(b"h[llo", b"h[llo"));
+    }
+}
@@ -0,0 +1,634 @@
+//! State shared by every connection, and the operations that the protocols
+//! are built from
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use cornerstore::{CornerStore, Counter, Encoding, Entry, Error, Overflow};
+
+/// memcached treats expiry times up to 30 days as relative, and anything
+/// larger as a Unix timestamp
+const RELATIVE_EXPTIME_LIMIT: i64 = 60 * 60 * 24 * 30;
+
+/// Longest TTL that is passed on to the store, which can't represent times
+/// arbitrarily far in the future. Longer ones are treated as never expiring.
+pub const MAX_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 365 * 100);
+
+/// An item as clients see it. The client's flags are stored in front of its
+/// data. The CAS unique is the version that the store gave the value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub flags: u32,
+    pub cas: u64,
+    pub data: Vec<u8>,
+}
+
+impl Item {
+    const HEADER: usize = 4;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Item::HEADER + self.data.len());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    fn decode(buf: &[u8], cas: u64) -> Option<Item> {
+        if buf.len() < Item::HEADER {
+            return None;
+        }
+        let (header, data) = buf.split_at(Item::HEADER);
+        Some(Item {
+            flags: u32::from_be_bytes([header[0], header[1], header[2], header[3]]),
+            cas,
+            data: data.to_vec(),
+        })
+    }
+}
+
+/// When an item expires, decoded from a memcached exptime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    Never,
+    After(Duration),
+
+    /// Exptimes over 30 days are Unix timestamps
+    At(SystemTime),
+
+    /// Negative exptimes, and timestamps in the past, expire items at once
+    Expired,
+}
+
+impl Expiry {
+    pub fn from_exptime(exptime: i64) -> Expiry {
+        Expiry::at(exptime, SystemTime::now())
+    }
+
+    /// When the item expires on the store's clock, which is the system's
+    fn instant(self) -> Option<Instant> {
+        let now = Instant::now();
+        match self {
+            Expiry::Never => None,
+            Expiry::After(ttl) => Some(now + ttl),
+            Expiry::At(at) => Some(now + at.duration_since(SystemTime::now()).unwrap_or_default()),
+            Expiry::Expired => Some(now),
+        }
+    }
+
+    fn at(exptime: i64, now: SystemTime) -> Expiry {
+        match exptime {
+            0 => Expiry::Never,
+            t if t < 0 => Expiry::Expired,
+            t if t <= RELATIVE_EXPTIME_LIMIT => Expiry::After(Duration::from_secs(t as u64)),
+            t => match UNIX_EPOCH.checked_add(Duration::from_secs(t as u64)) {
+                Some(at) => match at.duration_since(now) {
+                    Ok(ttl) if ttl > MAX_TTL => Expiry::Never,
+                    Ok(ttl) if !ttl.is_zero() => Expiry::At(at),
+                    _ => Expiry::Expired,
+                },
+                None => Expiry::Never,
+            },
+        }
+    }
+}
+
+/// How a storage command treats the item that it replaces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Store unconditionally
+    Set,
+    /// Only store if the key is missing
+    Add,
+    /// Only store if the key is present
+    Replace,
+    /// Add to the end of the present item's data
+    Append,
+    /// Add to the start of the present item's data
+    Prepend,
+    /// Only store if the present item's CAS unique matches
+    Cas(u64),
+}
+
+/// Result of a storage command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    /// Stored, with the item's new CAS unique
+    Stored(u64),
+    NotStored,
+    /// The item was changed since the client read its CAS unique
+    Exists,
+    NotFound,
+}
+
+/// Result of `delete`, and of invalidating an item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deletion {
+    Deleted,
+    NotFound,
+    /// The item was changed since the client read its CAS unique
+    Exists,
+}
+
+/// Result of `incr` and `decr`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counted {
+    /// The new value, and the item's new CAS unique
+    Value {
+        value: u64,
+        cas: u64,
+    },
+    NotFound,
+    NotANumber,
+}
+
+/// Counters reported by the `stats` command
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub curr_connections: AtomicU64,
+    pub total_connections: AtomicU64,
+    pub cmd_get: AtomicU64,
+    pub cmd_set: AtomicU64,
+    pub cmd_flush: AtomicU64,
+    pub cmd_touch: AtomicU64,
+    pub get_hits: AtomicU64,
+    pub get_misses: AtomicU64,
+    pub delete_hits: AtomicU64,
+    pub delete_misses: AtomicU64,
+    pub incr_hits: AtomicU64,
+    pub incr_misses: AtomicU64,
+    pub decr_hits: AtomicU64,
+    pub decr_misses: AtomicU64,
+    pub cas_hits: AtomicU64,
+    pub cas_misses: AtomicU64,
+    pub cas_badval: AtomicU64,
+    pub touch_hits: AtomicU64,
+    pub touch_misses: AtomicU64,
+    pub total_items: AtomicU64,
+}
+
+impl Stats {
+    #[inline]
+    pub fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Server {
+    store: CornerStore,
+
+    /// When a delayed `flush_all` is due. Like memcached, the server removes
+    /// the items stored before then, rather than ones stored afterwards.
+    flush_at: Mutex<Option<Instant>>,
+
+    /// Whether `flush_at` holds a time, so that commands can skip its lock
+    flush_pending: AtomicBool,
+    pub stats: Stats,
+    pub started: Instant,
+    pub max_item_size: usize,
+    pub memory_limit: Option<usize>,
+}
+
+impl Server {
+    pub fn new(store: CornerStore, max_item_size: usize, memory_limit: Option<usize>) -> Server {
+        Server {
+            store,
+            flush_at: Mutex::new(None),
+            flush_pending: AtomicBool::new(false),
+            stats: Stats::default(),
+            started: Instant::now(),
+            max_item_size,
+            memory_limit,
+        }
+    }
+
+    /// The store, after any delayed flush that is due. Every command reaches
+    /// the store through here, so none of them sees an item that was stored
+    /// before the flush's deadline.
+    pub fn store(&self) -> &CornerStore {
+        if self.flush_pending.load(Ordering::Acquire) {
+            let mut flush_at = self.flush_at();
+            if matches!(*flush_at, Some(at) if at <= Instant::now()) {
+                // poisoned shards are left for the next flush
+                let _ = self.store.clear();
+                *flush_at = None;
+                self.flush_pending.store(false, Ordering::Release);
+            }
+        }
+        &self.store
+    }
+
+    fn flush_at(&self) -> MutexGuard<'_, Option<Instant>> {
+        // a panic while holding the lock leaves a time or nothing, both valid
+        self.flush_at.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Item>, Error> {
+        Ok(self
+            .store()
+            .get_versioned(key)?
+            .and_then(|(buf, cas)| Item::decode(&buf, cas)))
+    }
+
+    /// Like `get`, along with whether the item is stale and how long it has left
+    pub fn entry(&self, key: &[u8]) -> Result<Option<(Item, Entry)>, Error> {
+        Ok(self.store().get_entry(key)?.and_then(|entry| {
+            let item = Item::decode(&entry.value, entry.version)?;
+            Some((item, entry))
+        }))
+    }
+
+    /// Writes `item`, and returns its new CAS unique, or 0 if it expired at once
+    fn put(&self, key: &[u8], item: Item, expiry: Expiry) -> Result<u64, Error> {
+        Ok(match expiry {
+            Expiry::Never => self.store().set(key, &item.encode(), None)?,
+            Expiry::After(ttl) => self.store().set_with_ttl(key, &item.encode(), ttl)?,
+            Expiry::At(at) => self.store().set_expiring_at(key, &item.encode(), at)?,
+            Expiry::Expired => {
+                self.store().remove(key)?;
+                0
+            }
+        })
+    }
+
+    pub fn set(
+        &self,
+        mode: Mode,
+        key: &[u8],
+        flags: u32,
+        expiry: Expiry,
+        data: Vec<u8>,
+    ) -> Result<Storage, Error> {
+        Stats::bump(&self.stats.cmd_set);
+        let item = Item {
+            flags,
+            cas: 0,
+            data,
+        };
+        let stored = match mode {
+            Mode::Set => Some(self.put(key, item, expiry)?),
+            Mode::Add => self.store().set_if_absent(key, &item.encode(), expiry.instant())?,
+            Mode::Replace => self.store().set_if_present(key, &item.encode(), expiry.instant())?,
+            Mode::Append => self.concatenate(key, &item.data, false)?,
+            Mode::Prepend => self.concatenate(key, &item.data, true)?,
+            Mode::Cas(cas) => {
+                let stored = self.store().compare_and_set(key, cas, &item.encode(), expiry.instant());
+                return Ok(match stored {
+                    Ok(cas) => {
+                        Stats::bump(&self.stats.cas_hits);
+                        Stats::bump(&self.stats.total_items);
+                        Storage::Stored(cas)
+                    }
+                    Err(Error::Conflict { current: None }) => {
+                        Stats::bump(&self.stats.cas_misses);
+                        Storage::NotFound
+                    }
+                    Err(Error::Conflict { current: Some(_) }) => {
+                        Stats::bump(&self.stats.cas_badval);
+                        Storage::Exists
+                    }
+                    Err(err) => return Err(err),
+                });
+            }
+        };
+
+        Ok(match stored {
+            Some(cas) => {
+                Stats::bump(&self.stats.total_items);
+                Storage::Stored(cas)
+            }
+            None => Storage::NotStored,
+        })
+    }
+
+    /// Adds `data` to the end of the present item's data, or to the start
+    /// when `prepend` is set. The item keeps its flags and expiry.
+    ///
+    /// Returns the item's new CAS unique, or `None` if it is not present.
+    fn concatenate(&self, key: &[u8], data: &[u8], prepend: bool) -> Result<Option<u64>, Error> {
+        loop {
+            let (buf, cas) = match self.store().get_versioned(key)? {
+                Some(present) if present.0.len() >= Item::HEADER => present,
+                _ => return Ok(None),
+            };
+            let at = if prepend { Item::HEADER } else { buf.len() };
+            let joined = [&buf[..at], data, &buf[at..]].concat();
+            match self.store().compare_and_replace(key, cas, &joined) {
+                Ok(cas) => return Ok(Some(cas)),
+                // another client wrote first, so start again from its item
+                Err(Error::Conflict { current: Some(_) }) => continue,
+                Err(Error::Conflict { current: None }) => return Ok(None),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Removes the item at key, if its CAS unique is `cas`, when given
+    pub fn delete(&self, key: &[u8], cas: Option<u64>) -> Result<Deletion, Error> {
+        let deleted = match cas {
+            None if self.store().take(key)?.is_some() => Deletion::Deleted,
+            None => Deletion::NotFound,
+            Some(cas) => deletion(self.store().compare_and_remove(key, cas))?,
+        };
+        if deleted == Deletion::Deleted {
+            Stats::bump(&self.stats.delete_hits);
+        } else {
+            Stats::bump(&self.stats.delete_misses);
+        }
+        Ok(deleted)
+    }
+
+    /// Marks the item at key as stale rather than removing it, if its CAS
+    /// unique is `cas`, when given. Readers are still served the item, and the
+    /// first of them is told to fetch a new one. `expiry` replaces the item's
+    /// remaining lifetime.
+    pub fn invalidate(
+        &self,
+        key: &[u8],
+        cas: Option<u64>,
+        expiry: Option<Expiry>,
+    ) -> Result<Deletion, Error> {
+        let invalidated = match cas {
+            None if self.store().invalidate(key)? => Deletion::Deleted,
+            None => Deletion::NotFound,
+            Some(cas) => deletion(self.store().compare_and_invalidate(key, cas))?,
+        };
+        if invalidated == Deletion::Deleted {
+            if let Some(expiry) = expiry {
+                self.expire(key, expiry)?;
+            }
+            Stats::bump(&self.stats.delete_hits);
+        } else {
+            Stats::bump(&self.stats.delete_misses);
+        }
+        Ok(invalidated)
+    }
+
+    /// Adds `delta` to the decimal number stored at key, wrapping at 2^64.
+    /// Decrementing stops at zero, as it does in memcached.
+    ///
+    /// Missing items are created with the value and expiry in `create`, if
+    /// one is given.
+    pub fn count(
+        &self,
+        key: &[u8],
+        delta: u64,
+        increment: bool,
+        create: Option<(u64, Expiry)>,
+    ) -> Result<Counted, Error> {
+        let (hits, misses) = if increment {
+            (&self.stats.incr_hits, &self.stats.incr_misses)
+        } else {
+            (&self.stats.decr_hits, &self.stats.decr_misses)
+        };
+        let counter = Counter {
+            encoding: Encoding::Decimal,
+            overflow: if increment {
+                Overflow::Wrap
+            } else {
+                Overflow::Saturate
+            },
+            initial: None,
+            ttl: None,
+            prefix: Item::HEADER,
+        };
+
+        loop {
+            let counted = if increment {
+                self.store().incr_by_with(key, delta, &counter)
+            } else {
+                self.store().decr_by_with(key, delta, &counter)
+            };
+            match counted {
+                Ok(Some((value, cas))) => {
+                    Stats::bump(hits);
+                    return Ok(Counted::Value { value, cas });
+                }
+                Ok(None) => {}
+                Err(Error::NotANumber) => return Ok(Counted::NotANumber),
+                Err(err) => return Err(err),
+            }
+
+            let (initial, expiry) = match create {
+                Some(create) => create,
+                None => {
+                    Stats::bump(misses);
+                    return Ok(Counted::NotFound);
+                }
+            };
+            // memcached stores the initial value as it is, without the delta
+            let item = Item {
+                flags: 0,
+                cas: 0,
+                data: initial.to_string().into_bytes(),
+            };
+            if let Some(cas) = self.store().set_if_absent(key, &item.encode(), expiry.instant())? {
+                Stats::bump(misses);
+                return Ok(Counted::Value {
+                    value: initial,
+                    cas,
+                });
+            }
+            // another client created the item first, so count that one
+        }
+    }
+
+    pub fn touch(&self, key: &[u8], expiry: Expiry) -> Result<bool, Error> {
+        Stats::bump(&self.stats.cmd_touch);
+        let found = self.expire(key, expiry)?;
+        if found {
+            Stats::bump(&self.stats.touch_hits);
+        } else {
+            Stats::bump(&self.stats.touch_misses);
+        }
+        Ok(found)
+    }
+
+    /// Gives the item at key a new expiry, keeping its CAS unique. Returns
+    /// whether the item was present.
+    fn expire(&self, key: &[u8], expiry: Expiry) -> Result<bool, Error> {
+        Ok(match expiry {
+            // persist only says whether there was an expiry to remove
+            Expiry::Never => self.store().persist(key)? || self.store().get_with(key, |_| ())?.is_some(),
+            Expiry::After(ttl) => self.store().expire(key, ttl)?,
+            Expiry::At(at) => match at.duration_since(SystemTime::now()) {
+                Ok(ttl) => self.store().expire(key, ttl)?,
+                Err(_) => self.store().take(key)?.is_some(),
+            },
+            Expiry::Expired => self.store().take(key)?.is_some(),
+        })
+    }
+
+    /// Removes every item, now or after `delay`. A flush replaces any
+    /// delayed one that is still to come, as it does in memcached.
+    pub fn flush_all(&self, delay: Duration) -> Result<(), Error> {
+        Stats::bump(&self.stats.cmd_flush);
+        let mut flush_at = self.flush_at();
+        if delay.is_zero() {
+            *flush_at = None;
+            self.flush_pending.store(false, Ordering::Release);
+            return self.store.clear();
+        }
+        // a delay too long to represent never comes
+        *flush_at = Instant::now().checked_add(delay);
+        self.flush_pending.store(flush_at.is_some(), Ordering::Release);
+        Ok(())
+    }
+}
+
+/// Reads the outcome of a conditional delete or invalidation
+fn deletion(result: Result<(), Error>) -> Result<Deletion, Error> {
+    match result {
+        Ok(()) => Ok(Deletion::Deleted),
+        Err(Error::Conflict { current: None }) => Ok(Deletion::NotFound),
+        Err(Error::Conflict { current: Some(_) }) => Ok(Deletion::Exists),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_items_survive_encoding() {
+        let item = Item {
+            flags: 0xdead_beef,
+            cas: 42,
+            data: b"hello".to_vec(),
+        };
+        assert_eq!(Item::decode(&item.encode(), 42), Some(item));
+        assert_eq!(Item::decode(b"abc", 42), None);
+    }
+
+    #[test]
+    fn test_exptimes_follow_memcached() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(Expiry::at(0, now), Expiry::Never);
+        assert_eq!(Expiry::at(-1, now), Expiry::Expired);
+        assert_eq!(Expiry::at(60, now), Expiry::After(Duration::from_secs(60)));
+        assert_eq!(
+            Expiry::at(RELATIVE_EXPTIME_LIMIT, now),
+            Expiry::After(Duration::from_secs(RELATIVE_EXPTIME_LIMIT as u64))
+        );
+        assert_eq!(
+            Expiry::at(1_700_000_060, now),
+            Expiry::At(now + Duration::from_secs(60))
+        );
+        assert_eq!(Expiry::at(1_600_000_000, now), Expiry::Expired);
+        assert_eq!(Expiry::at(i64::MAX, now), Expiry::Never);
+    }
+
+    #[test]
+    fn test_counters_wrap_up_and_stop_at_zero_going_down() {
+        let server = Server::new(CornerStore::new(), 1024, None);
+        let max = u64::MAX.to_string().into_bytes();
+        server.set(Mode::Set, b"n", 0, Expiry::Never, max).unwrap();
+        let value = |counted| match counted {
+            Counted::Value { value, .. } => Some(value),
+            _ => None,
+        };
+        assert_eq!(value(server.count(b"n", 2, true, None).unwrap()), Some(1));
+        assert_eq!(value(server.count(b"n", 5, false, None).unwrap()), Some(0));
+        assert_eq!(
+            server.count(b"missing", 1, true, None).unwrap(),
+            Counted::NotFound
+        );
+        let created = server.count(b"created", 1, true, Some((10, Expiry::Never)));
+        assert_eq!(value(created.unwrap()), Some(10));
+
+        server
+            .set(Mode::Set, b"s", 0, Expiry::Never, b"hello".to_vec())
+            .unwrap();
+        assert_eq!(
+            server.count(b"s", 1, true, None).unwrap(),
+            Counted::NotANumber
+        );
+    }
+
+    #[test]
+    fn test_conditional_modes_keep_flags_and_expiry() {
+        let server = Server::new(CornerStore::new(), 1024, None);
+        let set = |mode, flags, data: &[u8]| {
+            let ttl = Expiry::After(Duration::from_secs(60));
+            server.set(mode, b"k", flags, ttl, data.to_vec()).unwrap()
+        };
+        assert_eq!(set(Mode::Replace, 1, b"a"), Storage::NotStored);
+        assert_eq!(set(Mode::Append, 1, b"a"), Storage::NotStored);
+        assert!(matches!(set(Mode::Add, 7, b"b"), Storage::Stored(_)));
+        assert_eq!(set(Mode::Add, 1, b"a"), Storage::NotStored);
+        server.touch(b"k", Expiry::Never).unwrap();
+
+        assert!(matches!(set(Mode::Append, 1, b"c"), Storage::Stored(_)));
+        let cas = match set(Mode::Prepend, 1, b"a") {
+            Storage::Stored(cas) => cas,
+            other => panic!("{:?}", other),
+        };
+        let item = server.get(b"k").unwrap().unwrap();
+        assert_eq!((item.flags, item.cas, &item.data[..]), (7, cas, &b"abc"[..]));
+        assert_eq!(server.store().ttl(b"k").unwrap(), None);
+
+        assert!(matches!(set(Mode::Replace, 1, b"d"), Storage::Stored(_)));
+        assert_eq!(server.get(b"k").unwrap().unwrap().flags, 1);
+        assert!(server.store().ttl(b"k").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_delayed_flushes_remove_items_stored_before_their_deadline() {
+        let server = Server::new(CornerStore::new(), 1024, None);
+        let set = |key: &[u8]| {
+            server
+                .set(Mode::Set, key, 0, Expiry::Never, b"x".to_vec())
+                .unwrap()
+        };
+        set(b"before");
+        server.flush_all(Duration::from_secs(60 * 60)).unwrap();
+        server.flush_all(Duration::from_millis(50)).unwrap();
+        set(b"during");
+        assert!(server.get(b"before").unwrap().is_some());
+
+        thread::sleep(Duration::from_millis(100));
+        set(b"after");
+        assert_eq!(server.get(b"before").unwrap(), None);
+        assert_eq!(server.get(b"during").unwrap(), None);
+        assert!(server.get(b"after").unwrap().is_some());
+
+        // the earlier, longer delay was replaced rather than kept
+        assert!(!server.flush_pending.load(Ordering::Acquire));
+        server.flush_all(Duration::from_secs(60 * 60)).unwrap();
+        server.flush_all(Duration::ZERO).unwrap();
+        assert_eq!(server.get(b"after").unwrap(), None);
+        assert!(!server.flush_pending.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_cas_uniques_follow_the_store_versions() {
+        let server = Server::new(CornerStore::new(), 1024, None);
+        let cas = match server.set(Mode::Set, b"k", 0, Expiry::Never, b"a".to_vec()) {
+            Ok(Storage::Stored(cas)) => cas,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(server.get(b"k").unwrap().unwrap().cas, cas);
+
+        // touching keeps the CAS unique, writing replaces it
+        server
+            .touch(b"k", Expiry::After(Duration::from_secs(60)))
+            .unwrap();
+        assert_eq!(server.get(b"k").unwrap().unwrap().cas, cas);
+        assert_eq!(
+            server
+                .set(Mode::Cas(cas + 1), b"k", 0, Expiry::Never, b"b".to_vec())
+                .unwrap(),
+            Storage::Exists
+        );
+        assert_eq!(server.delete(b"k", Some(cas + 1)).unwrap(), Deletion::Exists);
+        assert_eq!(
+            server.invalidate(b"k", Some(cas), None).unwrap(),
+            Deletion::Deleted
+        );
+        assert_eq!(server.delete(b"k", Some(cas)).unwrap(), Deletion::Deleted);
+        assert_eq!(server.delete(b"k", None).unwrap(), Deletion::NotFound);
+    }
+}
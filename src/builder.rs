@@ -0,0 +1,294 @@
+//! Configuration for new stores
+
+use std::hash::BuildHasher;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::aof::{Aof, LogConfig, DEFAULT_COMPACT_AFTER};
+use crate::policy::Limits;
+use crate::reaper::{Reaper, Schedule};
+use crate::refresh::{RefreshHook, Refresher};
+use crate::{
+    Clock, CornerStore, EvictionPolicy, Fsync, KeyHasher, Result, SystemClock, Value, DEFAULT_SHARDS,
+};
+
+/// Configures a [`CornerStore`] before it is created.
+///
+/// ```
+/// use std::time::Duration;
+/// use cornerstore::CornerStore;
+///
+/// let store = CornerStore::builder()
+///     .capacity(10_000)
+///     .reaper(Duration::from_secs(1))
+///     .build();
+/// ```
+///
+/// A bounded cache that keeps the keys which are requested most often:
+///
+/// ```
+/// use cornerstore::{CornerStore, EvictionPolicy};
+///
+/// let cache = CornerStore::builder()
+///     .max_entries(100_000)
+///     .max_bytes(64 * 1024 * 1024)
+///     .eviction_policy(EvictionPolicy::TinyLfu)
+///     .build();
+/// ```
+///
+/// A small store for a constrained device, and a large one for a many-core
+/// server that hashes keys with randomly seeded SipHash:
+///
+/// ```
+/// use std::collections::hash_map::RandomState;
+/// use cornerstore::CornerStore;
+///
+/// let small = CornerStore::builder().shards(4).build();
+///
+/// let large = CornerStore::builder()
+///     .shards(1024)
+///     .hasher(RandomState::new())
+///     .capacity(10_000_000)
+///     .build();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+    capacity: usize,
+    shards: Option<usize>,
+    hasher: Option<KeyHasher>,
+    clock: Option<Arc<dyn Clock>>,
+    reaper: Option<Schedule>,
+    limits: Limits,
+    refresh: Option<RefreshHook>,
+    log: Option<PathBuf>,
+    fsync: Fsync,
+    compact_log_after: Option<u64>,
+}
+
+impl Builder {
+    /// Pre-allocates space for roughly `cap` items.
+    pub fn capacity(mut self, cap: usize) -> Self {
+        self.capacity = cap;
+        self
+    }
+
+    /// Divides the store into `n` shards, each with its own lock. More shards
+    /// let more threads write at once, but cost memory even while they are
+    /// empty. Defaults to 128.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is not a power of two.
+    pub fn shards(mut self, n: usize) -> Self {
+        assert!(n.is_power_of_two(), "shard count must be a power of two, not {}", n);
+        self.shards = Some(n);
+        self
+    }
+
+    /// Hashes keys with `hasher`, such as
+    /// [`RandomState`](std::collections::hash_map::RandomState) for randomly
+    /// seeded SipHash, or aHash's `RandomState`. Defaults to a fixed SipHash
+    /// key, or to FxHash with the `safe-input` feature.
+    ///
+    /// Keys are spread across shards by bits in the middle of their hash, so
+    /// the hasher must mix its input well.
+    pub fn hasher<S: BuildHasher + Send + Sync + 'static>(mut self, hasher: S) -> Self {
+        self.hasher = Some(KeyHasher::from_build_hasher(hasher));
+        self
+    }
+
+    /// Reads the time from `clock`, rather than from [`SystemClock`]. Expiry
+    /// times and TTLs are measured against it.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Starts a background thread that evicts expired items every `interval`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero, which would keep the thread busy.
+    pub fn reaper(mut self, interval: Duration) -> Self {
+        assert!(!interval.is_zero(), "reaper interval must not be zero");
+        self.reaper = Some(Schedule::Fixed(interval));
+        self
+    }
+
+    /// Starts a background thread that evicts expired items, adapting how
+    /// often it runs to how much it finds. Runs get closer together, down to
+    /// `min`, while many of the items in the store are expiring, and further
+    /// apart, up to `max`, while none are.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min` is zero, which would keep the thread busy, or if it is
+    /// longer than `max`.
+    pub fn adaptive_reaper(mut self, min: Duration, max: Duration) -> Self {
+        assert!(!min.is_zero(), "reaper interval must not be zero");
+        assert!(min <= max, "reaper's minimum interval {:?} is longer than its maximum {:?}", min, max);
+        self.reaper = Some(Schedule::Adaptive { min, max });
+        self
+    }
+
+    /// Limits the store to roughly `n` key/value pairs. Writes beyond the
+    /// limit evict pairs chosen by the [eviction policy](Builder::eviction_policy).
+    ///
+    /// The limit is split evenly between the store's shards, so a shard may
+    /// start evicting before the store as a whole holds `n` pairs.
+    pub fn max_entries(mut self, n: usize) -> Self {
+        self.limits.max_entries = Some(n);
+        self
+    }
+
+    /// Limits the total length of the keys and values in the store to roughly
+    /// `n` bytes. Like [`max_entries`](Builder::max_entries), the limit is split
+    /// evenly between shards, and a single pair that is larger than a shard's
+    /// share is refused with [`Error::ValueTooLarge`](crate::Error::ValueTooLarge).
+    pub fn max_bytes(mut self, n: usize) -> Self {
+        self.limits.max_bytes = Some(n);
+        self
+    }
+
+    /// Limits the store's estimated memory use to roughly `n` bytes. Unlike
+    /// [`max_bytes`](Builder::max_bytes), this includes the overhead of
+    /// storing each pair, as reported by
+    /// [`CornerStore::memory_usage`](crate::CornerStore::memory_usage).
+    ///
+    /// The limit is split evenly between shards. Writes beyond it evict pairs,
+    /// or fail with [`Error::OutOfMemory`](crate::Error::OutOfMemory) under
+    /// [`EvictionPolicy::NoEviction`].
+    pub fn max_memory(mut self, n: usize) -> Self {
+        self.limits.max_memory = Some(n);
+        self
+    }
+
+    /// Chooses which pairs are evicted once the store reaches one of its
+    /// limits. Defaults to [`EvictionPolicy::Lru`]. Has no effect on a store
+    /// without limits.
+    pub fn eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.limits.policy = policy;
+        self
+    }
+
+    /// Starts a background thread that refreshes values once they go stale.
+    /// See [`CornerStore::set_with_soft_ttl`].
+    ///
+    /// The first read of a stale value queues its key, and `hook` is later
+    /// called with it on the refresh thread. Returning `Some` replaces the
+    /// value, keeping its soft and hard TTLs. Returning `None` keeps serving
+    /// the stale value, and the next read queues the key again. Refreshed
+    /// values never overwrite values that were written while the hook ran.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use cornerstore::CornerStore;
+    ///
+    /// let store = CornerStore::builder()
+    ///     .refresh_ahead(|key: &[u8]| Some(key.to_ascii_uppercase()))   // e.g. a database query
+    ///     .build();
+    ///
+    /// store.set_with_soft_ttl(b"greeting", b"hello", Duration::from_secs(60), Duration::from_secs(300))?;
+    /// # Ok::<(), cornerstore::Error>(())
+    /// ```
+    pub fn refresh_ahead<V: Into<Value>>(
+        mut self,
+        hook: impl Fn(&[u8]) -> Option<V> + Send + Sync + 'static,
+    ) -> Self {
+        self.refresh = Some(RefreshHook::new(hook));
+        self
+    }
+
+    /// Logs every write to the file at `path`, so that the store can be
+    /// [opened](Builder::open) again after a restart with the same contents.
+    /// Writes are flushed to disk as [`fsync`](Builder::fsync) says.
+    ///
+    /// Once the log grows past [a threshold](Builder::compact_log_after), a
+    /// background thread saves a [snapshot](crate::CornerStore::save_snapshot)
+    /// beside it, with `.snapshot` added to its name, and starts the log
+    /// again. Opening the store loads that snapshot and replays the log on top.
+    ///
+    /// Pairs that are evicted, either because they expired or to keep the
+    /// store within its limits, aren't logged. After a restart, expired pairs
+    /// stay gone, but a bounded store may hold different pairs than before.
+    ///
+    /// ```
+    /// use cornerstore::{CornerStore, Fsync};
+    ///
+    /// let path = std::env::temp_dir().join("cornerstore-doc.aof");
+    /// # let _ = std::fs::remove_file(&path);
+    /// let store = CornerStore::builder()
+    ///     .append_log(&path)
+    ///     .fsync(Fsync::Always)
+    ///     .open()?;
+    /// store.set(b"greeting", b"hello", None)?;
+    /// drop(store);
+    ///
+    /// let store = CornerStore::builder().append_log(&path).open()?;
+    /// assert_eq!(&*store.get(b"greeting")?.unwrap(), b"hello");
+    /// # std::fs::remove_file(&path)?;
+    /// # Ok::<(), cornerstore::Error>(())
+    /// ```
+    pub fn append_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.log = Some(path.into());
+        self
+    }
+
+    /// When writes to the [append-only log](Builder::append_log) are flushed
+    /// to disk. Defaults to [`Fsync::EverySec`].
+    pub fn fsync(mut self, fsync: Fsync) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Compacts the [append-only log](Builder::append_log) once it is larger
+    /// than `n` bytes, and has at least doubled in size since it was last
+    /// compacted. Defaults to 64 MiB.
+    pub fn compact_log_after(mut self, n: u64) -> Self {
+        self.compact_log_after = Some(n);
+        self
+    }
+
+    /// Creates the store.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the store has an [append-only log](Builder::append_log) that
+    /// can't be opened. Use [`open`](Builder::open) to handle that instead.
+    pub fn build(self) -> CornerStore {
+        self.open().expect("failed to open the append-only log")
+    }
+
+    /// Creates the store, and loads whatever its
+    /// [append-only log](Builder::append_log) and snapshot hold. Returns
+    /// [`Error::Corrupt`](crate::Error::Corrupt) if either is damaged, except
+    /// for a write at the end of the log that was cut short by a crash, which
+    /// is dropped.
+    pub fn open(self) -> Result<CornerStore> {
+        let store = CornerStore::with_config(
+            self.capacity,
+            self.shards.unwrap_or(DEFAULT_SHARDS),
+            self.hasher.unwrap_or_default(),
+            self.limits,
+            self.clock.unwrap_or_else(|| Arc::new(SystemClock)),
+        );
+        if let Some(path) = self.log {
+            let config = LogConfig {
+                path,
+                fsync: self.fsync,
+                compact_after: self.compact_log_after.unwrap_or(DEFAULT_COMPACT_AFTER),
+            };
+            Aof::open(&store, config)?;
+        }
+        if let Some(schedule) = self.reaper {
+            let reaper = Reaper::start(&store.0, schedule);
+            let _ = store.0.reaper.set(reaper);
+        }
+        if let Some(hook) = self.refresh {
+            let refresher = Refresher::start(&store.0, hook);
+            let _ = store.0.refresher.set(refresher);
+        }
+        Ok(store)
+    }
+}
@@ -0,0 +1,134 @@
+//! Where the store gets the time from
+
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A source of the current time. The store reads it whenever it decides
+/// whether a pair has expired or gone stale, and when it turns TTLs into
+/// expiry times.
+///
+/// Expiry times passed to [`CornerStore::set`](crate::CornerStore::set) are
+/// compared against this clock, so they should come from it too.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The current time
+    fn now(&self) -> Instant;
+
+    /// The current wall-clock time. Used to convert expiry times to and from
+    /// [`SystemTime`]s, for [`CornerStore::set_expiring_at`](crate::CornerStore::set_expiring_at)
+    /// and for snapshots, and nowhere else. Defaults to [`SystemTime::now`].
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// The system's monotonic clock, [`Instant::now`]. Used unless
+/// [`Builder::clock`](crate::Builder::clock) says otherwise.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when it is told to, for testing expiry without
+/// waiting for it. Clones share the same time, so keep a clone of the clock
+/// that a store was built with to move the store's time along. Its
+/// wall-clock time starts at the system's, and moves along with it.
+///
+/// ```
+/// use std::time::Duration;
+/// use cornerstore::{CornerStore, ManualClock};
+///
+/// let clock = ManualClock::new();
+/// let store = CornerStore::builder().clock(clock.clone()).build();
+/// store.set_with_ttl(b"greeting", b"hello", Duration::from_secs(60))?;
+///
+/// clock.advance(Duration::from_secs(59));
+/// assert_eq!(store.ttl(b"greeting")?, Some(Duration::from_secs(1)));
+///
+/// clock.advance(Duration::from_secs(1));
+/// assert_eq!(store.get(b"greeting")?, None);
+/// # Ok::<(), cornerstore::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<(Instant, SystemTime)>>);
+
+impl ManualClock {
+    /// A clock stopped at the current time
+    pub fn new() -> Self {
+        ManualClock::starting_at(Instant::now())
+    }
+
+    /// A clock stopped at `now`
+    pub fn starting_at(now: Instant) -> Self {
+        ManualClock(Arc::new(Mutex::new((now, SystemTime::now()))))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, (Instant, SystemTime)> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Moves the clock forward by `by`, for every clone
+    pub fn advance(&self, by: Duration) {
+        let mut times = self.lock();
+        times.0 += by;
+        times.1 += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        self.lock().0
+    }
+
+    fn wall(&self) -> SystemTime {
+        self.lock().1
+    }
+}
+
+/// A moment read from both of a clock's times, for converting between them.
+/// Expiry times are kept as `Instant`s, which are cheap to compare on every
+/// read and don't jump when the system time is changed, and only converted
+/// to wall-clock times to cross a process boundary.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Epoch {
+    pub(crate) instant: Instant,
+    wall: SystemTime,
+}
+
+impl Epoch {
+    pub(crate) fn of(clock: &dyn Clock) -> Epoch {
+        Epoch {
+            instant: clock.now(),
+            wall: clock.wall(),
+        }
+    }
+
+    /// The wall-clock time at `instant`
+    pub(crate) fn to_wall(self, instant: Instant) -> SystemTime {
+        let wall = if instant >= self.instant {
+            self.wall.checked_add(instant - self.instant)
+        } else {
+            self.wall.checked_sub(self.instant - instant)
+        };
+        wall.unwrap_or(self.wall)
+    }
+
+    /// The instant at `wall`, or `None` if it has passed. A time too far off
+    /// for an `Instant` to represent is `Some(None)`, as it never comes.
+    pub(crate) fn to_instant(self, wall: SystemTime) -> Option<Option<Instant>> {
+        let remaining = wall.duration_since(self.wall).ok()?;
+        Some(self.instant.checked_add(remaining))
+    }
+}
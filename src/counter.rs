@@ -0,0 +1,193 @@
+//! Integers stored as values, and changed atomically
+
+use std::time::Duration;
+
+/// How a counter's value is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// ASCII decimal digits, like memcached's and Redis' counters. Values
+    /// written by other clients can be read and changed as counters.
+    #[default]
+    Decimal,
+
+    /// The integer's 8 bytes, in the machine's byte order
+    Native,
+}
+
+/// What happens when a counter would go past the range of its type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// The change fails with [`Error::Overflow`](crate::Error::Overflow), and
+    /// the counter keeps its value
+    #[default]
+    Fail,
+
+    /// The counter stops at its type's minimum or maximum. memcached's `decr`
+    /// stops at zero this way.
+    Saturate,
+
+    /// The counter wraps around, like memcached's `incr` at `u64::MAX`
+    Wrap,
+}
+
+/// How [`CornerStore::incr_by_with`](crate::CornerStore::incr_by_with) and
+/// [`CornerStore::decr_by_with`](crate::CornerStore::decr_by_with) treat a
+/// counter of type `T`, which is `u64` or `i64`.
+///
+/// The default counts in ASCII decimal, fails on overflow, and starts
+/// missing keys at zero without an expiry time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Counter<T> {
+    /// How the value is stored
+    pub encoding: Encoding,
+
+    /// What happens at the limits of `T`
+    pub overflow: Overflow,
+
+    /// The value that a missing key starts at, before the change is applied.
+    /// With `None`, missing keys stay missing.
+    pub initial: Option<T>,
+
+    /// How long a key that was created by the change lives for, or forever
+    /// if this is too long to represent. Existing keys keep their expiry time.
+    pub ttl: Option<Duration>,
+
+    /// How many bytes at the start of the value aren't part of the number,
+    /// such as a header written by the application. They are kept as they
+    /// are, and a key that was created by the change starts with this many
+    /// zero bytes.
+    pub prefix: usize,
+}
+
+impl<T: CounterValue> Default for Counter<T> {
+    fn default() -> Self {
+        Counter {
+            encoding: Encoding::default(),
+            overflow: Overflow::default(),
+            initial: Some(T::ZERO),
+            ttl: None,
+            prefix: 0,
+        }
+    }
+}
+
+/// An integer type that can be stored as a counter: `u64` or `i64`.
+///
+/// This trait is sealed, and can't be implemented outside of cornerstore.
+pub trait CounterValue: private::Arithmetic {}
+
+impl CounterValue for u64 {}
+impl CounterValue for i64 {}
+
+pub(crate) mod private {
+    use std::convert::TryInto;
+
+    use super::{Encoding, Overflow};
+
+    pub trait Arithmetic: Copy + Sized {
+        const ZERO: Self;
+
+        /// Reads a value, or returns `None` if it isn't a `Self` in `encoding`
+        fn decode(bytes: &[u8], encoding: Encoding) -> Option<Self>;
+
+        fn encode(self, encoding: Encoding) -> Vec<u8>;
+
+        /// Returns `None` only when the sum overflows under [`Overflow::Fail`]
+        fn add(self, delta: Self, overflow: Overflow) -> Option<Self>;
+
+        /// Returns `None` only when the difference overflows under [`Overflow::Fail`]
+        fn sub(self, delta: Self, overflow: Overflow) -> Option<Self>;
+    }
+
+    macro_rules! arithmetic {
+        ($($int:ty),*) => {$(
+            impl Arithmetic for $int {
+                const ZERO: Self = 0;
+
+                fn decode(bytes: &[u8], encoding: Encoding) -> Option<Self> {
+                    match encoding {
+                        Encoding::Decimal => std::str::from_utf8(bytes).ok()?.parse().ok(),
+                        Encoding::Native => Some(<$int>::from_ne_bytes(bytes.try_into().ok()?)),
+                    }
+                }
+
+                fn encode(self, encoding: Encoding) -> Vec<u8> {
+                    match encoding {
+                        Encoding::Decimal => self.to_string().into_bytes(),
+                        Encoding::Native => self.to_ne_bytes().to_vec(),
+                    }
+                }
+
+                fn add(self, delta: Self, overflow: Overflow) -> Option<Self> {
+                    match overflow {
+                        Overflow::Fail => self.checked_add(delta),
+                        Overflow::Saturate => Some(self.saturating_add(delta)),
+                        Overflow::Wrap => Some(self.wrapping_add(delta)),
+                    }
+                }
+
+                fn sub(self, delta: Self, overflow: Overflow) -> Option<Self> {
+                    match overflow {
+                        Overflow::Fail => self.checked_sub(delta),
+                        Overflow::Saturate => Some(self.saturating_sub(delta)),
+                        Overflow::Wrap => Some(self.wrapping_sub(delta)),
+                    }
+                }
+            }
+        )*};
+    }
+
+    arithmetic!(u64, i64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::private::Arithmetic;
+    use super::*;
+
+    #[test]
+    fn test_counters_decode_only_their_own_encoding() {
+        assert_eq!(
+            u64::decode(b"18446744073709551615", Encoding::Decimal),
+            Some(u64::MAX)
+        );
+        assert_eq!(i64::decode(b"-42", Encoding::Decimal), Some(-42));
+        assert_eq!(u64::decode(b"-42", Encoding::Decimal), None);
+        assert_eq!(u64::decode(b"4 2", Encoding::Decimal), None);
+        assert_eq!(u64::decode(b"", Encoding::Decimal), None);
+        assert_eq!(u64::decode(&[0xff; 3], Encoding::Decimal), None);
+
+        assert_eq!(
+            i64::decode(&(-7i64).to_ne_bytes(), Encoding::Native),
+            Some(-7)
+        );
+        assert_eq!(u64::decode(b"7", Encoding::Native), None);
+
+        for encoding in [Encoding::Decimal, Encoding::Native] {
+            assert_eq!(
+                u64::decode(&u64::MAX.encode(encoding), encoding),
+                Some(u64::MAX)
+            );
+            assert_eq!(
+                i64::decode(&i64::MIN.encode(encoding), encoding),
+                Some(i64::MIN)
+            );
+        }
+    }
+
+    #[test]
+    fn test_overflow_policies() {
+        assert_eq!(u64::MAX.add(2, Overflow::Fail), None);
+        assert_eq!(u64::MAX.add(2, Overflow::Saturate), Some(u64::MAX));
+        assert_eq!(u64::MAX.add(2, Overflow::Wrap), Some(1));
+
+        assert_eq!(1u64.sub(2, Overflow::Fail), None);
+        assert_eq!(1u64.sub(2, Overflow::Saturate), Some(0));
+        assert_eq!(1u64.sub(2, Overflow::Wrap), Some(u64::MAX));
+
+        assert_eq!(0i64.sub(i64::MIN, Overflow::Fail), None);
+        assert_eq!(0i64.sub(i64::MIN, Overflow::Saturate), Some(i64::MAX));
+        assert_eq!(i64::MAX.add(1, Overflow::Wrap), Some(i64::MIN));
+        assert_eq!(5i64.add(-7, Overflow::Fail), Some(-2));
+    }
+}
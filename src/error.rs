@@ -0,0 +1,105 @@
+//! Errors returned by CornerStore
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// The error type for [`CornerStore`](crate::CornerStore) operations.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A thread panicked while writing to `shard`. The shard may hold
+    /// partially written data, so it refuses reads and writes until
+    /// [`CornerStore::recover`](crate::CornerStore::recover) is called.
+    Poisoned { shard: usize },
+
+    /// The store is full, and was built with
+    /// [`EvictionPolicy::NoEviction`](crate::EvictionPolicy::NoEviction), so
+    /// nothing was dropped to make room for the write.
+    CapacityExceeded,
+
+    /// Storing the pair would take the store over its memory limit, and it
+    /// was built with [`EvictionPolicy::NoEviction`](crate::EvictionPolicy::NoEviction).
+    OutOfMemory,
+
+    /// The key and value together are larger than a shard's share of the
+    /// store's byte or memory limit, so they could never be stored.
+    ValueTooLarge,
+
+    /// The loader passed to
+    /// [`CornerStore::get_or_try_insert_with`](crate::CornerStore::get_or_try_insert_with)
+    /// failed. Every thread that was waiting for the same key receives the
+    /// same error. Use `downcast_ref` to get the loader's own error type back.
+    Loader(Arc<dyn std::error::Error + Send + Sync>),
+
+    /// [`CornerStore::update`](crate::CornerStore::update) found no live pair
+    /// at the key to update.
+    NotFound,
+
+    /// [`CornerStore::compare_and_set`](crate::CornerStore::compare_and_set),
+    /// or one of the other `compare_and_` methods,
+    /// found a different version at the key than the one it expected, so
+    /// another writer got there first. `current` is the live pair's version,
+    /// or `None` if the key is not present or has expired.
+    Conflict { current: Option<u64> },
+
+    /// A counter's value isn't an integer of the counter's type and encoding,
+    /// so it can't be incremented or decremented.
+    NotANumber,
+
+    /// Changing a counter would take it past the range of its type, and its
+    /// [`Overflow`](crate::Overflow) policy is `Fail`. The counter keeps its value.
+    Overflow,
+
+    /// Reading or writing a snapshot or the append-only log failed.
+    Io(io::Error),
+
+    /// A snapshot or append-only log is damaged, or was written by a version
+    /// of CornerStore that uses a different format. Nothing was loaded from a
+    /// damaged snapshot.
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Poisoned { shard } => write!(
+                f,
+                "shard {} is poisoned: a writer panicked while holding its lock",
+                shard
+            ),
+            Error::CapacityExceeded => write!(f, "store is full and eviction is disabled"),
+            Error::OutOfMemory => write!(f, "store has reached its memory limit and eviction is disabled"),
+            Error::ValueTooLarge => write!(f, "key and value are larger than the store's limits allow"),
+            Error::Loader(err) => write!(f, "failed to load value: {}", err),
+            Error::NotFound => write!(f, "key is not present"),
+            Error::Conflict { current: Some(version) } => {
+                write!(f, "key has been changed since it was read, and is now at version {}", version)
+            }
+            Error::Conflict { current: None } => write!(f, "key has been removed since it was read"),
+            Error::NotANumber => write!(f, "value is not an integer of the counter's type"),
+            Error::Overflow => write!(f, "counter would overflow"),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Corrupt(reason) => write!(f, "file is unusable: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Loader(err) => Some(&**err),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// A `Result` with [`Error`] as its default error type.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
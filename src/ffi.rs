@@ -0,0 +1,280 @@
+//! C API
+//!
+//! A `CornerStore` is created with [`cnr_init`] and must be released with
+//! [`cnr_free`]. Keys and values are passed as pointer/length pairs and are
+//! copied into the store, so callers keep ownership of their buffers.
+//!
+//! Functions that can fail return [`CNR_OK`] on success, or a [libc error code]:
+//!
+//! - `EINVAL` (invalid argument) indicates that a required pointer was null,
+//!   a key was empty or a TTL was negative
+//! - `ENOENT` (no such entry) indicates that the key is not present, or has expired
+//! - `ENOTRECOVERABLE` indicates that part of the store is unusable because
+//!   another thread panicked while modifying it. Call [`cnr_recover`] to
+//!   discard the affected data and continue
+//!
+//! Values returned by [`cnr_get`] are allocated by the library and must be
+//! released with [`cnr_buf_free`].
+//!
+//! [libc error code]: https://www.gnu.org/software/libc/manual/html_node/Error-Codes.html
+
+use std::time::Duration;
+
+use crate::CornerStore;
+
+/// Indicates that the function returned successfully
+pub const CNR_OK: isize = 0;
+
+/// Create an empty, in-process cache. Returns a pointer
+/// to the new instance, which must be released with [`cnr_free`].
+#[no_mangle]
+pub extern "C" fn cnr_init() -> *mut CornerStore {
+    Box::into_raw(Box::new(CornerStore::new()))
+}
+
+/// Release a cache created by [`cnr_init`]. Passing a null pointer is a no-op.
+///
+/// # Safety
+///
+/// `store` must be null or a pointer returned by [`cnr_init`] that has not
+/// already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn cnr_free(store: *mut CornerStore) {
+    if !store.is_null() {
+        drop(Box::from_raw(store));
+    }
+}
+
+/// Sets key to value, overwriting any previous value.
+///
+/// If `ttl_ms` is not null, it points to the number of milliseconds that the
+/// key/value pair remains fresh for. A null `ttl_ms` stores an item that does
+/// not expire.
+///
+/// # Safety
+///
+/// `store` must be a live pointer returned by [`cnr_init`]. `key` must be valid
+/// for reads of `key_len` bytes, and `val` for reads of `val_len` bytes. `val`
+/// may only be null when `val_len` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn cnr_set(
+    store: *mut CornerStore,
+    key: *const u8,
+    key_len: usize,
+    val: *const u8,
+    val_len: usize,
+    ttl_ms: *const i64,
+) -> isize {
+    if store.is_null() || key.is_null() || key_len == 0 || (val.is_null() && val_len > 0) {
+        return libc::EINVAL as isize;
+    }
+
+    let ttl = if ttl_ms.is_null() {
+        None
+    } else if *ttl_ms < 0 {
+        return libc::EINVAL as isize;
+    } else {
+        Some(Duration::from_millis(*ttl_ms as u64))
+    };
+
+    let key = std::slice::from_raw_parts(key, key_len);
+    let val = if val_len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(val, val_len)
+    };
+
+    let stored = match ttl {
+        Some(ttl) => (*store).set_with_ttl(key, val, ttl),
+        None => (*store).set(key, val, None),
+    };
+    match stored {
+        Ok(_) => CNR_OK,
+        Err(_) => libc::ENOTRECOVERABLE as isize,
+    }
+}
+
+/// Retrieve the value stored at key, but only if it has not expired.
+///
+/// On success, `*val_out` points to a newly allocated copy of the value and
+/// `*val_len_out` holds its length. The buffer must be released with
+/// [`cnr_buf_free`]. When the key is absent, `ENOENT` is returned and the
+/// output parameters are left untouched.
+///
+/// # Safety
+///
+/// `store` must be a live pointer returned by [`cnr_init`]. `key` must be valid
+/// for reads of `key_len` bytes. `val_out` and `val_len_out` must be valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn cnr_get(
+    store: *const CornerStore,
+    key: *const u8,
+    key_len: usize,
+    val_out: *mut *mut u8,
+    val_len_out: *mut usize,
+) -> isize {
+    if store.is_null()
+        || key.is_null()
+        || key_len == 0
+        || val_out.is_null()
+        || val_len_out.is_null()
+    {
+        return libc::EINVAL as isize;
+    }
+
+    let key = std::slice::from_raw_parts(key, key_len);
+
+    match (*store).get(key) {
+        Ok(Some(value)) => {
+            // C callers free the copy themselves, so it can't share the stored value
+            let value = Box::<[u8]>::from(&*value);
+            *val_len_out = value.len();
+            *val_out = Box::into_raw(value) as *mut u8;
+            CNR_OK
+        }
+        Ok(None) => libc::ENOENT as isize,
+        Err(_) => libc::ENOTRECOVERABLE as isize,
+    }
+}
+
+/// Release a buffer returned by [`cnr_get`]. Passing a null pointer is a no-op.
+///
+/// # Safety
+///
+/// `buf` must be null or a buffer returned by [`cnr_get`] that has not already
+/// been freed, and `len` must be the length reported alongside it.
+#[no_mangle]
+pub unsafe extern "C" fn cnr_buf_free(buf: *mut u8, len: usize) {
+    if !buf.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(buf, len)));
+    }
+}
+
+/// Removes the key/value pair from the store. Removing a key that is
+/// not present is not an error.
+///
+/// # Safety
+///
+/// `store` must be a live pointer returned by [`cnr_init`]. `key` must be valid
+/// for reads of `key_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cnr_remove(
+    store: *mut CornerStore,
+    key: *const u8,
+    key_len: usize,
+) -> isize {
+    if store.is_null() || key.is_null() || key_len == 0 {
+        return libc::EINVAL as isize;
+    }
+
+    let key = std::slice::from_raw_parts(key, key_len);
+
+    match (*store).remove(key) {
+        Ok(()) => CNR_OK,
+        Err(_) => libc::ENOTRECOVERABLE as isize,
+    }
+}
+
+/// Remove any expired perishable items from the store
+///
+/// # Safety
+///
+/// `store` must be a live pointer returned by [`cnr_init`].
+#[no_mangle]
+pub unsafe extern "C" fn cnr_evict(store: *mut CornerStore) -> isize {
+    if store.is_null() {
+        return libc::EINVAL as isize;
+    }
+
+    match (*store).evict() {
+        Ok(_) => CNR_OK,
+        Err(_) => libc::ENOTRECOVERABLE as isize,
+    }
+}
+
+/// Makes the store usable again after a thread panicked while writing to it,
+/// discarding any data that may have been left half-written. When the
+/// discarded data can't be logged as removed, `ENOTRECOVERABLE` is returned.
+///
+/// # Safety
+///
+/// `store` must be a live pointer returned by [`cnr_init`].
+#[no_mangle]
+pub unsafe extern "C" fn cnr_recover(store: *mut CornerStore) -> isize {
+    if store.is_null() {
+        return libc::EINVAL as isize;
+    }
+
+    match (*store).recover() {
+        Ok(_) => CNR_OK,
+        Err(_) => libc::ENOTRECOVERABLE as isize,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    #[test]
+    fn test_round_trip_through_c_api() {
+        let store = cnr_init();
+        let key = b"greeting";
+        let value = b"hello";
+
+        unsafe {
+            let rc = cnr_set(
+                store,
+                key.as_ptr(),
+                key.len(),
+                value.as_ptr(),
+                value.len(),
+                ptr::null(),
+            );
+            assert_eq!(rc, CNR_OK);
+
+            let mut buf = ptr::null_mut();
+            let mut len = 0;
+            let rc = cnr_get(store, key.as_ptr(), key.len(), &mut buf, &mut len);
+            assert_eq!(rc, CNR_OK);
+            assert_eq!(std::slice::from_raw_parts(buf, len), value);
+            cnr_buf_free(buf, len);
+
+            assert_eq!(cnr_remove(store, key.as_ptr(), key.len()), CNR_OK);
+            let rc = cnr_get(store, key.as_ptr(), key.len(), &mut buf, &mut len);
+            assert_eq!(rc, libc::ENOENT as isize);
+
+            cnr_free(store);
+        }
+    }
+
+    #[test]
+    fn test_invalid_arguments_are_rejected() {
+        let store = cnr_init();
+        let key = b"greeting";
+        let negative_ttl = -1;
+
+        unsafe {
+            let rc = cnr_set(store, ptr::null(), 0, ptr::null(), 0, ptr::null());
+            assert_eq!(rc, libc::EINVAL as isize);
+
+            let rc = cnr_set(store, key.as_ptr(), key.len(), ptr::null(), 5, ptr::null());
+            assert_eq!(rc, libc::EINVAL as isize);
+
+            let rc = cnr_set(
+                store,
+                key.as_ptr(),
+                key.len(),
+                ptr::null(),
+                0,
+                &negative_ttl,
+            );
+            assert_eq!(rc, libc::EINVAL as isize);
+
+            assert_eq!(cnr_evict(ptr::null_mut()), libc::EINVAL as isize);
+
+            cnr_free(store);
+        }
+    }
+}
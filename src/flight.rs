@@ -0,0 +1,100 @@
+//! Coordination between threads that load the same missing key
+//!
+//! The first thread to miss becomes the leader and runs its loader. Threads
+//! that miss while the load is in flight wait for the leader, then share its
+//! outcome instead of running loaders of their own.
+
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+use crate::{BoH, HiddenKey, Value};
+
+/// What waiting threads learn when a load finishes
+#[derive(Debug, Clone)]
+pub(crate) enum Outcome {
+    /// The value was loaded and stored
+    Loaded(Value),
+
+    /// The loader returned an error. It is shared, but not cached.
+    Failed(Arc<dyn std::error::Error + Send + Sync>),
+
+    /// The loader panicked, or the value could not be stored. Waiters start
+    /// again, and one of them takes over as the leader.
+    Abandoned,
+}
+
+/// A load of `key` that is in progress
+#[derive(Debug)]
+pub(crate) struct Flight {
+    pub(crate) key: Value,
+    outcome: Mutex<Option<Outcome>>,
+    landed: Condvar,
+}
+
+impl Flight {
+    pub(crate) fn new(key: &[u8]) -> Arc<Flight> {
+        Arc::new(Flight {
+            key: Value::from(key),
+            outcome: Mutex::new(None),
+            landed: Condvar::new(),
+        })
+    }
+
+    /// Blocks until the leader publishes the outcome of its load
+    pub(crate) fn wait(&self) -> Outcome {
+        let outcome = self.outcome.lock().unwrap_or_else(PoisonError::into_inner);
+        let outcome = self
+            .landed
+            .wait_while(outcome, |outcome| outcome.is_none())
+            .unwrap_or_else(PoisonError::into_inner);
+        outcome.clone().expect("waited until landed")
+    }
+
+    fn finish(&self, outcome: Outcome) {
+        *self.outcome.lock().unwrap_or_else(PoisonError::into_inner) = Some(outcome);
+        self.landed.notify_all();
+    }
+}
+
+/// Held by the leader while it loads. If the leader unwinds before calling
+/// [`Landing::finish`], the flight is abandoned so that waiters can retry.
+pub(crate) struct Landing<'a> {
+    boh: &'a BoH,
+    hidden_key: HiddenKey,
+    flight: Option<Arc<Flight>>,
+}
+
+impl<'a> Landing<'a> {
+    pub(crate) fn new(boh: &'a BoH, hidden_key: HiddenKey, flight: Arc<Flight>) -> Landing<'a> {
+        Landing {
+            boh,
+            hidden_key,
+            flight: Some(flight),
+        }
+    }
+
+    /// Publishes the outcome. The caller must already have removed the
+    /// flight from its shard with [`Shard::end_flight`](crate::Shard::end_flight).
+    pub(crate) fn finish(mut self, outcome: Outcome) {
+        if let Some(flight) = self.flight.take() {
+            flight.finish(outcome);
+        }
+    }
+
+    pub(crate) fn flight(&self) -> &Arc<Flight> {
+        self.flight.as_ref().expect("not finished")
+    }
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        if let Some(flight) = self.flight.take() {
+            // the shard may have been poisoned by whatever made the leader give up
+            let shard = &self.boh.data[self.boh.shard(self.hidden_key)];
+            shard
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .end_flight(self.hidden_key, &flight);
+            flight.finish(Outcome::Abandoned);
+        }
+    }
+}
@@ -10,363 +10,2907 @@
 //!
 //! - https://doc.rust-lang.org/nightly/nightly-rustc/rustc_data_structures/sharded/index.html
 
+#[cfg(feature = "jemalloc")]
 #[global_allocator]
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-use std::hash::{Hash, Hasher};
-use std::{error::Error};
 use std::collections::{BTreeMap, HashMap};
-use std::time::{Instant};
-use std::{sync::RwLock};
+use std::convert::Infallible;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::{OnceLock, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-#[cfg(feature = "safe-input")]
-use fxhash;
 #[cfg(not(feature = "safe-input"))]
 use std::collections::hash_map::{DefaultHasher};
+#[cfg(not(feature = "safe-input"))]
+use std::hash::{Hash, Hasher};
 
-const SHARDS: usize = 128;
+mod aof;
+mod builder;
+mod clock;
+mod counter;
+mod error;
+mod flight;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod policy;
+mod reaper;
+mod refresh;
+mod snapshot;
+
+pub use aof::Fsync;
+pub use builder::Builder;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use counter::{Counter, CounterValue, Encoding, Overflow};
+pub use error::{Error, Result};
+pub use policy::EvictionPolicy;
+pub use reaper::ReaperStats;
+pub use refresh::Freshness;
+
+use aof::Aof;
+use flight::{Flight, Landing, Outcome};
+use policy::{Limits, Tracker, Usage};
+use reaper::Reaper;
+use refresh::Refresher;
+use clock::Epoch;
+use snapshot::Record;
+
+/// Number of shards in stores that don't choose their own with [`Builder::shards`]
+const DEFAULT_SHARDS: usize = 128;
+
+/// An immutable, reference-counted buffer. Stored keys and values are
+/// shared with readers rather than copied, so cloning one is cheap whatever
+/// its length.
+pub type Value = Arc<[u8]>;
+
+/// Source of pair versions. Shared by every store in the process, so that a
+/// version is never reused, even after a store is cleared or replaced.
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+#[inline]
+fn next_version() -> u64 {
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
 
-type Bytes = Vec<u8>; // we can tolerate
+/// A live pair, as read by [`CornerStore::get_entry`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub value: Value,
 
-/// HiddenKey is a pre-calculated hash of the key provided by
-/// the user. The key is stored in multiple places, keeping it
-/// as a fixed length means that memory is more manageable.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-struct HiddenKey(u64);
+    /// Changes whenever a new value is stored at the key, see [`CornerStore::get_versioned`]
+    pub version: u64,
 
-impl HiddenKey {
-    #[cfg(not(feature = "safe-input"))]
-    #[inline]
-    fn new(key: &[u8]) -> Self {
- 
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let ident = hasher.finish();
+    pub freshness: Freshness,
+
+    /// How long the pair has left before it expires, if it expires
+    pub ttl: Option<Duration>,
+
+    /// Whether the pair's refresh has been claimed, see [`CornerStore::claim_refresh`]
+    pub claimed: bool,
+}
+
+/// Hashes a user-provided key. Swappable so that callers can choose their own
+/// [`BuildHasher`], and so that tests can force collisions.
+#[derive(Clone)]
+struct KeyHasher(Arc<HashFn>);
 
-        HiddenKey(ident)
+type HashFn = dyn Fn(&[u8]) -> u64 + Send + Sync;
+
+impl KeyHasher {
+    fn new(hasher: impl Fn(&[u8]) -> u64 + Send + Sync + 'static) -> Self {
+        KeyHasher(Arc::new(hasher))
+    }
+
+    fn from_build_hasher<S: BuildHasher + Send + Sync + 'static>(build_hasher: S) -> Self {
+        KeyHasher::new(move |key| build_hasher.hash_one(key))
     }
 
-    #[cfg(feature = "safe-input")]
     #[inline]
-    fn new(key: &[u8]) -> Self {
-        let ident: u64 = fxhash::hash64(key);
+    fn hash(&self, key: &[u8]) -> u64 {
+        (self.0)(key)
+    }
+}
+
+impl Default for KeyHasher {
+    fn default() -> Self {
+        KeyHasher::new(hash_key)
+    }
+}
 
-        HiddenKey(ident)
+impl fmt::Debug for KeyHasher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyHasher")
     }
+}
+
+#[cfg(not(feature = "safe-input"))]
+#[inline]
+fn hash_key(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(feature = "safe-input")]
+#[inline]
+fn hash_key(key: &[u8]) -> u64 {
+    fxhash::hash64(key)
+}
+
+/// HiddenKey is a pre-calculated hash of the key provided by
+/// the user. The key is stored in multiple places, keeping it
+/// as a fixed length means that memory is more manageable.
+///
+/// Distinct keys may share a HiddenKey, so it only ever locates
+/// a [`Bucket`]. The full key decides which pair within the bucket
+/// is the one being asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct HiddenKey(u64);
 
+impl HiddenKey {
+    /// Which of `shards` holds this key. `shards` must be a power of two.
     #[inline]
-    fn shard(&self) -> usize {
+    fn shard(&self, shards: usize) -> usize {
         // avoid high bits and low bits, which are used by
         // the hashbrown crate (used for Rust's hashmap)
 
-        // returns a value in the range 0..=127
-        ((self.0 & 0xff000) >> 13) as usize
+        // returns a value in the range 0..shards
+        (self.0 >> 13) as usize & (shards - 1)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct KeyValuePair {
-    key: Vec<u8>,
-    value: Vec<u8>,
+    key: Value,
+    value: Value,
     expiry: Option<Instant>,
-}
 
-/// The "back of house" for the CornerStore.
-///
-/// Keys and values are untyped byte-streams of arbitrary length
-#[derive(Debug)]
-pub(crate) struct BoH {
-    //  TODO: make lock more granular
-    /// Map times to 1 or more keys. Using BTreeMap because we'll want
-    /// to take ranges of values.
-    expiry_times: RwLock<BTreeMap<Instant, Vec<HiddenKey>>>,
+    /// Taken from [`NEXT_VERSION`] when the pair is made. Changing a pair's
+    /// expiry keeps its version, storing a new value gets a new one.
+    version: u64,
 
-    /// Timestamp of when expired items were evicted from the cache  
-    created_at: Instant,
+    /// How long the pair had left to live when its expiry was last set.
+    /// `touch` uses it to push the expiry back.
+    ttl: Option<Duration>,
 
-    /// Sharded internal storage
-    data: Vec<RwLock<HashMap<HiddenKey, KeyValuePair>>>,
-}
+    /// When the value goes stale. Stale values are still served, and can be
+    /// refreshed in the background, until `expiry`.
+    stale_at: Option<Instant>,
 
-/// A thread-safe store for perishable items.
-///
-/// Keys and values are untyped byte-streams of arbitrary length
-pub struct CornerStore(std::sync::Arc<BoH>);
+    /// How long the value stays fresh, for refreshes and `touch`
+    soft_ttl: Option<Duration>,
 
-impl CornerStore {
-    pub fn new() -> Self {
-        let mut store = Vec::with_capacity(SHARDS);
-        for _ in 0..SHARDS {
-            store.push(RwLock::new(HashMap::new()));
+    /// Set by the first reader to see the pair stale, so that only one
+    /// refresh is requested, or by [`CornerStore::claim_refresh`]
+    refreshing: AtomicBool,
+
+    /// Where the shard's [`Tracker`] keeps this pair. Unused in unbounded stores.
+    node: usize,
+}
+
+impl KeyValuePair {
+    fn new(key: Value, value: Value, expiry: Option<Instant>, ttl: Option<Duration>) -> KeyValuePair {
+        KeyValuePair {
+            key,
+            value,
+            expiry,
+            version: next_version(),
+            ttl,
+            stale_at: None,
+            soft_ttl: None,
+            refreshing: AtomicBool::new(false),
+            node: 0,
         }
-        let boh = BoH {
-            data: store,
-            created_at: Instant::now(),
-            expiry_times: RwLock::new(BTreeMap::new()),
-        };
-        CornerStore(std::sync::Arc::new(boh))
     }
 
-    pub fn with_capacity(cap: usize) -> Self {
-        let mut store = Vec::with_capacity(SHARDS);
-        for _ in 0..SHARDS {
-            store.push(RwLock::new(HashMap::with_capacity(cap / SHARDS)));
+    /// A pair that expires at `expiry`, if given, with the TTL that it has at `now`
+    fn expiring_at(key: &[u8], value: &[u8], expiry: Option<Instant>, now: Instant) -> KeyValuePair {
+        let ttl = expiry.map(|expiry| expiry.saturating_duration_since(now));
+        KeyValuePair::new(Value::from(key), Value::from(value), expiry, ttl)
+    }
+
+    /// A pair holding `value` in place of this one's, with the same lifetimes
+    fn with_value(&self, value: Value) -> KeyValuePair {
+        KeyValuePair {
+            stale_at: self.stale_at,
+            soft_ttl: self.soft_ttl,
+            ..KeyValuePair::new(self.key.clone(), value, self.expiry, self.ttl)
         }
-        let boh = BoH {
-            data: store,
-            created_at: Instant::now(),
-            expiry_times: RwLock::new(BTreeMap::new()),
-        };
-        CornerStore(std::sync::Arc::new(boh))
     }
 
-    /// Get an item, but only if it has not expired
-    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>, Box<dyn Error + '_>> {
-        let hidden_key = HiddenKey::new(&key);
+    /// A pair that goes stale after `soft_ttl` and expires after `ttl`, if
+    /// they are given. See [`lifetime`] for TTLs too long to represent.
+    fn with_soft_ttl(
+        key: Value,
+        value: Value,
+        soft_ttl: Option<Duration>,
+        ttl: Option<Duration>,
+        now: Instant,
+    ) -> KeyValuePair {
+        let (stale_at, soft_ttl) = lifetime(now, soft_ttl);
+        let (expiry, ttl) = lifetime(now, ttl);
+        KeyValuePair {
+            stale_at,
+            soft_ttl,
+            ..KeyValuePair::new(key, value, expiry, ttl)
+        }
+    }
 
-        let shard = &self.0.data[hidden_key.shard()];
-        if let Some(kv_pair) = shard.read()?.get(&hidden_key) {
-            if let Some(expiry) = kv_pair.expiry {
-                if expiry <= Instant::now() {
-                    return Ok(None);
-                }
-            }
-            Ok(Some(kv_pair.value.clone()))
-        } else {
-            Ok(None)
+    /// What the pair counts for against a shard's limits. `tracked` says
+    /// whether the shard has a [`Tracker`].
+    ///
+    /// Memory is an estimate: the pair itself, its key and value, a slot in
+    /// `entries` (as if it didn't share its bucket), an entry in the expiry
+    /// index if it expires, and its eviction tracking.
+    fn usage(&self, tracked: bool) -> Usage {
+        let mut memory = size_of::<KeyValuePair>()
+            + arc_memory(&self.key)
+            + arc_memory(&self.value)
+            // hashbrown keeps a control byte for every slot
+            + size_of::<(HiddenKey, Bucket)>()
+            + 1;
+        if self.expiry.is_some() {
+            memory += size_of::<HiddenKey>() + size_of::<(Instant, Vec<HiddenKey>)>();
+        }
+        if tracked {
+            memory += Tracker::NODE_MEMORY;
         }
+        Usage {
+            len: 1,
+            bytes: self.key.len() + self.value.len(),
+            memory,
+        }
+    }
+
+    #[inline]
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expiry.is_some_and(|expiry| expiry <= now)
     }
 
-    /// Retrieve a key/value paid, but only if they have not expired
-    pub fn get_key_value(&self, key: &[u8]) -> Result<Option<(Bytes, Bytes)>, Box<dyn Error + '_>> {
-        let hidden_key = HiddenKey::new(&key);
-        let shard = &self.0.data[hidden_key.shard()];
+    #[inline]
+    fn is_stale(&self, now: Instant) -> bool {
+        self.stale_at.is_some_and(|stale_at| stale_at <= now)
+    }
+}
 
-        if let Some(kv_pair) = shard.read()?.get(&hidden_key) {
-            Ok(Some((kv_pair.key.clone(), kv_pair.value.clone())))
-        } else {
-            Ok(None)
-        }
+/// Memory used by the store's reference to `buf`. Readers may keep it
+/// alive for longer.
+#[inline]
+fn arc_memory(buf: &Value) -> usize {
+    // the strong and weak counts are stored alongside the bytes
+    2 * size_of::<usize>() + buf.len()
+}
+
+/// Expiry and TTL for `ttl` from `now`, or neither if the expiry can't be represented
+fn lifetime(now: Instant, ttl: Option<Duration>) -> (Option<Instant>, Option<Duration>) {
+    match ttl.and_then(|ttl| now.checked_add(ttl)) {
+        Some(expiry) => (Some(expiry), ttl),
+        None => (None, None),
     }
+}
 
-    /// Retrieve a value, even if it is stale
-    pub fn get_unchecked(&self, key: &[u8]) -> Result<Option<Bytes>, Box<dyn Error + '_>> {
-        let hidden_key = HiddenKey::new(&key);
-        let shard = &self.0.data[hidden_key.shard()];
+/// Every key/value pair whose key hashes to the same HiddenKey.
+/// Almost always holds a single pair.
+type Bucket = Vec<KeyValuePair>;
 
-        if let Some(kv_pair) = shard.read()?.get(&hidden_key) {
-            Ok(Some(kv_pair.value.clone()))
-        } else {
-            Ok(None)
+#[inline]
+fn find<'a>(bucket: &'a Bucket, key: &[u8]) -> Option<&'a KeyValuePair> {
+    bucket.iter().find(|kv_pair| *kv_pair.key == *key)
+}
+
+/// What a conditional write does, once it has seen the live pair at its key.
+/// See [`CornerStore::write_if`].
+enum Write {
+    /// Leave the shard as it was
+    Skip,
+    /// Store a pair at the key
+    Put(KeyValuePair),
+    /// Remove the live pair
+    Remove,
+}
+
+/// One of the partitions of the store's data. Each shard indexes the expiry
+/// times of its own keys, so writes with an expiry only contend with other
+/// writes to the same shard.
+#[derive(Debug, Default)]
+struct Shard {
+    entries: HashMap<HiddenKey, Bucket>,
+
+    /// Map times to 1 or more keys. Using BTreeMap because we'll want
+    /// to take ranges of values.
+    expiry_times: BTreeMap<Instant, Vec<HiddenKey>>,
+
+    /// What the shard holds, including its own fixed overhead. `len` can be
+    /// more than `entries.len()` when keys collide.
+    usage: Usage,
+
+    /// Decides what to evict when the shard is full. Only bounded stores have one.
+    tracker: Option<Tracker>,
+
+    /// Loads in progress, see [`CornerStore::get_or_try_insert_with`]. Keyed like
+    /// `entries`, so colliding keys share a Vec.
+    flights: HashMap<HiddenKey, Vec<Arc<Flight>>>,
+}
+
+impl Shard {
+    /// Finds the pair stored at key, letting the eviction policy know that it was asked for.
+    #[inline]
+    fn lookup(&self, hidden_key: HiddenKey, key: &[u8]) -> Option<&KeyValuePair> {
+        let kv_pair = self.entries.get(&hidden_key).and_then(|b| find(b, key));
+        if let (Some(tracker), Some(kv_pair)) = (&self.tracker, kv_pair) {
+            tracker.record_hit(kv_pair.node);
         }
+        kv_pair
     }
 
-    /// Retrieve a key/value pair, even if the pair is stale.
-    pub fn get_key_value_unchecked(
-        &self,
-        key: &[u8],
-    ) -> Result<Option<(Bytes, Bytes)>, Box<dyn Error + '_>> {
-        let hidden_key = HiddenKey::new(&key);
-        let shard = &self.0.data[hidden_key.shard()];
+    /// Adds a pair, or replaces the pair with the same key, keeping the expiry
+    /// index, the shard's size and its eviction policy up to date.
+    fn put(&mut self, hidden_key: HiddenKey, mut kv_pair: KeyValuePair) {
+        let expiry = kv_pair.expiry;
+        let usage = kv_pair.usage(self.tracker.is_some());
+
+        // collisions are rare, so don't let the first push reserve room for more
+        let bucket = self.entries.entry(hidden_key).or_insert_with(|| Vec::with_capacity(1));
+        let previous = match bucket.iter_mut().find(|existing| existing.key == kv_pair.key) {
+            Some(existing) => {
+                kv_pair.node = existing.node;
+                Some(std::mem::replace(existing, kv_pair))
+            }
+            None => {
+                if let Some(tracker) = &mut self.tracker {
+                    kv_pair.node = tracker.admit(hidden_key);
+                }
+                bucket.push(kv_pair);
+                None
+            }
+        };
 
-        if let Some(kv_pair) = shard.read()?.get(&hidden_key) {
-            Ok(Some((kv_pair.key.clone(), kv_pair.value.clone())))
-        } else {
-            Ok(None)
+        if let Some(previous) = previous {
+            self.usage -= previous.usage(self.tracker.is_some());
+            if let Some(time) = previous.expiry {
+                self.unindex_expiry(hidden_key, time);
+            }
+            if let Some(tracker) = &self.tracker {
+                tracker.record_hit(previous.node);
+            }
+        }
+        self.usage += usage;
+        if let Some(time) = expiry {
+            self.index_expiry(hidden_key, time);
         }
     }
 
-    /// Sets key to value, overwriting any previous value. Providing an optional `expiry`
-    /// time treats the key/value pair as perishable.
-    pub fn set(
-        &mut self,
-        key: &[u8],
-        val: &[u8],
-        expiry: Option<Instant>,
-    ) -> Result<(), Box<dyn Error + '_>> {
-        // willing to take the hit allocating on insertion
-        let key = key.to_vec();
-        let hidden_key = HiddenKey::new(&key);
-        let value = val.to_vec();
+    /// Removes the pair at `position` in the `hidden_key` bucket, keeping the
+    /// expiry index, the shard's size and its eviction policy up to date.
+    fn take(&mut self, hidden_key: HiddenKey, position: usize) -> KeyValuePair {
+        let bucket = self.entries.get_mut(&hidden_key).expect("bucket exists");
+        let kv_pair = bucket.swap_remove(position);
+        if bucket.is_empty() {
+            self.entries.remove(&hidden_key);
+        }
 
-        let kv_pair = KeyValuePair { key, value, expiry };
+        if let Some(expiry) = kv_pair.expiry {
+            self.unindex_expiry(hidden_key, expiry);
+        }
+        if let Some(tracker) = &mut self.tracker {
+            tracker.forget(kv_pair.node);
+        }
+        self.usage -= kv_pair.usage(self.tracker.is_some());
+        kv_pair
+    }
 
-        if let Some(time) = expiry {
-            self.0
-                .expiry_times
-                .write()?
-                .entry(time)
-                .or_insert_with(|| vec![hidden_key]);
+    /// Like [`Shard::put`], but checks `limits` first, and evicts other
+    /// pairs afterwards to stay within them.
+    fn store(&mut self, hidden_key: HiddenKey, kv_pair: KeyValuePair, limits: &Limits) -> Result<()> {
+        let tracked = self.tracker.is_some();
+        let usage = kv_pair.usage(tracked);
+
+        // pairs that wouldn't fit in an empty shard could never be stored
+        let mut alone = self.empty_usage();
+        alone += usage;
+        if limits.max_bytes.is_some_and(|max| usage.bytes > max) || limits.memory_exceeded(alone) {
+            return Err(Error::ValueTooLarge);
         }
 
-        {
-            let shard = hidden_key.shard();
-            let _ = self.0.data[shard].write()?.insert(hidden_key, kv_pair);
+        if !tracked {
+            // either unbounded, or bounded without eviction
+            let mut after = self.usage;
+            after += usage;
+            if let Some(replaced) = self.entries.get(&hidden_key).and_then(|b| find(b, &kv_pair.key)) {
+                after -= replaced.usage(tracked);
+            }
+            if limits.memory_exceeded(after) {
+                return Err(Error::OutOfMemory);
+            }
+            if limits.exceeded(after) {
+                return Err(Error::CapacityExceeded);
+            }
         }
 
+        self.put(hidden_key, kv_pair);
+        self.make_room(limits);
         Ok(())
     }
 
-    pub fn update(
-        &mut self,
-        key: &[u8],
-        val: &[u8],
-        expiry: Option<Instant>,
-    ) -> Result<(), Box<dyn Error + '_>> {
-        self.set(key, val, expiry)?;
-        Ok(())
+    /// Evicts pairs chosen by the shard's policy until it is back within `limits`.
+    fn make_room(&mut self, limits: &Limits) {
+        while limits.exceeded(self.usage) {
+            let (hidden_key, node) = match &mut self.tracker {
+                Some(tracker) => match tracker.victim() {
+                    Some(node) => (tracker.hidden_key(node), node),
+                    None => return,
+                },
+                None => return,
+            };
+            let position = self.entries[&hidden_key]
+                .iter()
+                .position(|kv_pair| kv_pair.node == node)
+                .expect("tracked pair is stored");
+            self.take(hidden_key, position);
+        }
+        if let Some(tracker) = &mut self.tracker {
+            tracker.settle();
+        }
     }
 
-    /// Removes the key/value pair from the store.
-    pub fn remove(&mut self, key: &[u8]) -> Result<(), Box<dyn Error + '_>> {
-        let hidden_key = HiddenKey::new(&key);
+    /// The load of key that is in progress, if any
+    fn flight(&self, hidden_key: HiddenKey, key: &[u8]) -> Option<Arc<Flight>> {
+        self.flights
+            .get(&hidden_key)
+            .and_then(|flights| flights.iter().find(|flight| *flight.key == *key))
+            .cloned()
+    }
 
-        let mut expiry = None;
-        {
-            let shard = &self.0.data[hidden_key.shard()];
-            let mut lock = shard.write()?;
-            if let Some(kv_pair) = lock.get_mut(&hidden_key) {
-                expiry = kv_pair.expiry.clone(); // copying out of this scope to avoid deadlock
-                lock.remove(&hidden_key);
+    fn start_flight(&mut self, hidden_key: HiddenKey, key: &[u8]) -> Arc<Flight> {
+        let flight = Flight::new(key);
+        self.flights.entry(hidden_key).or_default().push(flight.clone());
+        flight
+    }
+
+    /// Stops new readers from joining `flight`. Does nothing if it has already ended.
+    fn end_flight(&mut self, hidden_key: HiddenKey, flight: &Arc<Flight>) {
+        if let Some(flights) = self.flights.get_mut(&hidden_key) {
+            flights.retain(|other| !Arc::ptr_eq(other, flight));
+            if flights.is_empty() {
+                self.flights.remove(&hidden_key);
             }
         }
+    }
 
-        if let Some(expiry) = expiry {
-            if let Some(keys) = self.0.expiry_times.write()?.get_mut(&expiry) {
-                keys.retain(|&x| x != hidden_key);
-            }
+    /// Discards every pair
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.expiry_times.clear();
+        if let Some(tracker) = &mut self.tracker {
+            tracker.clear();
         }
+        self.usage = self.empty_usage();
+    }
 
-        Ok(())
+    /// What the shard holds when it is empty
+    fn empty_usage(&self) -> Usage {
+        Usage {
+            memory: size_of::<RwLock<Shard>>() + self.tracker.as_ref().map_or(0, Tracker::fixed_memory),
+            ..Usage::default()
+        }
     }
 
-    /// Remove any expired perishable items from the store
-    pub fn evict(&mut self) -> Result<(), Box<dyn Error + '_>> {
-        let now = Instant::now();
+    /// Records that a pair in the `hidden_key` bucket expires at `expiry`.
+    /// Called once per pair, so a HiddenKey appears as many times as there
+    /// are pairs in its bucket with that expiry time.
+    fn index_expiry(&mut self, hidden_key: HiddenKey, expiry: Instant) {
+        self.expiry_times.entry(expiry).or_default().push(hidden_key);
+    }
 
-        let mut times_to_remove = vec![];
-        let mut items_to_remove: Vec<HiddenKey> = vec![];
+    /// Reverses a single call to [`Shard::index_expiry`].
+    fn unindex_expiry(&mut self, hidden_key: HiddenKey, expiry: Instant) {
+        if let Some(keys) = self.expiry_times.get_mut(&expiry) {
+            // other keys in the same bucket may share this expiry time
+            if let Some(i) = keys.iter().position(|&x| x == hidden_key) {
+                keys.swap_remove(i);
+            }
+            if keys.is_empty() {
+                self.expiry_times.remove(&expiry);
+            }
+        }
+    }
 
-        for (expiry, items) in self.0.expiry_times.read()?.range(self.0.created_at..now) {
-            // Avoid deleting things while holding the read lock - potential deadlock
-            times_to_remove.push(expiry.clone());
-            items_to_remove.extend(items);
+    /// Describes the first way that `entries` and `expiry_times`, or the
+    /// shard's size and eviction tracking, disagree
+    fn check_invariants(&self, shard: usize, shards: usize, hasher: &KeyHasher) -> std::result::Result<(), String> {
+        let mut expected: HashMap<(Instant, HiddenKey), usize> = HashMap::new();
+        let mut usage = self.empty_usage();
+
+        for (hidden_key, bucket) in &self.entries {
+            if bucket.is_empty() {
+                return Err(format!("empty bucket {:?}", hidden_key));
+            }
+            if hidden_key.shard(shards) != shard {
+                return Err(format!("{:?} stored in shard {}", hidden_key, shard));
+            }
+            for (i, kv_pair) in bucket.iter().enumerate() {
+                if HiddenKey(hasher.hash(&kv_pair.key)) != *hidden_key {
+                    return Err(format!("key {:?} stored in bucket {:?}", kv_pair.key, hidden_key));
+                }
+                if bucket[..i].iter().any(|other| other.key == kv_pair.key) {
+                    return Err(format!("key {:?} stored twice", kv_pair.key));
+                }
+                if let Some(expiry) = kv_pair.expiry {
+                    *expected.entry((expiry, *hidden_key)).or_default() += 1;
+                }
+                if let Some(tracker) = &self.tracker {
+                    if tracker.hidden_key(kv_pair.node) != *hidden_key {
+                        return Err(format!("key {:?} tracked as {:?}", kv_pair.key, tracker.hidden_key(kv_pair.node)));
+                    }
+                }
+                usage += kv_pair.usage(self.tracker.is_some());
+            }
         }
 
-        for item in &items_to_remove {
-            self.0.data[item.shard()].write()?.remove(&item);
+        if usage != self.usage {
+            return Err(format!("holds {:?}, but counted {:?}", usage, self.usage));
         }
-        for expiry in &times_to_remove {
-            &mut self.0.expiry_times.write()?.remove(expiry);
+        if let Some(tracker) = &self.tracker {
+            if tracker.len() != usage.len {
+                return Err(format!("tracks {} pairs, but holds {}", tracker.len(), usage.len));
+            }
+        }
+
+        let mut indexed: HashMap<(Instant, HiddenKey), usize> = HashMap::new();
+        for (expiry, keys) in &self.expiry_times {
+            if keys.is_empty() {
+                return Err(format!("no keys indexed at {:?}", expiry));
+            }
+            for hidden_key in keys {
+                *indexed.entry((*expiry, *hidden_key)).or_default() += 1;
+            }
         }
 
+        if expected != indexed {
+            return Err(format!(
+                "expiry index {:?} does not match entries {:?}",
+                indexed, expected
+            ));
+        }
         Ok(())
     }
 }
 
-// /// C API.. in a pretty bad state
-
-// /// Indicates that the function returned successfully
-// pub const CNR_OK: isize = 0;
-
-// /// Create an empty, in-process cache. Returns a pointer
-// /// to the new instance.
-// #[no_mangle]
-// pub extern "C" fn cnr_init() -> *const CornerStore {
-//     let s = CornerStore::new();
-//     &s as *const _
-// }
-
-// #[no_mangle]
-// pub extern "C" fn cnr_free(s: *mut CornerStore) {
-//     unsafe {
-//         drop_in_place(s);
-//     }
-// }
-
-// /// Returns a [libc error code]
-// ///
-// ///
-// /// - 1: success
-// /// - `libc::EINVAL` (invalid argument) indicates that
-// ///
-// /// [libc error code]: https://www.gnu.org/software/libc/manual/html_node/Error-Codes.html
-// #[no_mangle]
-// pub extern "C" fn cnr_set(
-//     shuttle: *mut CornerStore,
-//     key: *mut u8,
-//     key_len: usize,
-//     val: *mut u8,
-//     val_len: usize,
-//     expiry: *const i64,
-// ) -> isize {
-//     if key.is_null() || val.is_null() || key_len == 0 || val_len == 0 {
-//         return libc::EINVAL as isize;
-//     }
-
-//     let key = unsafe { std::slice::from_raw_parts(key, key_len) };
-
-//     let val = unsafe { std::slice::from_raw_parts(val, val_len) };
-
-//     CNR_OK
-// }
+/// The "back of house" for the CornerStore.
+///
+/// Keys and values are untyped byte-streams of arbitrary length
+#[derive(Debug)]
+pub(crate) struct BoH {
+    /// Sharded internal storage
+    data: Vec<RwLock<Shard>>,
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::time::Duration;
+    /// Converts keys to HiddenKeys
+    hasher: KeyHasher,
 
-    #[test]
-    fn test_can_store_data() {
-        let mut store = CornerStore::new();
+    /// Where the time comes from
+    clock: Arc<dyn Clock>,
 
-        let key = b"greeting";
-        let expected_value = b"hello";
-        store.set(key, expected_value, None).unwrap();
+    /// Background thread that evicts expired items, if one was requested
+    reaper: OnceLock<Reaper>,
 
-        let actual_value = store.get(key).unwrap();
-        assert_eq!(actual_value.unwrap(), expected_value.to_vec())
+    /// Background thread that refreshes stale values, if one was requested
+    refresher: OnceLock<Refresher>,
+
+    /// Log of writes, and the thread that syncs and compacts it, if one was requested
+    aof: OnceLock<Aof>,
+
+    /// Each shard's share of the store's size limits
+    limits: Limits,
+
+    /// How many [`CornerStore`] handles there are. Background threads hold
+    /// the store without being counted.
+    handles: AtomicUsize,
+}
+
+impl BoH {
+    #[inline]
+    fn hidden_key(&self, key: &[u8]) -> HiddenKey {
+        HiddenKey(self.hasher.hash(key))
     }
 
-    #[test]
-    fn test_expired_data_is_not_returned() {
-        let mut store = CornerStore::new();
+    #[inline]
+    fn shard(&self, hidden_key: HiddenKey) -> usize {
+        hidden_key.shard(self.data.len())
+    }
 
-        let past = Instant::now() - Duration::new(1, 0);
+    /// Whether a pair can be served, and how fresh it is. The first read of a
+    /// stale pair asks the refresh thread, if there is one, for a new value.
+    #[inline]
+    fn freshness(&self, kv_pair: &KeyValuePair, now: Instant) -> Option<Freshness> {
+        if kv_pair.is_expired(now) {
+            return None;
+        }
+        if !kv_pair.is_stale(now) {
+            return Some(Freshness::Fresh);
+        }
+        if let Some(refresher) = self.refresher.get() {
+            if !kv_pair.refreshing.swap(true, Ordering::Relaxed) {
+                refresher.request(kv_pair.key.clone());
+            }
+        }
+        Some(Freshness::Stale)
+    }
 
-        let key = b"greeting";
-        let value = b"hello";
-        let expected_value: Result<_, Box<dyn Error>> = Ok(None);
-        store.set(key, value, Some(past)).unwrap();
+    /// Stores a refreshed value, unless the pair was removed or replaced while
+    /// the refresh hook ran. `None` leaves the stale value in place, to be
+    /// retried on a later read.
+    fn finish_refresh(&self, key: &[u8], value: Option<Value>) -> Result<()> {
+        let hidden_key = self.hidden_key(key);
+        let mut lock = self.write_shard(self.shard(hidden_key))?;
+
+        // replacements start out with `refreshing` unset
+        let kv_pair = match lock
+            .entries
+            .get(&hidden_key)
+            .and_then(|b| find(b, key))
+            .filter(|kv_pair| kv_pair.refreshing.load(Ordering::Relaxed))
+        {
+            Some(kv_pair) => kv_pair,
+            None => return Ok(()),
+        };
 
-        let actual_value = store.get(key);
-        assert_eq!(actual_value.unwrap(), expected_value.unwrap());
+        let value = match value {
+            Some(value) => value,
+            None => {
+                kv_pair.refreshing.store(false, Ordering::Relaxed);
+                return Ok(());
+            }
+        };
 
-        let expected_unchecked_value: Result<_, Box<dyn Error>> = Ok(Some(value.to_vec()));
-        let actual_unchecked_value = store.get_unchecked(key);
-        assert_eq!(
-            expected_unchecked_value.unwrap(),
-            actual_unchecked_value.unwrap()
-        );
+        let now = self.clock.now();
+        let key = kv_pair.key.clone();
+        let kv_pair = KeyValuePair::with_soft_ttl(key, value, kv_pair.soft_ttl, kv_pair.ttl, now);
+        self.store(&mut lock, hidden_key, kv_pair)
+    }
 
-        store.evict().unwrap();
+    /// Like [`Shard::store`], and logs the pair if the store has an append-only log.
+    fn store(&self, shard: &mut Shard, hidden_key: HiddenKey, kv_pair: KeyValuePair) -> Result<()> {
+        let logged = self.aof.get().map(|aof| (aof, Record::new(&kv_pair, Epoch::of(&*self.clock))));
+        shard.store(hidden_key, kv_pair, &self.limits)?;
+        match logged {
+            Some((aof, record)) => aof.set(&record),
+            None => Ok(()),
+        }
+    }
 
-        let actual_value: Result<_, Box<dyn Error>> = store.get(key);
-        assert_eq!(actual_value.unwrap(), None);
+    #[inline]
+    fn read_shard(&self, shard: usize) -> Result<RwLockReadGuard<'_, Shard>> {
+        self.data[shard].read().map_err(|_| Error::Poisoned { shard })
     }
 
-    #[test]
-    fn test_fresh_data_is_returned() {
-        let mut store = CornerStore::new();
+    #[inline]
+    fn write_shard(&self, shard: usize) -> Result<RwLockWriteGuard<'_, Shard>> {
+        self.data[shard].write().map_err(|_| Error::Poisoned { shard })
+    }
 
-        let future = Instant::now() + Duration::new(1, 0);
+    /// Removes the pairs in `shard` that expired before `now`.
+    ///
+    /// Returns the number of pairs removed and the number that remain.
+    fn evict_shard(&self, shard: usize, now: Instant) -> Result<(usize, usize)> {
+        let mut lock = self.write_shard(shard)?;
+        let lock = &mut *lock;
+
+        let fresh = lock.expiry_times.split_off(&now);
+        let expired = std::mem::replace(&mut lock.expiry_times, fresh);
+
+        let mut evicted = 0;
+        for &hidden_key in expired.values().flatten() {
+            // only the expired pairs, as the bucket may be shared with fresh ones.
+            // Uses the same cut-off as split_off, which keeps `now` in the index.
+            while let Some(position) = lock.entries.get(&hidden_key).and_then(|bucket| {
+                bucket
+                    .iter()
+                    .position(|kv_pair| kv_pair.expiry.is_some_and(|expiry| expiry < now))
+            }) {
+                lock.take(hidden_key, position);
+                evicted += 1;
+            }
+        }
 
-        let key = b"greeting";
-        let value = b"hello";
-        let expected_value: Result<_, Box<dyn Error>> = Ok(Some(value.to_vec()));
-        store.set(key, value, Some(future)).unwrap();
+        Ok((evicted, lock.entries.len()))
+    }
+}
 
-        let actual_value = store.get(key);
-        assert_eq!(actual_value.unwrap(), expected_value.unwrap())
+/// A thread-safe store for perishable items.
+///
+/// Keys and values are untyped byte-streams of arbitrary length
+///
+/// A CornerStore is a handle. Cloning it is cheap and every clone refers
+/// to the same underlying data, so clones can be handed out to threads
+/// that read and write concurrently.
+pub struct CornerStore(std::sync::Arc<BoH>);
+
+impl Clone for CornerStore {
+    fn clone(&self) -> Self {
+        self.0.handles.fetch_add(1, Ordering::Relaxed);
+        CornerStore(Arc::clone(&self.0))
+    }
+}
+
+impl Drop for CornerStore {
+    fn drop(&mut self) {
+        // the log's thread can be holding the store when the last handle goes
+        if self.0.handles.fetch_sub(1, Ordering::AcqRel) == 1 {
+            if let Some(aof) = self.0.aof.get() {
+                aof.close();
+            }
+        }
+    }
+}
+
+impl Default for CornerStore {
+    fn default() -> Self {
+        CornerStore::new()
+    }
+}
+
+impl CornerStore {
+    /// Configure a store before creating it. See [`Builder`].
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    pub fn new() -> Self {
+        CornerStore::with_capacity(0)
+    }
+
+    pub fn with_capacity(cap: usize) -> Self {
+        CornerStore::builder().capacity(cap).build()
+    }
+
+    #[cfg(test)]
+    fn with_capacity_and_hasher(cap: usize, hasher: fn(&[u8]) -> u64) -> Self {
+        let hasher = KeyHasher::new(hasher);
+        CornerStore::with_config(cap, DEFAULT_SHARDS, hasher, Limits::default(), Arc::new(SystemClock))
+    }
+
+    fn with_config(
+        cap: usize,
+        shards: usize,
+        hasher: KeyHasher,
+        limits: Limits,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        debug_assert!(shards.is_power_of_two());
+        let limits = limits.per_shard(shards);
+        let mut store = Vec::with_capacity(shards);
+        for _ in 0..shards {
+            let mut shard = Shard {
+                entries: HashMap::with_capacity(cap / shards),
+                tracker: limits.tracker(),
+                ..Shard::default()
+            };
+            shard.usage = shard.empty_usage();
+            store.push(RwLock::new(shard));
+        }
+        let boh = BoH {
+            data: store,
+            hasher,
+            clock,
+            reaper: OnceLock::new(),
+            refresher: OnceLock::new(),
+            aof: OnceLock::new(),
+            limits,
+            handles: AtomicUsize::new(1),
+        };
+        CornerStore(std::sync::Arc::new(boh))
+    }
+
+    /// Get an item, but only if it has not expired. Stale items, which are
+    /// past their soft TTL, are still returned.
+    ///
+    /// Returns a shared handle to the stored value, without copying it.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Value>> {
+        Ok(self.get_with_freshness(key)?.map(|(value, _)| value))
+    }
+
+    /// Get an item, but only if it has not expired, along with whether it is
+    /// past its soft TTL.
+    ///
+    /// If the store was built with [`Builder::refresh_ahead`], the first read
+    /// of a stale item asks the refresh hook for a new value in the background.
+    /// Until it arrives, every read gets the stale value.
+    ///
+    /// ```
+    /// use cornerstore::{CornerStore, Freshness};
+    /// use std::time::Duration;
+    ///
+    /// let store = CornerStore::new();
+    /// store.set_with_soft_ttl(b"greeting", b"hello", Duration::from_secs(60), Duration::from_secs(300))?;
+    ///
+    /// let (value, freshness) = store.get_with_freshness(b"greeting")?.unwrap();
+    /// assert_eq!((&*value, freshness), (&b"hello"[..], Freshness::Fresh));
+    /// # Ok::<(), cornerstore::Error>(())
+    /// ```
+    pub fn get_with_freshness(&self, key: &[u8]) -> Result<Option<(Value, Freshness)>> {
+        let hidden_key = self.0.hidden_key(key);
+        let shard = self.0.read_shard(self.0.shard(hidden_key))?;
+
+        Ok(shard.lookup(hidden_key, key).and_then(|kv_pair| {
+            let freshness = self.0.freshness(kv_pair, self.0.clock.now())?;
+            Some((kv_pair.value.clone(), freshness))
+        }))
+    }
+
+    /// Get an item, but only if it has not expired, along with its version.
+    ///
+    /// Every value stored at a key gets a new, larger version, so a reader can
+    /// tell whether the key has been written to since it last looked.
+    ///
+    /// ```
+    /// use cornerstore::CornerStore;
+    ///
+    /// let store = CornerStore::new();
+    /// store.set(b"greeting", b"hello", None)?;
+    /// let (_, version) = store.get_versioned(b"greeting")?.unwrap();
+    ///
+    /// store.set(b"greeting", b"kia ora", None)?;
+    /// let (value, newer) = store.get_versioned(b"greeting")?.unwrap();
+    /// assert_eq!(&*value, b"kia ora");
+    /// assert!(newer > version);
+    /// # Ok::<(), cornerstore::Error>(())
+    /// ```
+    pub fn get_versioned(&self, key: &[u8]) -> Result<Option<(Value, u64)>> {
+        let hidden_key = self.0.hidden_key(key);
+        let shard = self.0.read_shard(self.0.shard(hidden_key))?;
+
+        Ok(shard
+            .lookup(hidden_key, key)
+            .filter(|kv_pair| self.0.freshness(kv_pair, self.0.clock.now()).is_some())
+            .map(|kv_pair| (kv_pair.value.clone(), kv_pair.version)))
+    }
+
+    /// Get an item, but only if it has not expired, along with everything the
+    /// store knows about it. Stale items are returned.
+    pub fn get_entry(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let hidden_key = self.0.hidden_key(key);
+        let shard = self.0.read_shard(self.0.shard(hidden_key))?;
+        let now = self.0.clock.now();
+
+        Ok(shard.lookup(hidden_key, key).and_then(|kv_pair| {
+            let freshness = self.0.freshness(kv_pair, now)?;
+            Some(Entry {
+                value: kv_pair.value.clone(),
+                version: kv_pair.version,
+                freshness,
+                ttl: kv_pair.expiry.map(|expiry| expiry - now),
+                claimed: kv_pair.refreshing.load(Ordering::Relaxed),
+            })
+        }))
+    }
+
+    /// Passes the value stored at key to `f`, but only if it has not expired.
+    /// The value is read in place, so nothing is allocated or reference-counted.
+    ///
+    /// `f` runs while the key's shard is locked for reading. It must not write
+    /// to the store, as a write to the same shard would deadlock, and long
+    /// running closures hold up writers.
+    ///
+    /// ```
+    /// use cornerstore::CornerStore;
+    ///
+    /// let store = CornerStore::new();
+    /// store.set(b"greeting", b"hello", None)?;
+    ///
+    /// let len = store.get_with(b"greeting", |value| value.len())?;
+    /// assert_eq!(len, Some(5));
+    /// # Ok::<(), cornerstore::Error>(())
+    /// ```
+    pub fn get_with<R>(&self, key: &[u8], f: impl FnOnce(&[u8]) -> R) -> Result<Option<R>> {
+        let hidden_key = self.0.hidden_key(key);
+        let shard = self.0.read_shard(self.0.shard(hidden_key))?;
+
+        Ok(shard
+            .lookup(hidden_key, key)
+            .filter(|kv_pair| self.0.freshness(kv_pair, self.0.clock.now()).is_some())
+            .map(|kv_pair| f(&kv_pair.value)))
+    }
+
+    /// Retrieve a key/value pair, but only if it has not expired
+    pub fn get_key_value(&self, key: &[u8]) -> Result<Option<(Value, Value)>> {
+        let hidden_key = self.0.hidden_key(key);
+        let shard = self.0.read_shard(self.0.shard(hidden_key))?;
+
+        Ok(shard
+            .lookup(hidden_key, key)
+            .filter(|kv_pair| self.0.freshness(kv_pair, self.0.clock.now()).is_some())
+            .map(|kv_pair| (kv_pair.key.clone(), kv_pair.value.clone())))
+    }
+
+    /// Retrieve a value, even if it is stale
+    pub fn get_unchecked(&self, key: &[u8]) -> Result<Option<Value>> {
+        let hidden_key = self.0.hidden_key(key);
+        let shard = self.0.read_shard(self.0.shard(hidden_key))?;
+
+        if let Some(kv_pair) = shard.lookup(hidden_key, key) {
+            Ok(Some(kv_pair.value.clone()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Retrieve a key/value pair, even if the pair is stale.
+    pub fn get_key_value_unchecked(
+        &self,
+        key: &[u8],
+    ) -> Result<Option<(Value, Value)>> {
+        let hidden_key = self.0.hidden_key(key);
+        let shard = self.0.read_shard(self.0.shard(hidden_key))?;
+
+        if let Some(kv_pair) = shard.lookup(hidden_key, key) {
+            Ok(Some((kv_pair.key.clone(), kv_pair.value.clone())))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Sets key to value, overwriting any previous value. Providing an optional `expiry`
+    /// time treats the key/value pair as perishable.
+    ///
+    /// Returns the new pair's version, as [`get_versioned`](CornerStore::get_versioned)
+    /// would read it.
+    pub fn set(
+        &self,
+        key: &[u8],
+        val: &[u8],
+        expiry: Option<Instant>,
+    ) -> Result<u64> {
+        let ttl = expiry.map(|expiry| expiry.saturating_duration_since(self.0.clock.now()));
+        self.insert(key, val, expiry, ttl)
+    }
+
+    /// Sets key to value, overwriting any previous value. The pair expires
+    /// once `ttl` has elapsed, or never if `ttl` is too long to represent.
+    ///
+    /// Returns the new pair's version.
+    pub fn set_with_ttl(&self, key: &[u8], val: &[u8], ttl: Duration) -> Result<u64> {
+        let (expiry, ttl) = lifetime(self.0.clock.now(), Some(ttl));
+        self.insert(key, val, expiry, ttl)
+    }
+
+    /// Sets key to value, overwriting any previous value. The pair expires at
+    /// the wall-clock time `at`, such as a Unix timestamp from a client.
+    ///
+    /// `at` is converted to the store's monotonic clock once, when the pair is
+    /// stored, so that reads only compare `Instant`s. Changing the system time
+    /// afterwards doesn't move the expiry. Times in the past store a pair
+    /// that has already expired, and times too far off to represent store a
+    /// pair that never expires.
+    ///
+    /// Returns the new pair's version.
+    ///
+    /// ```
+    /// use cornerstore::CornerStore;
+    /// use std::time::{Duration, SystemTime};
+    ///
+    /// let store = CornerStore::new();
+    /// let at = SystemTime::now() + Duration::from_secs(60);
+    /// store.set_expiring_at(b"greeting", b"hello", at)?;
+    ///
+    /// assert!(store.ttl(b"greeting")?.unwrap() <= Duration::from_secs(60));
+    /// # Ok::<(), cornerstore::Error>(())
+    /// ```
+    pub fn set_expiring_at(&self, key: &[u8], val: &[u8], at: SystemTime) -> Result<u64> {
+        let epoch = Epoch::of(&*self.0.clock);
+        let (expiry, ttl) = match epoch.to_instant(at) {
+            Some(expiry) => (expiry, expiry.map(|expiry| expiry - epoch.instant)),
+            None => (Some(epoch.instant), Some(Duration::ZERO)),
+        };
+        self.insert(key, val, expiry, ttl)
+    }
+
+    /// Sets key to value, overwriting any previous value. The value goes stale
+    /// once `soft_ttl` has elapsed, but is still served until it expires after
+    /// `hard_ttl`. See [`CornerStore::get_with_freshness`].
+    ///
+    /// Returns the new pair's version.
+    pub fn set_with_soft_ttl(
+        &self,
+        key: &[u8],
+        val: &[u8],
+        soft_ttl: Duration,
+        hard_ttl: Duration,
+    ) -> Result<u64> {
+        let kv_pair = KeyValuePair::with_soft_ttl(
+            Value::from(key),
+            Value::from(val),
+            Some(soft_ttl),
+            Some(hard_ttl),
+            self.0.clock.now(),
+        );
+        self.insert_pair(kv_pair)
+    }
+
+    /// Returns the live value at key, or stores and returns the value made by
+    /// `loader`, which expires after `ttl` if one is given.
+    ///
+    /// When several threads miss on the same key at once, only one of them
+    /// runs its loader. The others wait for it and return the same value, so
+    /// that an expiring hot key doesn't send every reader to the database at
+    /// once. If that loader panics, one of the waiting threads runs its own.
+    ///
+    /// The loader runs without holding any locks, so it may use the store.
+    pub fn get_or_insert_with<V: Into<Value>>(
+        &self,
+        key: &[u8],
+        ttl: Option<Duration>,
+        loader: impl FnOnce() -> V,
+    ) -> Result<Value> {
+        self.get_or_try_insert_with(key, ttl, || Ok::<_, Infallible>(loader()))
+    }
+
+    /// Like [`get_or_insert_with`](CornerStore::get_or_insert_with), for loaders
+    /// that can fail. A loader's error is returned as [`Error::Loader`] to its
+    /// own thread and to every thread that was waiting for it. Errors are not
+    /// cached: the next call for the key runs a loader again.
+    ///
+    /// ```
+    /// use cornerstore::{CornerStore, Error};
+    /// use std::time::Duration;
+    ///
+    /// let store = CornerStore::new();
+    /// let ttl = Some(Duration::from_secs(60));
+    ///
+    /// let result = store.get_or_try_insert_with(b"user:7", ttl, || -> Result<Vec<u8>, std::io::Error> {
+    ///     Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "database is slow"))
+    /// });
+    /// match result {
+    ///     Err(Error::Loader(err)) => assert!(err.downcast_ref::<std::io::Error>().is_some()),
+    ///     _ => unreachable!(),
+    /// }
+    ///
+    /// let value = store.get_or_try_insert_with(b"user:7", ttl, || Ok::<_, std::io::Error>(b"ana".to_vec()))?;
+    /// assert_eq!(&*value, b"ana");
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn get_or_try_insert_with<V, E>(
+        &self,
+        key: &[u8],
+        ttl: Option<Duration>,
+        loader: impl FnOnce() -> std::result::Result<V, E>,
+    ) -> Result<Value>
+    where
+        V: Into<Value>,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let hidden_key = self.0.hidden_key(key);
+
+        let flight = loop {
+            if let Some(value) = self.get(key)? {
+                return Ok(value);
+            }
+
+            let mut lock = self.0.write_shard(self.0.shard(hidden_key))?;
+            // another thread may have stored the value since the read lock was released
+            if let Some(kv_pair) = lock.lookup(hidden_key, key) {
+                if self.0.freshness(kv_pair, self.0.clock.now()).is_some() {
+                    return Ok(kv_pair.value.clone());
+                }
+            }
+            let flight = match lock.flight(hidden_key, key) {
+                Some(flight) => flight,
+                None => break lock.start_flight(hidden_key, key),
+            };
+            drop(lock);
+
+            match flight.wait() {
+                Outcome::Loaded(value) => return Ok(value),
+                Outcome::Failed(err) => return Err(Error::Loader(err)),
+                Outcome::Abandoned => continue,
+            }
+        };
+
+        // this thread leads the flight
+        let landing = Landing::new(&self.0, hidden_key, flight);
+        let loaded = loader();
+        let (expiry, ttl) = lifetime(self.0.clock.now(), ttl);
+
+        let mut lock = self.0.write_shard(self.0.shard(hidden_key))?;
+        lock.end_flight(hidden_key, landing.flight());
+        let (outcome, result) = match loaded {
+            Ok(value) => {
+                let value = value.into();
+                let kv_pair = KeyValuePair::new(landing.flight().key.clone(), Value::clone(&value), expiry, ttl);
+                match self.0.store(&mut lock, hidden_key, kv_pair) {
+                    Ok(()) => (Outcome::Loaded(value.clone()), Ok(value)),
+                    // waiters will try for themselves, and most likely see the same error
+                    Err(err) => (Outcome::Abandoned, Err(err)),
+                }
+            }
+            Err(err) => {
+                let err: Arc<dyn std::error::Error + Send + Sync> = Arc::new(err);
+                (Outcome::Failed(err.clone()), Err(Error::Loader(err)))
+            }
+        };
+        drop(lock);
+
+        landing.finish(outcome);
+        result
+    }
+
+    /// Stores a pair. In a bounded store, this may evict other pairs, or the
+    /// new pair itself if the eviction policy decides that it is the least
+    /// valuable.
+    fn insert(
+        &self,
+        key: &[u8],
+        val: &[u8],
+        expiry: Option<Instant>,
+        ttl: Option<Duration>,
+    ) -> Result<u64> {
+        // willing to take the hit allocating on insertion
+        let kv_pair = KeyValuePair::new(Value::from(key), Value::from(val), expiry, ttl);
+        self.insert_pair(kv_pair)
+    }
+
+    /// Stores a pair, and returns its version
+    fn insert_pair(&self, kv_pair: KeyValuePair) -> Result<u64> {
+        let version = kv_pair.version;
+        let hidden_key = self.0.hidden_key(&kv_pair.key);
+        let mut lock = self.0.write_shard(self.0.shard(hidden_key))?;
+        self.0.store(&mut lock, hidden_key, kv_pair)?;
+        Ok(version)
+    }
+
+    /// Sets key to value, like [`set`](CornerStore::set), but only if a live
+    /// pair is already stored at key.
+    ///
+    /// Returns [`Error::NotFound`] if the key is not present, or has expired.
+    pub fn update(
+        &self,
+        key: &[u8],
+        val: &[u8],
+        expiry: Option<Instant>,
+    ) -> Result<()> {
+        match self.set_if_present(key, val, expiry)? {
+            Some(_) => Ok(()),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    /// Sets key to value, unless a live pair is already stored at key, like
+    /// Redis' `SET NX` or memcached's `add`. Expired pairs count as absent.
+    ///
+    /// Returns the new pair's version, or `None` if nothing was stored.
+    ///
+    /// ```
+    /// use cornerstore::CornerStore;
+    ///
+    /// let store = CornerStore::new();
+    /// assert!(store.set_if_absent(b"greeting", b"hello", None)?.is_some());
+    /// assert_eq!(store.set_if_absent(b"greeting", b"kia ora", None)?, None);
+    /// assert_eq!(&*store.get(b"greeting")?.unwrap(), b"hello");
+    /// # Ok::<(), cornerstore::Error>(())
+    /// ```
+    pub fn set_if_absent(&self, key: &[u8], val: &[u8], expiry: Option<Instant>) -> Result<Option<u64>> {
+        self.put_if(key, |live, now| match live {
+            Some(_) => None,
+            None => Some(KeyValuePair::expiring_at(key, val, expiry, now)),
+        })
+    }
+
+    /// Sets key to value, but only if a live pair is already stored at key,
+    /// like Redis' `SET XX`. The new expiry replaces the old one.
+    ///
+    /// Returns the new pair's version, or `None` if nothing was stored.
+    pub fn set_if_present(&self, key: &[u8], val: &[u8], expiry: Option<Instant>) -> Result<Option<u64>> {
+        self.put_if(key, |live, now| live.map(|_| KeyValuePair::expiring_at(key, val, expiry, now)))
+    }
+
+    /// Replaces the value of the live pair at key, like memcached's `replace`,
+    /// but keeps the pair's expiry time and soft TTL.
+    ///
+    /// Returns the new pair's version, or `None` if nothing was replaced.
+    pub fn replace(&self, key: &[u8], val: &[u8]) -> Result<Option<u64>> {
+        self.put_if(key, |live, _| live.map(|live| live.with_value(Value::from(val))))
+    }
+
+    /// Replaces the value of the live pair at key, like [`replace`](CornerStore::replace),
+    /// but only if the pair is still at `expected_version`. Together with
+    /// [`get_versioned`](CornerStore::get_versioned), this builds
+    /// read-modify-writes that keep the pair's expiry time, such as
+    /// memcached's `append`, even while other writers change it.
+    ///
+    /// Returns the new pair's version, or [`Error::Conflict`] with the version
+    /// that was found instead.
+    ///
+    /// ```
+    /// use cornerstore::{CornerStore, Error};
+    ///
+    /// let store = CornerStore::new();
+    /// store.set(b"greeting", b"hello", None)?;
+    /// let version = loop {
+    ///     let (value, version) = store.get_versioned(b"greeting")?.unwrap();
+    ///     match store.compare_and_replace(b"greeting", version, &[&value[..], b", world"].concat()) {
+    ///         // another writer got there first, so read its value and try again
+    ///         Err(Error::Conflict { current: Some(_) }) => continue,
+    ///         replaced => break replaced?,
+    ///     }
+    /// };
+    /// assert_eq!(store.get_versioned(b"greeting")?, Some((b"hello, world".to_vec().into(), version)));
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn compare_and_replace(&self, key: &[u8], expected_version: u64, val: &[u8]) -> Result<u64> {
+        let mut current = None;
+        let version = self.put_if(key, |live, _| {
+            current = live.map(|kv_pair| kv_pair.version);
+            live.filter(|live| live.version == expected_version)
+                .map(|live| live.with_value(Value::from(val)))
+        })?;
+        version.ok_or(Error::Conflict { current })
+    }
+
+    /// Removes the live pair at key, and returns its value, like Redis' `GETDEL`.
+    ///
+    /// Returns `None`, and removes nothing, if the key is not present or has expired.
+    pub fn take(&self, key: &[u8]) -> Result<Option<Value>> {
+        let (taken, _) = self.write_if(key, |live, _| match live {
+            Some(_) => Write::Remove,
+            None => Write::Skip,
+        })?;
+        Ok(taken)
+    }
+
+    /// Sets key to value, like [`set`](CornerStore::set), and returns the
+    /// value that it replaced, if the key held a live pair.
+    ///
+    /// ```
+    /// use cornerstore::CornerStore;
+    ///
+    /// let store = CornerStore::new();
+    /// assert_eq!(store.get_and_set(b"greeting", b"hello", None)?, None);
+    ///
+    /// let previous = store.get_and_set(b"greeting", b"kia ora", None)?;
+    /// assert_eq!(previous.as_deref(), Some(&b"hello"[..]));
+    /// # Ok::<(), cornerstore::Error>(())
+    /// ```
+    #[doc(alias = "swap")]
+    pub fn get_and_set(&self, key: &[u8], val: &[u8], expiry: Option<Instant>) -> Result<Option<Value>> {
+        let (previous, _) = self.write_if(key, |_, now| Write::Put(KeyValuePair::expiring_at(key, val, expiry, now)))?;
+        Ok(previous)
+    }
+
+    /// Sets key to value, but only if the live pair at key is still at
+    /// `expected_version`, as read by [`get_versioned`](CornerStore::get_versioned)
+    /// or [`get_entry`](CornerStore::get_entry). This is memcached's `cas`:
+    /// a read-modify-write that loses the race to another writer fails,
+    /// rather than overwriting the other writer's value.
+    ///
+    /// Returns the new pair's version, or [`Error::Conflict`] with the version
+    /// that was found instead.
+    ///
+    /// ```
+    /// use cornerstore::{CornerStore, Error};
+    ///
+    /// let store = CornerStore::new();
+    /// store.set(b"config", b"v1", None)?;
+    /// let (_, version) = store.get_versioned(b"config")?.unwrap();
+    ///
+    /// let updated = store.compare_and_set(b"config", version, b"v2", None)?;
+    /// match store.compare_and_set(b"config", version, b"v3", None) {
+    ///     Err(Error::Conflict { current }) => assert_eq!(current, Some(updated)),
+    ///     _ => unreachable!(),
+    /// }
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn compare_and_set(
+        &self,
+        key: &[u8],
+        expected_version: u64,
+        val: &[u8],
+        expiry: Option<Instant>,
+    ) -> Result<u64> {
+        let mut current = None;
+        let mut version = None;
+        self.write_if(key, |live, now| {
+            current = live.map(|kv_pair| kv_pair.version);
+            if current != Some(expected_version) {
+                return Write::Skip;
+            }
+            let kv_pair = KeyValuePair::expiring_at(key, val, expiry, now);
+            version = Some(kv_pair.version);
+            Write::Put(kv_pair)
+        })?;
+        version.ok_or(Error::Conflict { current })
+    }
+
+    /// Removes the live pair at key, but only if it is still at
+    /// `expected_version`, like memcached's `delete` with a CAS unique. See
+    /// [`compare_and_set`](CornerStore::compare_and_set).
+    ///
+    /// Fails with [`Error::Conflict`], and removes nothing, if the key holds
+    /// another version or is not present.
+    pub fn compare_and_remove(&self, key: &[u8], expected_version: u64) -> Result<()> {
+        let mut current = None;
+        let (_, removed) = self.write_if(key, |live, _| {
+            current = live.map(|kv_pair| kv_pair.version);
+            if current == Some(expected_version) {
+                Write::Remove
+            } else {
+                Write::Skip
+            }
+        })?;
+        if removed {
+            Ok(())
+        } else {
+            Err(Error::Conflict { current })
+        }
+    }
+
+    /// Adds one to the counter at key, like Redis' `INCR`. The counter is an
+    /// `i64` in ASCII decimal, and a missing key starts at zero.
+    ///
+    /// Returns the new value. Fails with [`Error::NotANumber`] if the key
+    /// holds something else, and [`Error::Overflow`] past `i64::MAX`.
+    ///
+    /// ```
+    /// use cornerstore::CornerStore;
+    ///
+    /// let store = CornerStore::new();
+    /// assert_eq!(store.incr(b"visits")?, 1);
+    /// assert_eq!(store.incr_by(b"visits", 10)?, 11);
+    /// assert_eq!(store.decr(b"visits")?, 10);
+    /// assert_eq!(store.get(b"visits")?.as_deref(), Some(&b"10"[..]));
+    /// # Ok::<(), cornerstore::Error>(())
+    /// ```
+    pub fn incr(&self, key: &[u8]) -> Result<i64> {
+        self.incr_by(key, 1)
+    }
+
+    /// Subtracts one from the counter at key, like Redis' `DECR`. See [`incr`](CornerStore::incr).
+    pub fn decr(&self, key: &[u8]) -> Result<i64> {
+        self.incr_by(key, -1)
+    }
+
+    /// Adds `delta` to the counter at key, like Redis' `INCRBY`. See [`incr`](CornerStore::incr).
+    pub fn incr_by(&self, key: &[u8], delta: i64) -> Result<i64> {
+        // the default counter creates missing keys, so there is always a value
+        self.incr_by_with(key, delta, &Counter::default())?
+            .map(|(value, _)| value)
+            .ok_or(Error::NotFound)
+    }
+
+    /// Adds `delta` to the counter at key, treating it as `counter` describes.
+    /// Reading, adding and writing the value happen under one lock, so
+    /// concurrent changes are never lost. The key keeps its expiry time.
+    ///
+    /// Returns the new value and the pair's new version, or `None` if the key
+    /// is missing and `counter.initial` is `None`. Fails with [`Error::NotANumber`] if the
+    /// value isn't a `T` in `counter.encoding`, and with [`Error::Overflow`]
+    /// if the sum is out of range and `counter.overflow` is [`Overflow::Fail`].
+    ///
+    /// ```
+    /// use cornerstore::{Counter, CornerStore, Encoding, Overflow};
+    /// use std::time::Duration;
+    ///
+    /// let store = CornerStore::new();
+    /// let per_minute = Counter {
+    ///     encoding: Encoding::Native,
+    ///     overflow: Overflow::Saturate,
+    ///     initial: Some(0u64),
+    ///     ttl: Some(Duration::from_secs(60)),
+    ///     ..Counter::default()
+    /// };
+    /// let (requests, _version) = store.incr_by_with(b"requests", 1, &per_minute)?.unwrap();
+    /// assert_eq!(requests, 1);
+    /// let (requests, _version) = store.incr_by_with(b"requests", u64::MAX, &per_minute)?.unwrap();
+    /// assert_eq!(requests, u64::MAX);
+    /// assert!(store.ttl(b"requests")?.is_some());
+    /// # Ok::<(), cornerstore::Error>(())
+    /// ```
+    pub fn incr_by_with<T: CounterValue>(&self, key: &[u8], delta: T, counter: &Counter<T>) -> Result<Option<(T, u64)>> {
+        self.count(key, counter, |value| value.add(delta, counter.overflow))
+    }
+
+    /// Subtracts `delta` from the counter at key, treating it as `counter`
+    /// describes. See [`incr_by_with`](CornerStore::incr_by_with).
+    ///
+    /// memcached's `decr`, which stops at zero, is a `u64` counter with
+    /// [`Overflow::Saturate`].
+    pub fn decr_by_with<T: CounterValue>(&self, key: &[u8], delta: T, counter: &Counter<T>) -> Result<Option<(T, u64)>> {
+        self.count(key, counter, |value| value.sub(delta, counter.overflow))
+    }
+
+    /// Applies `change` to the counter at key under the shard's write lock.
+    /// `change` returns `None` when the new value would overflow.
+    fn count<T: CounterValue>(
+        &self,
+        key: &[u8],
+        counter: &Counter<T>,
+        change: impl FnOnce(T) -> Option<T>,
+    ) -> Result<Option<(T, u64)>> {
+        let (expiry, ttl) = lifetime(self.0.clock.now(), counter.ttl);
+        let mut outcome = Ok(None);
+        self.write_if(key, |live, _| {
+            let current = match live {
+                Some(live) => live
+                    .value
+                    .get(counter.prefix..)
+                    .and_then(|number| T::decode(number, counter.encoding)),
+                None => counter.initial,
+            };
+            let value = match (current, live) {
+                (Some(current), _) => change(current),
+                (None, Some(_)) => {
+                    outcome = Err(Error::NotANumber);
+                    return Write::Skip;
+                }
+                (None, None) => return Write::Skip,
+            };
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    outcome = Err(Error::Overflow);
+                    return Write::Skip;
+                }
+            };
+
+            let number = value.encode(counter.encoding);
+            let kv_pair = match live {
+                Some(live) => live.with_value([&live.value[..counter.prefix], &number].concat().into()),
+                None => {
+                    let bytes = [vec![0; counter.prefix], number].concat();
+                    KeyValuePair::new(Value::from(key), bytes.into(), expiry, ttl)
+                }
+            };
+            outcome = Ok(Some((value, kv_pair.version)));
+            Write::Put(kv_pair)
+        })?;
+        outcome
+    }
+
+    /// Like [`write_if`](CornerStore::write_if), for writes that only ever
+    /// store a pair. Returns the stored pair's version.
+    fn put_if(
+        &self,
+        key: &[u8],
+        make: impl FnOnce(Option<&KeyValuePair>, Instant) -> Option<KeyValuePair>,
+    ) -> Result<Option<u64>> {
+        let mut version = None;
+        self.write_if(key, |live, now| match make(live, now) {
+            Some(kv_pair) => {
+                version = Some(kv_pair.version);
+                Write::Put(kv_pair)
+            }
+            None => Write::Skip,
+        })?;
+        Ok(version)
+    }
+
+    /// Shows `decide` the live pair at key, if there is one, then makes the
+    /// write that it asks for under the same lock. Expired pairs count as absent.
+    ///
+    /// Returns the live pair's value, and whether anything was written.
+    fn write_if(
+        &self,
+        key: &[u8],
+        decide: impl FnOnce(Option<&KeyValuePair>, Instant) -> Write,
+    ) -> Result<(Option<Value>, bool)> {
+        let hidden_key = self.0.hidden_key(key);
+        let mut lock = self.0.write_shard(self.0.shard(hidden_key))?;
+        let now = self.0.clock.now();
+
+        let position = lock.entries.get(&hidden_key).and_then(|bucket| {
+            bucket
+                .iter()
+                .position(|kv_pair| *kv_pair.key == *key && !kv_pair.is_expired(now))
+        });
+        let live = position.map(|position| &lock.entries[&hidden_key][position]);
+        let previous = live.map(|kv_pair| kv_pair.value.clone());
+
+        match (decide(live, now), position) {
+            (Write::Put(kv_pair), _) => self.0.store(&mut lock, hidden_key, kv_pair)?,
+            (Write::Remove, Some(position)) => {
+                lock.take(hidden_key, position);
+                if let Some(aof) = self.0.aof.get() {
+                    aof.remove(key)?;
+                }
+            }
+            (Write::Skip, _) | (Write::Remove, None) => return Ok((previous, false)),
+        }
+        Ok((previous, true))
+    }
+
+    /// Removes the key/value pair from the store.
+    pub fn remove(&self, key: &[u8]) -> Result<()> {
+        let hidden_key = self.0.hidden_key(key);
+
+        let mut lock = self.0.write_shard(self.0.shard(hidden_key))?;
+
+        let position = lock
+            .entries
+            .get(&hidden_key)
+            .and_then(|bucket| bucket.iter().position(|kv_pair| *kv_pair.key == *key));
+        if let Some(position) = position {
+            lock.take(hidden_key, position);
+            if let Some(aof) = self.0.aof.get() {
+                aof.remove(key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes the pair at key expire once `ttl` has elapsed. A `ttl` too long
+    /// to represent makes the pair never expire.
+    ///
+    /// Returns false if the key is not present, or has already expired.
+    pub fn expire(&self, key: &[u8], ttl: Duration) -> Result<bool> {
+        let (expiry, ttl) = lifetime(self.0.clock.now(), Some(ttl));
+        let expired = self.reschedule(key, |kv_pair, _| {
+            kv_pair.expiry = expiry;
+            kv_pair.ttl = ttl;
+        })?;
+        Ok(expired.is_some())
+    }
+
+    /// Makes the pair at key expire at `expiry`.
+    ///
+    /// Returns false if the key is not present, or has already expired.
+    pub fn expire_at(&self, key: &[u8], expiry: Instant) -> Result<bool> {
+        let expired = self.reschedule(key, |kv_pair, now| {
+            kv_pair.expiry = Some(expiry);
+            kv_pair.ttl = Some(expiry.saturating_duration_since(now));
+        })?;
+        Ok(expired.is_some())
+    }
+
+    /// Stops the pair at key from expiring.
+    ///
+    /// Returns true if the pair had an expiry time to remove.
+    pub fn persist(&self, key: &[u8]) -> Result<bool> {
+        let persisted = self.reschedule(key, |kv_pair, _| {
+            kv_pair.ttl = None;
+            kv_pair.expiry.take().is_some()
+        })?;
+        Ok(persisted.unwrap_or(false))
+    }
+
+    /// How long the pair at key has left before it expires.
+    ///
+    /// Returns `None` if the key is not present, has expired, or never expires.
+    pub fn ttl(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let hidden_key = self.0.hidden_key(key);
+        let shard = self.0.read_shard(self.0.shard(hidden_key))?;
+        let now = self.0.clock.now();
+
+        Ok(shard
+            .lookup(hidden_key, key)
+            .filter(|kv_pair| !kv_pair.is_expired(now))
+            .and_then(|kv_pair| kv_pair.expiry)
+            .map(|expiry| expiry - now))
+    }
+
+    /// When the pair at key expires, as a wall-clock time.
+    ///
+    /// Returns `None` if the key is not present, has expired, or never expires.
+    pub fn expires_at(&self, key: &[u8]) -> Result<Option<SystemTime>> {
+        let hidden_key = self.0.hidden_key(key);
+        let shard = self.0.read_shard(self.0.shard(hidden_key))?;
+        let epoch = Epoch::of(&*self.0.clock);
+
+        Ok(shard
+            .lookup(hidden_key, key)
+            .filter(|kv_pair| !kv_pair.is_expired(epoch.instant))
+            .and_then(|kv_pair| kv_pair.expiry)
+            .map(|expiry| epoch.to_wall(expiry)))
+    }
+
+    /// Pushes the pair's expiry back by the TTL that it was last given, so that
+    /// items which keep being touched stay in the store. Pairs that never
+    /// expire are left as they are. Pairs with a soft TTL become fresh again.
+    ///
+    /// Returns false if the key is not present, or has already expired.
+    pub fn touch(&self, key: &[u8]) -> Result<bool> {
+        let touched = self.reschedule(key, |kv_pair, now| {
+            if kv_pair.ttl.is_some() {
+                (kv_pair.expiry, kv_pair.ttl) = lifetime(now, kv_pair.ttl);
+            }
+            if kv_pair.soft_ttl.is_some() {
+                (kv_pair.stale_at, kv_pair.soft_ttl) = lifetime(now, kv_pair.soft_ttl);
+            }
+        })?;
+        Ok(touched.is_some())
+    }
+
+    /// Makes the pair at key stale, as if it had reached its soft TTL, so that
+    /// it is served as [`Freshness::Stale`] until a new value is stored. Any
+    /// claim on its refresh is dropped, so the next claim succeeds.
+    ///
+    /// Returns false if the key is not present, or has already expired.
+    pub fn invalidate(&self, key: &[u8]) -> Result<bool> {
+        let invalidated = self.reschedule(key, |kv_pair, now| {
+            kv_pair.stale_at = Some(now);
+            kv_pair.refreshing.store(false, Ordering::Relaxed);
+        })?;
+        Ok(invalidated.is_some())
+    }
+
+    /// Makes the pair at key stale, like [`invalidate`](CornerStore::invalidate),
+    /// but only if it is still at `expected_version`, like memcached's meta
+    /// delete with a CAS unique.
+    ///
+    /// Fails with [`Error::Conflict`], and changes nothing, if the key holds
+    /// another version or is not present.
+    pub fn compare_and_invalidate(&self, key: &[u8], expected_version: u64) -> Result<()> {
+        let invalidated = self.reschedule(key, |kv_pair, now| {
+            if kv_pair.version != expected_version {
+                return Err(kv_pair.version);
+            }
+            kv_pair.stale_at = Some(now);
+            kv_pair.refreshing.store(false, Ordering::Relaxed);
+            Ok(())
+        })?;
+        match invalidated {
+            Some(Ok(())) => Ok(()),
+            Some(Err(current)) => Err(Error::Conflict { current: Some(current) }),
+            None => Err(Error::Conflict { current: None }),
+        }
+    }
+
+    /// Claims the job of replacing the value at key, for callers that refresh
+    /// values themselves rather than with [`Builder::refresh_ahead`]. Only the
+    /// first claim on a value succeeds, so when many readers find the same
+    /// stale value, one of them fetches a new one while the rest keep
+    /// serving the stale one. Storing a new value ends the claim.
+    ///
+    /// Returns false if another caller (or the refresh thread) has already
+    /// claimed the value, or if the live value at key is no longer at `version`.
+    ///
+    /// ```
+    /// use cornerstore::CornerStore;
+    ///
+    /// let store = CornerStore::new();
+    /// store.set(b"greeting", b"hello", None)?;
+    /// store.invalidate(b"greeting")?;
+    ///
+    /// let entry = store.get_entry(b"greeting")?.unwrap();
+    /// assert!(store.claim_refresh(b"greeting", entry.version)?);
+    /// assert!(!store.claim_refresh(b"greeting", entry.version)?);
+    /// # Ok::<(), cornerstore::Error>(())
+    /// ```
+    pub fn claim_refresh(&self, key: &[u8], version: u64) -> Result<bool> {
+        let hidden_key = self.0.hidden_key(key);
+        let shard = self.0.read_shard(self.0.shard(hidden_key))?;
+        let now = self.0.clock.now();
+
+        Ok(shard
+            .lookup(hidden_key, key)
+            .filter(|kv_pair| kv_pair.version == version && !kv_pair.is_expired(now))
+            .is_some_and(|kv_pair| !kv_pair.refreshing.swap(true, Ordering::Relaxed)))
+    }
+
+    /// Lets `f` change the expiry of the live pair at key, then moves the pair
+    /// to its new place in the expiry index under the same lock.
+    fn reschedule<T>(
+        &self,
+        key: &[u8],
+        f: impl FnOnce(&mut KeyValuePair, Instant) -> T,
+    ) -> Result<Option<T>> {
+        let hidden_key = self.0.hidden_key(key);
+        let mut lock = self.0.write_shard(self.0.shard(hidden_key))?;
+        let now = self.0.clock.now();
+
+        let tracked = lock.tracker.is_some();
+
+        let kv_pair = match lock
+            .entries
+            .get_mut(&hidden_key)
+            .and_then(|bucket| bucket.iter_mut().find(|kv_pair| *kv_pair.key == *key))
+        {
+            Some(kv_pair) if !kv_pair.is_expired(now) => kv_pair,
+            _ => return Ok(None),
+        };
+
+        let previous_expiry = kv_pair.expiry;
+        let previous_usage = kv_pair.usage(tracked);
+        let result = f(kv_pair, now);
+        let expiry = kv_pair.expiry;
+        let usage = kv_pair.usage(tracked);
+        let logged = self.0.aof.get().map(|aof| (aof, Record::schedule(kv_pair, Epoch::of(&*self.0.clock))));
+
+        if previous_expiry != expiry {
+            if let Some(time) = previous_expiry {
+                lock.unindex_expiry(hidden_key, time);
+            }
+            if let Some(time) = expiry {
+                lock.index_expiry(hidden_key, time);
+            }
+        }
+
+        // indexing an expiry takes memory
+        lock.usage -= previous_usage;
+        lock.usage += usage;
+        lock.make_room(&self.0.limits);
+
+        if let Some((aof, record)) = logged {
+            aof.schedule(&record)?;
+        }
+        Ok(Some(result))
+    }
+
+    /// Remove any expired perishable items from the store
+    ///
+    /// Shards are visited one at a time, so readers and writers are only ever
+    /// blocked by the shard that is being cleaned.
+    ///
+    /// Returns the number of key/value pairs that were removed.
+    pub fn evict(&self) -> Result<usize> {
+        let now = self.0.clock.now();
+
+        let mut evicted = 0;
+        for shard in 0..self.0.data.len() {
+            evicted += self.0.evict_shard(shard, now)?.0;
+        }
+
+        Ok(evicted)
+    }
+
+    /// Removes every key/value pair from the store.
+    ///
+    /// Like [`evict`](CornerStore::evict), shards are cleared one at a time,
+    /// so pairs written to shards that have already been cleared survive.
+    /// With an [append-only log](Builder::append_log), each pair's removal is
+    /// logged.
+    pub fn clear(&self) -> Result<()> {
+        for shard in 0..self.0.data.len() {
+            let mut lock = self.0.write_shard(shard)?;
+            if let Some(aof) = self.0.aof.get() {
+                for kv_pair in lock.entries.values().flatten() {
+                    aof.remove(&kv_pair.key)?;
+                }
+            }
+            lock.clear();
+        }
+        Ok(())
+    }
+
+    /// Iterates over the keys of live pairs, a few shards at a time, in the
+    /// style of Redis' `SCAN`. Start with a cursor of 0, then pass each
+    /// returned cursor to the next call, until the returned cursor is 0 again.
+    ///
+    /// Each call reads whole shards until it has found at least `count` keys,
+    /// or run out of shards, so it may return more than `count`. Keys that are
+    /// present for the whole scan are returned exactly once. Keys that are
+    /// written or removed during the scan may or may not be returned.
+    ///
+    /// ```
+    /// use cornerstore::CornerStore;
+    ///
+    /// let store = CornerStore::new();
+    /// store.set(b"greeting", b"hello", None)?;
+    /// store.set(b"farewell", b"goodbye", None)?;
+    ///
+    /// let mut keys = Vec::new();
+    /// let mut cursor = 0;
+    /// loop {
+    ///     let (next, batch) = store.scan(cursor, 10)?;
+    ///     keys.extend(batch);
+    ///     if next == 0 {
+    ///         break;
+    ///     }
+    ///     cursor = next;
+    /// }
+    /// assert_eq!(keys.len(), 2);
+    /// # Ok::<(), cornerstore::Error>(())
+    /// ```
+    pub fn scan(&self, cursor: usize, count: usize) -> Result<(usize, Vec<Value>)> {
+        let now = self.0.clock.now();
+        let mut keys = Vec::new();
+
+        let mut shard = cursor;
+        while shard < self.0.data.len() && keys.len() < count.max(1) {
+            let lock = self.0.read_shard(shard)?;
+            let live = lock.entries.values().flatten().filter(|kv_pair| !kv_pair.is_expired(now));
+            keys.extend(live.map(|kv_pair| kv_pair.key.clone()));
+            shard += 1;
+        }
+
+        let next = if shard < self.0.data.len() { shard } else { 0 };
+        Ok((next, keys))
+    }
+
+    /// Number of key/value pairs in the store, including any that have expired
+    /// but not yet been evicted.
+    pub fn len(&self) -> Result<usize> {
+        let mut len = 0;
+        for shard in 0..self.0.data.len() {
+            len += self.0.read_shard(shard)?.usage.len;
+        }
+        Ok(len)
+    }
+
+    /// Whether the store holds no key/value pairs, including expired ones.
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Estimated memory held by the store, in bytes: every key and value,
+    /// plus the overhead of storing, indexing and tracking them, plus each
+    /// shard's fixed overhead. This is what [`Builder::max_memory`] limits.
+    ///
+    /// Allocators round allocations up, and may hold on to memory after pairs
+    /// are removed. Neither is included.
+    pub fn memory_usage(&self) -> Result<usize> {
+        Ok(self.shard_memory_usage()?.iter().sum())
+    }
+
+    /// Like [`memory_usage`](CornerStore::memory_usage), broken down by shard.
+    /// A shard that holds much more than the others points to uneven keys.
+    pub fn shard_memory_usage(&self) -> Result<Vec<usize>> {
+        (0..self.0.data.len())
+            .map(|shard| Ok(self.0.read_shard(shard)?.usage.memory))
+            .collect()
+    }
+
+    /// Writes every live pair to a snapshot file at `path`, which
+    /// [`load_snapshot`](CornerStore::load_snapshot) can read back, in this
+    /// process or a later one. Returns the number of pairs written.
+    ///
+    /// Shards are copied one at a time, so the store stays available while
+    /// the snapshot is taken, but writes made meanwhile may be included for
+    /// some shards and not others. The snapshot is written beside `path` and
+    /// then renamed over it, so `path` always holds a complete snapshot.
+    /// Expiry times are saved as wall-clock times, so TTLs keep running while
+    /// nothing has the snapshot loaded.
+    ///
+    /// ```
+    /// use cornerstore::CornerStore;
+    ///
+    /// let path = std::env::temp_dir().join("cornerstore-doc.snapshot");
+    /// let store = CornerStore::new();
+    /// store.set(b"greeting", b"hello", None)?;
+    /// assert_eq!(store.save_snapshot(&path)?, 1);
+    ///
+    /// let restored = CornerStore::new();
+    /// assert_eq!(restored.load_snapshot(&path)?, 1);
+    /// assert_eq!(&*restored.get(b"greeting")?.unwrap(), b"hello");
+    /// # std::fs::remove_file(&path)?;
+    /// # Ok::<(), cornerstore::Error>(())
+    /// ```
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<usize> {
+        snapshot::save(&self.0, path.as_ref())
+    }
+
+    /// Stores the pairs in a snapshot written by
+    /// [`save_snapshot`](CornerStore::save_snapshot), replacing any pairs
+    /// with the same keys. Pairs that have expired since the snapshot was
+    /// taken are skipped. Returns the number of pairs loaded.
+    ///
+    /// The whole snapshot is read and checked before anything is stored, so
+    /// a damaged one is refused with [`Error::Corrupt`] and leaves the store
+    /// as it was. If the store fills up, loading stops at the pair that
+    /// didn't fit, and the pairs stored before it are kept.
+    pub fn load_snapshot(&self, path: impl AsRef<Path>) -> Result<usize> {
+        snapshot::load(&self.0, path.as_ref())
+    }
+
+    /// Statistics from the background reaper, if the store was built with one.
+    pub fn reaper_stats(&self) -> Option<ReaperStats> {
+        self.0.reaper.get().map(Reaper::stats)
+    }
+
+    /// Panics if the expiry index of any shard has drifted out of sync with
+    /// the key/value pairs that it stores. Does nothing in release builds.
+    ///
+    /// Poisoned shards are skipped.
+    pub fn debug_assert_invariants(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        for shard in 0..self.0.data.len() {
+            if let Ok(lock) = self.0.read_shard(shard) {
+                if let Err(reason) = lock.check_invariants(shard, self.0.data.len(), &self.0.hasher) {
+                    panic!("shard {} is inconsistent: {}", shard, reason);
+                }
+            }
+        }
+    }
+
+    /// Makes shards usable again after a thread panicked while writing to them.
+    /// The contents of a poisoned shard can't be trusted, so they are discarded.
+    ///
+    /// Returns the number of shards that were recovered. With an
+    /// [append-only log](Builder::append_log), the discarded pairs are logged
+    /// as removed, so that they don't come back when the log is replayed. If
+    /// that fails, the shard stays poisoned and the error is returned.
+    pub fn recover(&self) -> Result<usize> {
+        let mut recovered = 0;
+        for shard in &self.0.data {
+            if shard.is_poisoned() {
+                let mut lock = shard.write().unwrap_or_else(PoisonError::into_inner);
+                if let Some(aof) = self.0.aof.get() {
+                    for kv_pair in lock.entries.values().flatten() {
+                        aof.remove(&kv_pair.key)?;
+                    }
+                }
+                lock.clear();
+                shard.clear_poison();
+                recovered += 1;
+            }
+        }
+        Ok(recovered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_can_store_data() {
+        let store = CornerStore::new();
+
+        let key = b"greeting";
+        let expected_value = b"hello";
+        store.set(key, expected_value, None).unwrap();
+
+        let actual_value = store.get(key).unwrap();
+        assert_eq!(*actual_value.unwrap(), expected_value[..])
+    }
+
+    #[test]
+    fn test_expired_data_is_not_returned() {
+        let store = CornerStore::new();
+
+        let past = Instant::now() - Duration::new(1, 0);
+
+        let key = b"greeting";
+        let value = b"hello";
+        let expected_value: Option<Value> = None;
+        store.set(key, value, Some(past)).unwrap();
+
+        let actual_value = store.get(key);
+        assert_eq!(actual_value.unwrap(), expected_value);
+
+        let expected_unchecked_value = Some(Value::from(&value[..]));
+        let actual_unchecked_value = store.get_unchecked(key);
+        assert_eq!(expected_unchecked_value, actual_unchecked_value.unwrap());
+
+        assert_eq!(store.get_key_value(key).unwrap(), None);
+        assert!(store.get_key_value_unchecked(key).unwrap().is_some());
+
+        store.evict().unwrap();
+
+        let actual_value = store.get(key);
+        assert_eq!(actual_value.unwrap(), None);
+    }
+
+    #[test]
+    fn test_fresh_data_is_returned() {
+        let store = CornerStore::new();
+
+        let future = Instant::now() + Duration::new(1, 0);
+
+        let key = b"greeting";
+        let value = b"hello";
+        let expected_value = Some(Value::from(&value[..]));
+        store.set(key, value, Some(future)).unwrap();
+
+        let actual_value = store.get(key);
+        assert_eq!(actual_value.unwrap(), expected_value)
+    }
+
+    #[test]
+    fn test_store_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync + Clone>() {}
+        assert_send_sync::<CornerStore>();
+    }
+
+    #[test]
+    fn test_clones_share_writes_across_threads() {
+        let store = CornerStore::new();
+
+        let writers: Vec<_> = (0..8)
+            .map(|t| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for i in 0..1_000 {
+                        let key = format!("{}-{}", t, i);
+                        store.set(key.as_bytes(), key.as_bytes(), None).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        for t in 0..8 {
+            for i in 0..1_000 {
+                let key = format!("{}-{}", t, i);
+                assert_eq!(store.get(key.as_bytes()).unwrap().as_deref(), Some(key.as_bytes()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_readers_never_see_torn_values_while_writers_run() {
+        let store = CornerStore::new();
+        let keys: Vec<Vec<u8>> = (0..64).map(|i| format!("key-{}", i).into_bytes()).collect();
+        for key in &keys {
+            store.set(key, &[0; 32], None).unwrap();
+        }
+
+        let mut handles = vec![];
+        for t in 0..4u8 {
+            let store = store.clone();
+            let keys = keys.clone();
+            handles.push(thread::spawn(move || {
+                for round in 0..200 {
+                    let key = &keys[(round + t as usize) % keys.len()];
+                    if round % 10 == 0 {
+                        store.remove(key).unwrap();
+                    } else {
+                        store.set(key, &[t; 32], None).unwrap();
+                    }
+                }
+            }));
+        }
+        for _ in 0..4 {
+            let store = store.clone();
+            let keys = keys.clone();
+            handles.push(thread::spawn(move || {
+                for _ in 0..50 {
+                    for key in &keys {
+                        if let Some(value) = store.get(key).unwrap() {
+                            assert_eq!(value.len(), 32);
+                            assert!(value.iter().all(|&b| b == value[0]));
+                        }
+                    }
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_errors_can_cross_threads() {
+        fn assert_error<T: std::error::Error + Send + Sync + 'static>() {}
+        assert_error::<Error>();
+    }
+
+    #[test]
+    fn test_poisoned_shard_can_be_recovered() {
+        let store = CornerStore::new();
+        let key = b"greeting";
+        store.set(key, b"hello", None).unwrap();
+
+        let shard = store.0.shard(store.0.hidden_key(key));
+        let handle = store.clone();
+        let _ = thread::spawn(move || {
+            let _lock = handle.0.data[shard].write().unwrap();
+            panic!("writer panicked while holding the lock");
+        })
+        .join();
+
+        assert!(matches!(store.get(key), Err(Error::Poisoned { shard: s }) if s == shard));
+        assert!(matches!(store.set(key, b"hello", None), Err(Error::Poisoned { .. })));
+
+        assert_eq!(store.recover().unwrap(), 1);
+        assert_eq!(store.get(key).unwrap(), None);
+        store.set(key, b"hello", None).unwrap();
+        assert_eq!(store.get(key).unwrap().as_deref(), Some(&b"hello"[..]));
+    }
+
+    /// Sends every key to the same bucket
+    fn colliding_hasher(_key: &[u8]) -> u64 {
+        42
+    }
+
+    #[test]
+    fn test_colliding_keys_are_kept_apart() {
+        let store = CornerStore::with_capacity_and_hasher(0, colliding_hasher);
+
+        store.set(b"greeting", b"hello", None).unwrap();
+        store.set(b"farewell", b"goodbye", None).unwrap();
+
+        assert_eq!(store.get(b"greeting").unwrap().as_deref(), Some(&b"hello"[..]));
+        assert_eq!(store.get(b"farewell").unwrap().as_deref(), Some(&b"goodbye"[..]));
+        assert_eq!(store.get(b"question").unwrap(), None);
+
+        store.set(b"greeting", b"kia ora", None).unwrap();
+        store.remove(b"farewell").unwrap();
+
+        assert_eq!(
+            store.get_key_value(b"greeting").unwrap(),
+            Some((Value::from(&b"greeting"[..]), Value::from(&b"kia ora"[..])))
+        );
+        assert_eq!(store.get_unchecked(b"farewell").unwrap(), None);
+    }
+
+    #[test]
+    fn test_evicting_a_colliding_key_keeps_its_neighbours() {
+        let store = CornerStore::with_capacity_and_hasher(0, colliding_hasher);
+
+        let past = Instant::now() - Duration::new(1, 0);
+        store.set(b"greeting", b"hello", Some(past)).unwrap();
+        store.set(b"farewell", b"goodbye", None).unwrap();
+
+        store.evict().unwrap();
+
+        assert_eq!(store.get_unchecked(b"greeting").unwrap(), None);
+        assert_eq!(store.get(b"farewell").unwrap().as_deref(), Some(&b"goodbye"[..]));
+    }
+
+    #[test]
+    fn test_keys_sharing_an_expiry_time_are_all_evicted() {
+        let store = CornerStore::new();
+        let past = Instant::now() - Duration::new(1, 0);
+
+        store.set(b"greeting", b"hello", Some(past)).unwrap();
+        store.set(b"farewell", b"goodbye", Some(past)).unwrap();
+        store.debug_assert_invariants();
+
+        store.evict().unwrap();
+
+        assert_eq!(store.get_unchecked(b"greeting").unwrap(), None);
+        assert_eq!(store.get_unchecked(b"farewell").unwrap(), None);
+    }
+
+    #[test]
+    fn test_clear_empties_every_shard() {
+        let store = CornerStore::builder().max_entries(10_000).build();
+        let empty = store.memory_usage().unwrap();
+        let future = Instant::now() + Duration::from_secs(60);
+        for i in 0..1_000u32 {
+            store.set(&i.to_le_bytes(), b"hello", Some(future)).unwrap();
+        }
+
+        store.clear().unwrap();
+
+        assert!(store.is_empty().unwrap());
+        assert_eq!(store.memory_usage().unwrap(), empty);
+        store.debug_assert_invariants();
+        store.set(b"greeting", b"hello", None).unwrap();
+        assert_eq!(store.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_scan_returns_each_live_key_once() {
+        let store = CornerStore::builder().shards(16).build();
+        let past = Instant::now() - Duration::from_secs(1);
+        for i in 0..1_000u32 {
+            store.set(&i.to_le_bytes(), b"hello", None).unwrap();
+        }
+        store.set(b"expired", b"goodbye", Some(past)).unwrap();
+
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        let mut calls = 0;
+        loop {
+            let (next, batch) = store.scan(cursor, 100).unwrap();
+            assert!(batch.len() >= 100 || next == 0);
+            keys.extend(batch);
+            calls += 1;
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        assert!(calls > 1);
+        assert_eq!(keys.len(), 1_000);
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 1_000);
+        assert!(!keys.contains(&Value::from(&b"expired"[..])));
+        assert_eq!(store.scan(16, 100).unwrap(), (0, vec![]));
+    }
+
+    #[test]
+    fn test_overwriting_a_key_discards_its_old_expiry() {
+        let store = CornerStore::new();
+        let past = Instant::now() - Duration::new(1, 0);
+
+        store.set(b"greeting", b"hello", Some(past)).unwrap();
+        store.set(b"greeting", b"kia ora", None).unwrap();
+        store.debug_assert_invariants();
+
+        store.evict().unwrap();
+
+        assert_eq!(store.get(b"greeting").unwrap().as_deref(), Some(&b"kia ora"[..]));
+    }
+
+    #[test]
+    fn test_ttl_reports_remaining_lifetime() {
+        let store = CornerStore::new();
+        let ttl = Duration::from_secs(60);
+
+        store.set_with_ttl(b"greeting", b"hello", ttl).unwrap();
+        store.set(b"farewell", b"goodbye", None).unwrap();
+
+        let remaining = store.ttl(b"greeting").unwrap().unwrap();
+        assert!(remaining <= ttl && remaining > ttl - Duration::from_secs(5));
+        assert_eq!(store.ttl(b"farewell").unwrap(), None);
+        assert_eq!(store.ttl(b"question").unwrap(), None);
+    }
+
+    #[test]
+    fn test_expire_and_persist_change_the_expiry_index() {
+        let store = CornerStore::new();
+        store.set(b"greeting", b"hello", None).unwrap();
+
+        assert!(store.expire_at(b"greeting", Instant::now() - Duration::from_secs(1)).unwrap());
+        store.debug_assert_invariants();
+        assert_eq!(store.get(b"greeting").unwrap(), None);
+        assert!(!store.persist(b"greeting").unwrap(), "expired keys are absent");
+
+        store.set(b"greeting", b"hello", None).unwrap();
+        assert!(!store.persist(b"greeting").unwrap(), "nothing to persist");
+        assert!(store.expire(b"greeting", Duration::from_secs(60)).unwrap());
+        assert!(store.ttl(b"greeting").unwrap().is_some());
+        assert!(store.persist(b"greeting").unwrap());
+        assert_eq!(store.ttl(b"greeting").unwrap(), None);
+        store.debug_assert_invariants();
+
+        assert!(!store.expire(b"question", Duration::from_secs(60)).unwrap());
+    }
+
+    #[test]
+    fn test_touch_pushes_expiry_back_by_the_original_ttl() {
+        let clock = ManualClock::new();
+        let store = CornerStore::builder().clock(clock.clone()).build();
+        let ttl = Duration::from_secs(60);
+        store.set_with_ttl(b"greeting", b"hello", ttl).unwrap();
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(store.ttl(b"greeting").unwrap(), Some(Duration::from_secs(40)));
+        assert!(store.touch(b"greeting").unwrap());
+        assert_eq!(store.ttl(b"greeting").unwrap(), Some(ttl));
+        store.debug_assert_invariants();
+        assert!(!store.touch(b"question").unwrap());
+    }
+
+    #[test]
+    fn test_ttls_too_long_to_represent_never_expire() {
+        let store = CornerStore::builder().shards(1).build();
+        store.set_with_ttl(b"greeting", b"hello", Duration::MAX).unwrap();
+        assert_eq!(store.ttl(b"greeting").unwrap(), None);
+
+        store.set_with_ttl(b"farewell", b"goodbye", Duration::from_secs(60)).unwrap();
+        assert!(store.expire(b"farewell", Duration::MAX).unwrap());
+        assert_eq!(store.ttl(b"farewell").unwrap(), None);
+        assert!(store.touch(b"farewell").unwrap());
+
+        // the shard was not poisoned
+        assert_eq!(store.len().unwrap(), 2);
+        assert!(store.expire(b"farewell", Duration::from_secs(60)).unwrap());
+        store.debug_assert_invariants();
+    }
+
+    #[test]
+    fn test_soft_and_loaded_ttls_too_long_to_represent_never_expire() {
+        let store = CornerStore::builder().shards(1).build();
+        store.set_with_soft_ttl(b"greeting", b"hello", Duration::MAX, Duration::MAX).unwrap();
+        assert_eq!(store.get_entry(b"greeting").unwrap().unwrap().freshness, Freshness::Fresh);
+        assert_eq!(store.ttl(b"greeting").unwrap(), None);
+        assert!(store.touch(b"greeting").unwrap());
+
+        let value = store
+            .get_or_insert_with(b"farewell", Some(Duration::MAX), || b"goodbye".to_vec())
+            .unwrap();
+        assert_eq!(&*value, b"goodbye");
+        assert_eq!(store.ttl(b"farewell").unwrap(), None);
+        assert_eq!(store.len().unwrap(), 2);
+        store.debug_assert_invariants();
+    }
+
+    #[test]
+    fn test_wall_clock_expiry_times_follow_the_store_clock() {
+        let clock = ManualClock::new();
+        let store = CornerStore::builder().clock(clock.clone()).build();
+        let at = clock.wall() + Duration::from_secs(60);
+        store.set_expiring_at(b"greeting", b"hello", at).unwrap();
+        store.set(b"farewell", b"goodbye", None).unwrap();
+
+        assert_eq!(store.ttl(b"greeting").unwrap(), Some(Duration::from_secs(60)));
+        assert_eq!(store.expires_at(b"greeting").unwrap(), Some(at));
+        assert_eq!(store.expires_at(b"farewell").unwrap(), None);
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(store.expires_at(b"greeting").unwrap(), Some(at));
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(store.get(b"greeting").unwrap(), None);
+        assert_eq!(store.expires_at(b"greeting").unwrap(), None);
+
+        store.set_expiring_at(b"farewell", b"goodbye", clock.wall() - Duration::from_secs(1)).unwrap();
+        assert_eq!(store.get(b"farewell").unwrap(), None);
+        store.debug_assert_invariants();
+    }
+
+    /// A wall clock stuck in 1970, so that late wall-clock times are further
+    /// off on the store's clock than an `Instant` can represent
+    #[derive(Debug)]
+    struct UnixEpochClock;
+
+    impl Clock for UnixEpochClock {
+        fn now(&self) -> Instant {
+            Instant::now()
+        }
+
+        fn wall(&self) -> SystemTime {
+            UNIX_EPOCH
+        }
+    }
+
+    #[test]
+    fn test_wall_clock_times_too_far_off_to_represent_never_expire() {
+        let store = CornerStore::builder().clock(UnixEpochClock).build();
+        let at = UNIX_EPOCH.checked_add(Duration::from_secs(u64::MAX / 2)).unwrap();
+        store.set_expiring_at(b"greeting", b"hello", at).unwrap();
+        assert_eq!(store.get(b"greeting").unwrap().as_deref(), Some(&b"hello"[..]));
+        assert_eq!(store.ttl(b"greeting").unwrap(), None);
+        store.debug_assert_invariants();
+    }
+
+    #[test]
+    fn test_conditional_writes_treat_expired_pairs_as_absent() {
+        let clock = ManualClock::new();
+        let store = CornerStore::builder().clock(clock.clone()).build();
+        let minute = Some(clock.now() + Duration::from_secs(60));
+
+        assert_eq!(store.set_if_present(b"greeting", b"hello", None).unwrap(), None);
+        assert!(matches!(store.update(b"greeting", b"hello", None), Err(Error::NotFound)));
+        assert_eq!(store.replace(b"greeting", b"hello").unwrap(), None);
+        assert_eq!(store.take(b"greeting").unwrap(), None);
+        assert!(store.is_empty().unwrap());
+
+        assert!(store.set_if_absent(b"greeting", b"hello", minute).unwrap().is_some());
+        assert_eq!(store.set_if_absent(b"greeting", b"hey", None).unwrap(), None);
+        assert!(store.replace(b"greeting", b"kia ora").unwrap().is_some());
+        assert_eq!(store.ttl(b"greeting").unwrap(), Some(Duration::from_secs(60)));
+        let previous = store.get_and_set(b"greeting", b"hi", minute).unwrap();
+        assert_eq!(previous.as_deref(), Some(&b"kia ora"[..]));
+
+        // expired, but not yet evicted
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(store.len().unwrap(), 1);
+        assert_eq!(store.set_if_present(b"greeting", b"hello", None).unwrap(), None);
+        assert_eq!(store.replace(b"greeting", b"hello").unwrap(), None);
+        assert_eq!(store.take(b"greeting").unwrap(), None);
+        assert_eq!(store.get_and_set(b"greeting", b"hello", None).unwrap(), None);
+        assert_eq!(store.set_if_absent(b"greeting", b"hey", None).unwrap(), None);
+
+        store.update(b"greeting", b"hey", None).unwrap();
+        assert_eq!(store.take(b"greeting").unwrap().as_deref(), Some(&b"hey"[..]));
+        assert!(store.is_empty().unwrap());
+        store.debug_assert_invariants();
+    }
+
+    #[test]
+    fn test_compare_and_set_only_writes_over_the_expected_version() {
+        let clock = ManualClock::new();
+        let store = CornerStore::builder().clock(clock.clone()).build();
+        assert!(matches!(
+            store.compare_and_set(b"config", 1, b"v1", None),
+            Err(Error::Conflict { current: None })
+        ));
+
+        store.set(b"config", b"v1", Some(clock.now() + Duration::from_secs(60))).unwrap();
+        let (_, read) = store.get_versioned(b"config").unwrap().unwrap();
+        let written = store.compare_and_set(b"config", read, b"v2", None).unwrap();
+        assert!(written > read);
+        assert_eq!(store.get_versioned(b"config").unwrap(), Some((Value::from(&b"v2"[..]), written)));
+        assert_eq!(store.ttl(b"config").unwrap(), None);
+
+        // a writer that read the old version loses
+        match store.compare_and_set(b"config", read, b"v3", None) {
+            Err(Error::Conflict { current }) => assert_eq!(current, Some(written)),
+            other => panic!("{:?}", other),
+        }
+
+        let expiry = Some(clock.now() + Duration::from_secs(1));
+        let written = store.compare_and_set(b"config", written, b"v3", expiry).unwrap();
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(
+            store.compare_and_set(b"config", written, b"v4", None),
+            Err(Error::Conflict { current: None })
+        ));
+        store.debug_assert_invariants();
+    }
+
+    #[test]
+    fn test_compare_and_remove_only_removes_the_expected_version() {
+        let store = CornerStore::new();
+        let first = store.set(b"config", b"v1", None).unwrap();
+        assert_eq!(store.get_versioned(b"config").unwrap().unwrap().1, first);
+        let second = store.set_with_ttl(b"config", b"v2", Duration::from_secs(60)).unwrap();
+        assert!(second > first);
+
+        match store.compare_and_remove(b"config", first) {
+            Err(Error::Conflict { current }) => assert_eq!(current, Some(second)),
+            other => panic!("{:?}", other),
+        }
+        assert!(store.get(b"config").unwrap().is_some());
+
+        store.compare_and_remove(b"config", second).unwrap();
+        assert_eq!(store.get(b"config").unwrap(), None);
+        assert!(matches!(
+            store.compare_and_remove(b"config", second),
+            Err(Error::Conflict { current: None })
+        ));
+        store.debug_assert_invariants();
+    }
+
+    #[test]
+    fn test_compare_and_replace_and_invalidate_keep_the_expiry() {
+        let clock = ManualClock::new();
+        let store = CornerStore::builder().clock(clock.clone()).build();
+        let first = store.set_with_ttl(b"page", b"<p>", Duration::from_secs(60)).unwrap();
+        clock.advance(Duration::from_secs(20));
+
+        assert!(matches!(
+            store.compare_and_replace(b"page", first + 1, b"<div>"),
+            Err(Error::Conflict { current: Some(current) }) if current == first
+        ));
+        let second = store.compare_and_replace(b"page", first, b"<p>hi").unwrap();
+        assert!(second > first);
+        assert_eq!(store.get(b"page").unwrap().as_deref(), Some(&b"<p>hi"[..]));
+        assert_eq!(store.ttl(b"page").unwrap(), Some(Duration::from_secs(40)));
+
+        assert!(matches!(
+            store.compare_and_invalidate(b"page", first),
+            Err(Error::Conflict { current: Some(current) }) if current == second
+        ));
+        assert_eq!(store.get_entry(b"page").unwrap().unwrap().freshness, Freshness::Fresh);
+        store.compare_and_invalidate(b"page", second).unwrap();
+        let entry = store.get_entry(b"page").unwrap().unwrap();
+        assert_eq!((entry.freshness, entry.version), (Freshness::Stale, second));
+        assert_eq!(store.ttl(b"page").unwrap(), Some(Duration::from_secs(40)));
+
+        clock.advance(Duration::from_secs(40));
+        assert!(matches!(
+            store.compare_and_replace(b"page", second, b"<p>"),
+            Err(Error::Conflict { current: None })
+        ));
+        assert!(matches!(
+            store.compare_and_invalidate(b"page", second),
+            Err(Error::Conflict { current: None })
+        ));
+        store.debug_assert_invariants();
+    }
+
+    #[test]
+    fn test_counters_keep_their_expiry_and_refuse_other_values() {
+        let clock = ManualClock::new();
+        let store = CornerStore::builder().clock(clock.clone()).build();
+        let counter = Counter {
+            encoding: Encoding::Native,
+            overflow: Overflow::Fail,
+            initial: Some(10u64),
+            ttl: Some(Duration::from_secs(60)),
+            ..Counter::default()
+        };
+        let value = |counted: Result<Option<(u64, u64)>>| counted.unwrap().map(|(value, _)| value);
+        assert_eq!(value(store.incr_by_with(b"hits", 5, &counter)), Some(15));
+        assert_eq!(store.get(b"hits").unwrap().as_deref(), Some(&15u64.to_ne_bytes()[..]));
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(value(store.decr_by_with(b"hits", 15, &counter)), Some(0));
+        assert_eq!(store.ttl(b"hits").unwrap(), Some(Duration::from_secs(30)));
+
+        // an overflow leaves the counter as it was
+        assert!(matches!(store.decr_by_with(b"hits", 1, &counter), Err(Error::Overflow)));
+        let wrapping = Counter { overflow: Overflow::Wrap, ..counter };
+        assert_eq!(value(store.decr_by_with(b"hits", 1, &wrapping)), Some(u64::MAX));
+
+        // expired counters start again from `initial`, with a new TTL
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(value(store.incr_by_with(b"hits", 1, &counter)), Some(11));
+        assert_eq!(store.ttl(b"hits").unwrap(), Some(Duration::from_secs(60)));
+
+        let forever = Counter { ttl: Some(Duration::MAX), ..counter };
+        assert_eq!(value(store.incr_by_with(b"total", 1, &forever)), Some(11));
+        assert_eq!(store.ttl(b"total").unwrap(), None);
+
+        let existing_only = Counter { initial: None, ..counter };
+        assert_eq!(value(store.incr_by_with(b"misses", 1, &existing_only)), None);
+        assert_eq!(store.get(b"misses").unwrap(), None);
+
+        // a counter after a header keeps the header, and returns the version it wrote
+        let after_header = Counter { prefix: 4, ..existing_only };
+        store.set(b"flagged", &[0, 0, 0, 7], None).unwrap();
+        assert!(matches!(store.incr_by_with(b"flagged", 1, &after_header), Err(Error::NotANumber)));
+        store.set(b"flagged", &[&[0, 0, 0, 7], &9u64.to_ne_bytes()[..]].concat(), None).unwrap();
+        let (ten, version) = store.incr_by_with(b"flagged", 1, &after_header).unwrap().unwrap();
+        let (bytes, current) = store.get_versioned(b"flagged").unwrap().unwrap();
+        assert_eq!((ten, current), (10, version));
+        assert_eq!(*bytes, [&[0, 0, 0, 7], &10u64.to_ne_bytes()[..]].concat()[..]);
+        let created = Counter { prefix: 4, ..counter };
+        assert_eq!(value(store.incr_by_with(b"created", 1, &created)), Some(11));
+        assert_eq!(store.get(b"created").unwrap().as_deref(), Some(&[&[0; 4], &11u64.to_ne_bytes()[..]].concat()[..]));
+
+        store.set(b"greeting", b"hello", None).unwrap();
+        assert!(matches!(store.incr(b"greeting"), Err(Error::NotANumber)));
+        assert!(matches!(store.incr_by_with(b"hits", 1u64, &Counter::default()), Err(Error::NotANumber)));
+        store.set(b"greeting", i64::MAX.to_string().as_bytes(), None).unwrap();
+        assert!(matches!(store.incr(b"greeting"), Err(Error::Overflow)));
+        assert_eq!(store.decr(b"greeting").unwrap(), i64::MAX - 1);
+        store.debug_assert_invariants();
+    }
+
+    #[test]
+    fn test_concurrent_increments_are_not_lost() {
+        let store = CornerStore::new();
+        let handle = store.clone();
+        let results = race(8, move || {
+            for _ in 0..1000 {
+                handle.incr(b"counter").unwrap();
+            }
+        });
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(store.get(b"counter").unwrap().as_deref(), Some(&b"8000"[..]));
+    }
+
+    #[test]
+    fn test_versions_change_with_the_value_but_not_the_expiry() {
+        let store = CornerStore::new();
+        store.set(b"greeting", b"hello", None).unwrap();
+        let (_, first) = store.get_versioned(b"greeting").unwrap().unwrap();
+
+        store.expire(b"greeting", Duration::from_secs(60)).unwrap();
+        store.touch(b"greeting").unwrap();
+        assert_eq!(store.get_versioned(b"greeting").unwrap().unwrap().1, first);
+
+        store.set(b"greeting", b"hello", None).unwrap();
+        let (_, second) = store.get_versioned(b"greeting").unwrap().unwrap();
+        assert!(second > first);
+
+        store.remove(b"greeting").unwrap();
+        assert_eq!(store.get_versioned(b"greeting").unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalidated_pairs_are_stale_and_claimed_once() {
+        let store = CornerStore::new();
+        store.set_with_ttl(b"greeting", b"hello", Duration::from_secs(60)).unwrap();
+        assert!(store.invalidate(b"greeting").unwrap());
+        assert!(!store.invalidate(b"question").unwrap());
+
+        let entry = store.get_entry(b"greeting").unwrap().unwrap();
+        assert_eq!(entry.freshness, Freshness::Stale);
+        assert!(!entry.claimed);
+        assert!(entry.ttl.unwrap() > Duration::from_secs(59));
+
+        assert!(!store.claim_refresh(b"greeting", entry.version + 1).unwrap());
+        assert!(store.claim_refresh(b"greeting", entry.version).unwrap());
+        assert!(!store.claim_refresh(b"greeting", entry.version).unwrap());
+        assert!(store.get_entry(b"greeting").unwrap().unwrap().claimed);
+
+        // a new value is fresh and unclaimed
+        store.set(b"greeting", b"kia ora", None).unwrap();
+        let entry = store.get_entry(b"greeting").unwrap().unwrap();
+        assert_eq!((entry.freshness, entry.claimed), (Freshness::Fresh, false));
+        store.debug_assert_invariants();
+    }
+
+    #[test]
+    fn test_reads_share_the_stored_value() {
+        let store = CornerStore::new();
+        store.set(b"greeting", &[7; 4096], None).unwrap();
+
+        let first = store.get(b"greeting").unwrap().unwrap();
+        let second = store.get(b"greeting").unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // readers keep their copy when the pair is overwritten or removed
+        store.set(b"greeting", b"kia ora", None).unwrap();
+        store.remove(b"greeting").unwrap();
+        assert_eq!(*first, [7; 4096]);
+    }
+
+    #[test]
+    fn test_get_with_reads_in_place() {
+        let store = CornerStore::new();
+        let past = Instant::now() - Duration::from_secs(1);
+        store.set(b"greeting", b"hello", None).unwrap();
+        store.set(b"farewell", b"goodbye", Some(past)).unwrap();
+
+        assert_eq!(store.get_with(b"greeting", |v| v.to_ascii_uppercase()).unwrap(), Some(b"HELLO".to_vec()));
+        assert_eq!(store.get_with(b"farewell", |v| v.len()).unwrap(), None);
+        assert_eq!(store.get_with(b"question", |v| v.len()).unwrap(), None);
+    }
+
+    /// Runs `f` on `n` threads at once, returning how each of them finished
+    fn race<T: Send + 'static>(n: usize, f: impl Fn() -> T + Send + Sync + 'static) -> Vec<thread::Result<T>> {
+        let f = Arc::new(f);
+        let barrier = Arc::new(std::sync::Barrier::new(n));
+        let threads: Vec<_> = (0..n)
+            .map(|_| {
+                let f = f.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    f()
+                })
+            })
+            .collect();
+        threads.into_iter().map(|t| t.join()).collect()
+    }
+
+    #[test]
+    fn test_concurrent_misses_run_one_loader() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let store = CornerStore::new();
+        let loads = Arc::new(AtomicUsize::new(0));
+
+        let values = race(8, {
+            let store = store.clone();
+            let loads = loads.clone();
+            move || {
+                store
+                    .get_or_insert_with(b"greeting", Some(Duration::from_secs(60)), || {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(100));
+                        b"hello".to_vec()
+                    })
+                    .unwrap()
+            }
+        });
+        let values: Vec<Value> = values.into_iter().map(|value| value.unwrap()).collect();
+
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(values.iter().all(|value| Arc::ptr_eq(value, &values[0])));
+        assert!(store.ttl(b"greeting").unwrap().is_some());
+        assert!(store.0.data.iter().all(|shard| shard.read().unwrap().flights.is_empty()));
+    }
+
+    #[test]
+    fn test_loader_errors_reach_every_waiter_but_are_not_cached() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let store = CornerStore::new();
+        let loads = Arc::new(AtomicUsize::new(0));
+
+        let results = race(4, {
+            let store = store.clone();
+            let loads = loads.clone();
+            move || {
+                store.get_or_try_insert_with(b"greeting", None, || {
+                    loads.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(100));
+                    Err::<Vec<u8>, _>(std::io::Error::other("database unavailable"))
+                })
+            }
+        });
+
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        for result in results {
+            match result.unwrap() {
+                Err(Error::Loader(err)) => assert!(err.downcast_ref::<std::io::Error>().is_some()),
+                other => panic!("expected a loader error, got {:?}", other),
+            }
+        }
+        assert_eq!(store.get(b"greeting").unwrap(), None);
+
+        let value = store.get_or_insert_with(b"greeting", None, || b"hello".to_vec()).unwrap();
+        assert_eq!(&*value, b"hello");
+    }
+
+    #[test]
+    fn test_waiters_take_over_from_a_panicking_loader() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let store = CornerStore::new();
+        let loads = Arc::new(AtomicUsize::new(0));
+
+        let results = race(4, {
+            let store = store.clone();
+            let loads = loads.clone();
+            move || {
+                store
+                    .get_or_insert_with(b"greeting", None, || {
+                        thread::sleep(Duration::from_millis(50));
+                        if loads.fetch_add(1, Ordering::SeqCst) == 0 {
+                            panic!("loader panicked");
+                        }
+                        b"hello".to_vec()
+                    })
+                    .unwrap()
+            }
+        });
+
+        assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert_eq!(store.get(b"greeting").unwrap().as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn test_memory_usage_follows_writes_and_removals() {
+        let store = CornerStore::new();
+        let empty = store.memory_usage().unwrap();
+        assert_eq!(store.shard_memory_usage().unwrap().len(), DEFAULT_SHARDS);
+
+        let future = Instant::now() + Duration::from_secs(60);
+        for i in 0..1_000u32 {
+            store.set(&i.to_le_bytes(), &[0; 100], Some(future)).unwrap();
+        }
+        let full = store.memory_usage().unwrap();
+        let payload = 1_000 * (4 + 100);
+        assert!(full - empty > payload, "overhead is counted");
+        assert!(full - empty < payload * 4, "{} bytes for {} of payload", full - empty, payload);
+
+        // losing the expiry frees its place in the index
+        store.persist(&0u32.to_le_bytes()).unwrap();
+        assert!(store.memory_usage().unwrap() < full);
+        store.debug_assert_invariants();
+
+        for i in 0..1_000u32 {
+            store.remove(&i.to_le_bytes()).unwrap();
+        }
+        assert_eq!(store.memory_usage().unwrap(), empty);
+    }
+
+    /// xorshift64, to keep the randomized tests reproducible without a dependency
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    /// Applies random operations to `store` and to a simple model of it, checking
+    /// the store's invariants and its contents against the model after each one.
+    fn run_random_operations(store: CornerStore, seed: u64) {
+        let now = Instant::now();
+        // few distinct times, so that keys often share an expiry
+        let expiries = [
+            None,
+            Some(now - Duration::from_secs(2)),
+            Some(now - Duration::from_secs(1)),
+            Some(now + Duration::from_secs(3600)),
+            Some(now + Duration::from_secs(7200)),
+        ];
+
+        let mut rng = Rng(seed);
+        let mut model: HashMap<Vec<u8>, (Vec<u8>, Option<Instant>)> = HashMap::new();
+
+        for step in 0..2_000u64 {
+            let key = format!("key-{}", rng.below(16)).into_bytes();
+            match rng.below(10) {
+                0..=4 => {
+                    let value = step.to_le_bytes().to_vec();
+                    let expiry = expiries[rng.below(expiries.len() as u64) as usize];
+                    store.set(&key, &value, expiry).unwrap();
+                    model.insert(key.clone(), (value, expiry));
+                }
+                5 => {
+                    let value = step.to_le_bytes().to_vec();
+                    let live = model.get(&key).is_some_and(|(_, e)| e.is_none_or(|e| e > now));
+                    match store.update(&key, &value, None) {
+                        Ok(()) if live => {
+                            model.insert(key.clone(), (value, None));
+                        }
+                        Err(Error::NotFound) if !live => {}
+                        other => panic!("update of a live={} key returned {:?}", live, other),
+                    }
+                }
+                6 if model.get(&key).is_some_and(|(_, e)| e.is_none_or(|e| e > now)) => {
+                    let expiry = expiries[rng.below(expiries.len() as u64) as usize];
+                    match expiry {
+                        Some(expiry) => assert!(store.expire_at(&key, expiry).unwrap()),
+                        None => {
+                            store.persist(&key).unwrap();
+                        }
+                    }
+                    model.get_mut(&key).unwrap().1 = expiry;
+                }
+                6..=7 => {
+                    store.remove(&key).unwrap();
+                    model.remove(&key);
+                }
+                _ => {
+                    store.evict().unwrap();
+                    model.retain(|_, (_, expiry)| expiry.is_none_or(|expiry| expiry > now));
+                }
+            }
+
+            store.debug_assert_invariants();
+            for i in 0..16 {
+                let key = format!("key-{}", i).into_bytes();
+                let expected = model.get(&key).map(|(value, _)| value.clone());
+                assert_eq!(store.get_unchecked(&key).unwrap().as_deref(), expected.as_deref(), "step {}", step);
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_operations_keep_expiry_index_in_sync() {
+        for seed in 1..=8 {
+            run_random_operations(CornerStore::new(), seed);
+        }
+    }
+
+    #[test]
+    fn test_random_operations_on_colliding_keys_keep_expiry_index_in_sync() {
+        for seed in 1..=8 {
+            run_random_operations(CornerStore::with_capacity_and_hasher(0, colliding_hasher), seed);
+        }
+    }
+
+    #[test]
+    fn test_random_operations_keep_eviction_tracking_in_sync() {
+        for policy in [
+            EvictionPolicy::Lru,
+            EvictionPolicy::Lfu,
+            EvictionPolicy::TinyLfu,
+            EvictionPolicy::Sieve,
+        ] {
+            // room for every key, so that the model never needs to know what was evicted
+            let limits = Limits {
+                max_entries: Some(DEFAULT_SHARDS * 16),
+                policy,
+                ..Limits::default()
+            };
+            for seed in 1..=4 {
+                let hasher = KeyHasher::new(colliding_hasher);
+                let store = CornerStore::with_config(0, DEFAULT_SHARDS, hasher, limits, Arc::new(SystemClock));
+                run_random_operations(store, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_operations_with_any_number_of_shards() {
+        for shards in [1, 2, 16, 1024] {
+            let store = CornerStore::builder().shards(shards).build();
+            assert_eq!(store.shard_memory_usage().unwrap().len(), shards);
+            run_random_operations(store, shards as u64);
+        }
+    }
+
+    #[test]
+    fn test_random_operations_with_a_seeded_hasher() {
+        let hasher = std::collections::hash_map::RandomState::new();
+        run_random_operations(CornerStore::builder().shards(8).hasher(hasher).build(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "power of two")]
+    fn test_shard_counts_must_be_powers_of_two() {
+        CornerStore::builder().shards(100);
+    }
+
+    #[test]
+    fn test_expiry_follows_the_store_clock() {
+        let clock = ManualClock::new();
+        let store = CornerStore::builder().clock(clock.clone()).build();
+        store.set_with_ttl(b"greeting", b"hello", Duration::from_secs(60)).unwrap();
+        store.set_with_soft_ttl(b"farewell", b"goodbye", Duration::from_secs(30), Duration::from_secs(90)).unwrap();
+
+        // real time passing makes no difference
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(store.ttl(b"greeting").unwrap(), Some(Duration::from_secs(60)));
+
+        clock.advance(Duration::from_secs(45));
+        let (_, freshness) = store.get_with_freshness(b"farewell").unwrap().unwrap();
+        assert_eq!(freshness, Freshness::Stale);
+        assert_eq!(store.evict().unwrap(), 0);
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(store.get(b"greeting").unwrap(), None);
+        assert_eq!(store.evict().unwrap(), 1);
+        assert!(store.get(b"farewell").unwrap().is_some());
+        store.debug_assert_invariants();
     }
 }
@@ -0,0 +1,725 @@
+//! Size limits, and the policies that choose what to drop when a shard is full
+//!
+//! Each shard tracks its own pairs, so enforcing a limit never needs more
+//! than the one shard lock that a write already holds. Reads only ever
+//! touch atomics here, which keeps `get` working under the shard's read lock.
+
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering::Relaxed};
+
+use crate::HiddenKey;
+
+/// Picks which key/value pair to drop when a bounded store is full.
+///
+/// See [`Builder::max_entries`](crate::Builder::max_entries) and
+/// [`Builder::max_bytes`](crate::Builder::max_bytes).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum EvictionPolicy {
+    /// Drops the least recently used pair. Reads only mark a pair as used;
+    /// it is moved to the front of the queue when eviction reaches it
+    /// ("lazy promotion"), so a read never needs a write lock.
+    #[default]
+    Lru,
+
+    /// Drops the least frequently used of the oldest few pairs, like Redis'
+    /// sampled LFU. Counts are halved once one of them saturates, so that
+    /// pairs which used to be popular don't stay forever.
+    Lfu,
+
+    /// Window TinyLFU. New pairs enter a small LRU window, and only move to
+    /// the main area if a frequency sketch shows that they are requested more
+    /// often than the pair they would replace. Every hit and every write
+    /// counts, so a key that keeps being reloaded after a miss earns its
+    /// place, while keys that are only seen once rarely displace popular ones.
+    TinyLfu,
+
+    /// SIEVE: a FIFO queue with a "visited" bit and a hand that sweeps from
+    /// the oldest pair towards the newest, keeping visited pairs in place.
+    Sieve,
+
+    /// Never drops anything. Writes that would go over a limit fail with
+    /// [`Error::CapacityExceeded`](crate::Error::CapacityExceeded), or
+    /// [`Error::OutOfMemory`](crate::Error::OutOfMemory) for the memory limit.
+    NoEviction,
+}
+
+/// How much a shard holds, in each of the units that limits are expressed in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Usage {
+    /// Number of pairs
+    pub(crate) len: usize,
+
+    /// Length of the keys and values
+    pub(crate) bytes: usize,
+
+    /// Estimated memory, including bookkeeping
+    pub(crate) memory: usize,
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.len += other.len;
+        self.bytes += other.bytes;
+        self.memory += other.memory;
+    }
+}
+
+impl std::ops::SubAssign for Usage {
+    fn sub_assign(&mut self, other: Usage) {
+        self.len -= other.len;
+        self.bytes -= other.bytes;
+        self.memory -= other.memory;
+    }
+}
+
+/// The limits that a store was built with, or a single shard's share of them
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Limits {
+    pub(crate) max_entries: Option<usize>,
+    pub(crate) max_bytes: Option<usize>,
+    pub(crate) max_memory: Option<usize>,
+    pub(crate) policy: EvictionPolicy,
+}
+
+impl Limits {
+    /// Splits the limits evenly between `shards`, rounding up
+    pub(crate) fn per_shard(self, shards: usize) -> Limits {
+        Limits {
+            max_entries: self.max_entries.map(|n| n.div_ceil(shards)),
+            max_bytes: self.max_bytes.map(|n| n.div_ceil(shards)),
+            max_memory: self.max_memory.map(|n| n.div_ceil(shards)),
+            policy: self.policy,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn exceeded(&self, usage: Usage) -> bool {
+        self.max_entries.is_some_and(|max| usage.len > max)
+            || self.max_bytes.is_some_and(|max| usage.bytes > max)
+            || self.memory_exceeded(usage)
+    }
+
+    #[inline]
+    pub(crate) fn memory_exceeded(&self, usage: Usage) -> bool {
+        self.max_memory.is_some_and(|max| usage.memory > max)
+    }
+
+    /// Shards only need a tracker when they may have to choose a victim
+    pub(crate) fn tracker(&self) -> Option<Tracker> {
+        if self.max_entries.is_none() && self.max_bytes.is_none() && self.max_memory.is_none() {
+            return None;
+        }
+        match self.policy {
+            EvictionPolicy::NoEviction => None,
+            // byte limits give no idea of the number of pairs, so guess
+            policy => Some(Tracker::new(policy, self.max_entries.unwrap_or(256))),
+        }
+    }
+}
+
+/// Marks the end of a list
+const NIL: usize = usize::MAX;
+
+/// How many pairs LFU compares when looking for a victim
+const LFU_SAMPLES: usize = 5;
+
+#[derive(Debug)]
+struct Node {
+    hidden_key: HiddenKey,
+    newer: usize,
+    older: usize,
+    /// Visited bit (LRU, SIEVE, TinyLFU's main area) or use count (LFU)
+    hits: AtomicU8,
+    in_window: bool,
+}
+
+/// A doubly-linked list threaded through `Tracker::nodes`
+#[derive(Debug, Clone, Copy)]
+struct List {
+    newest: usize,
+    oldest: usize,
+    len: usize,
+}
+
+impl List {
+    const EMPTY: List = List {
+        newest: NIL,
+        oldest: NIL,
+        len: 0,
+    };
+}
+
+/// A shard's record of its pairs, in the order that `policy` needs.
+#[derive(Debug)]
+pub(crate) struct Tracker {
+    policy: EvictionPolicy,
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    main: List,
+    /// TinyLFU's admission window
+    window: List,
+    /// SIEVE's hand. NIL means start again from the oldest pair.
+    hand: usize,
+    /// Set by readers when an LFU count saturates, so the next writer ages them all
+    saturated: AtomicBool,
+    sketch: Option<Sketch>,
+}
+
+impl Tracker {
+    /// Memory used for each tracked pair
+    pub(crate) const NODE_MEMORY: usize = std::mem::size_of::<Node>() + std::mem::size_of::<usize>();
+
+    /// `capacity` is a rough number of pairs that the shard will hold, used to
+    /// size TinyLFU's frequency sketch.
+    pub(crate) fn new(policy: EvictionPolicy, capacity: usize) -> Tracker {
+        let sketch = match policy {
+            EvictionPolicy::TinyLfu => Some(Sketch::new(capacity)),
+            _ => None,
+        };
+        Tracker {
+            policy,
+            nodes: Vec::new(),
+            free: Vec::new(),
+            main: List::EMPTY,
+            window: List::EMPTY,
+            hand: NIL,
+            saturated: AtomicBool::new(false),
+            sketch,
+        }
+    }
+
+    /// Number of pairs being tracked
+    pub(crate) fn len(&self) -> usize {
+        self.main.len + self.window.len
+    }
+
+    /// Memory that the tracker holds no matter how many pairs it tracks
+    pub(crate) fn fixed_memory(&self) -> usize {
+        self.sketch.as_ref().map_or(0, |sketch| sketch.counters.len())
+    }
+
+    /// Forgets every pair, but keeps the frequencies that TinyLFU has learned
+    pub(crate) fn clear(&mut self) {
+        self.nodes.clear();
+        self.free.clear();
+        self.main = List::EMPTY;
+        self.window = List::EMPTY;
+        self.hand = NIL;
+    }
+
+    pub(crate) fn hidden_key(&self, node: usize) -> HiddenKey {
+        self.nodes[node].hidden_key
+    }
+
+    /// Starts tracking a new pair. Returns the node that the pair must keep
+    /// hold of, so that it can later be passed to [`Tracker::forget`].
+    pub(crate) fn admit(&mut self, hidden_key: HiddenKey) -> usize {
+        let in_window = self.policy == EvictionPolicy::TinyLfu;
+        let node = Node {
+            hidden_key,
+            newer: NIL,
+            older: NIL,
+            hits: AtomicU8::new(0),
+            in_window,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        self.link_newest(index);
+
+        if let Some(sketch) = &mut self.sketch {
+            sketch.increment(hidden_key);
+            sketch.age();
+        }
+        index
+    }
+
+    /// Stops tracking a pair that has been removed from the shard.
+    pub(crate) fn forget(&mut self, node: usize) {
+        if self.hand == node {
+            self.hand = self.nodes[node].newer;
+        }
+        self.unlink(node);
+        self.free.push(node);
+    }
+
+    /// Records a read or overwrite of a tracked pair.
+    #[inline]
+    pub(crate) fn record_hit(&self, node: usize) {
+        let hits = &self.nodes[node].hits;
+        match self.policy {
+            EvictionPolicy::Lfu => {
+                let count = hits.load(Relaxed);
+                if count < u8::MAX {
+                    hits.store(count + 1, Relaxed);
+                } else {
+                    self.saturated.store(true, Relaxed);
+                }
+            }
+            _ => hits.store(1, Relaxed),
+        }
+        if let Some(sketch) = &self.sketch {
+            sketch.increment(self.nodes[node].hidden_key);
+        }
+    }
+
+    /// Called after a write that left the shard within its limits. Lets
+    /// TinyLFU's window overflow into the main area while there is room.
+    pub(crate) fn settle(&mut self) {
+        while self.window.len > self.window_capacity() {
+            let candidate = self.window.oldest;
+            self.promote(candidate);
+        }
+    }
+
+    /// Chooses the next pair to drop. The caller removes the pair from the
+    /// shard and then passes the node to [`Tracker::forget`].
+    pub(crate) fn victim(&mut self) -> Option<usize> {
+        if self.len() == 0 {
+            return None;
+        }
+        let victim = match self.policy {
+            EvictionPolicy::Lru | EvictionPolicy::NoEviction => self.second_chance(),
+            EvictionPolicy::Lfu => self.least_frequent(),
+            EvictionPolicy::Sieve => self.sieve(),
+            EvictionPolicy::TinyLfu => self.admission(),
+        };
+        Some(victim)
+    }
+
+    fn window_capacity(&self) -> usize {
+        (self.len() / 100).max(1)
+    }
+
+    /// Oldest pair in the main area that hasn't been used since it was last
+    /// given a second chance.
+    fn second_chance(&mut self) -> usize {
+        // every pass clears a visited bit, so this ends within two laps
+        loop {
+            let oldest = self.main.oldest;
+            if self.nodes[oldest].hits.swap(0, Relaxed) == 0 {
+                return oldest;
+            }
+            self.unlink(oldest);
+            self.link_newest(oldest);
+        }
+    }
+
+    fn least_frequent(&mut self) -> usize {
+        if self.saturated.swap(false, Relaxed) {
+            for node in &mut self.nodes {
+                *node.hits.get_mut() /= 2;
+            }
+        }
+
+        let mut sampled = Vec::with_capacity(LFU_SAMPLES);
+        let mut node = self.main.oldest;
+        while node != NIL && sampled.len() < LFU_SAMPLES {
+            sampled.push(node);
+            node = self.nodes[node].newer;
+        }
+
+        let victim = *sampled
+            .iter()
+            .min_by_key(|&&node| self.nodes[node].hits.load(Relaxed))
+            .expect("main list is not empty");
+
+        // survivors go to the back of the queue, so that the next sample sees other pairs
+        for &node in &sampled {
+            if node != victim {
+                self.unlink(node);
+                self.link_newest(node);
+            }
+        }
+        victim
+    }
+
+    fn sieve(&mut self) -> usize {
+        let mut node = self.hand;
+        loop {
+            if node == NIL {
+                node = self.main.oldest;
+            }
+            if self.nodes[node].hits.swap(0, Relaxed) == 0 {
+                self.hand = self.nodes[node].newer;
+                return node;
+            }
+            node = self.nodes[node].newer;
+        }
+    }
+
+    /// The oldest pair in the window competes with the main area's victim
+    /// for a place. Ties go to the pair that is already in the main area.
+    fn admission(&mut self) -> usize {
+        if self.window.len <= self.window_capacity() || self.main.len == 0 {
+            return match self.main.len {
+                0 => self.window.oldest,
+                _ => self.second_chance(),
+            };
+        }
+
+        let candidate = self.window.oldest;
+        let victim = self.second_chance();
+        let sketch = self.sketch.as_ref().expect("TinyLFU has a sketch");
+        let candidate_frequency = sketch.frequency(self.nodes[candidate].hidden_key);
+        let victim_frequency = sketch.frequency(self.nodes[victim].hidden_key);
+
+        if candidate_frequency > victim_frequency {
+            self.promote(candidate);
+            victim
+        } else {
+            candidate
+        }
+    }
+
+    /// Moves a pair from the window into the main area
+    fn promote(&mut self, node: usize) {
+        self.unlink(node);
+        self.nodes[node].in_window = false;
+        self.link_newest(node);
+    }
+
+    fn list_mut(&mut self, node: usize) -> &mut List {
+        if self.nodes[node].in_window {
+            &mut self.window
+        } else {
+            &mut self.main
+        }
+    }
+
+    fn link_newest(&mut self, node: usize) {
+        let mut list = *self.list_mut(node);
+        self.nodes[node].older = list.newest;
+        self.nodes[node].newer = NIL;
+        if list.newest != NIL {
+            self.nodes[list.newest].newer = node;
+        } else {
+            list.oldest = node;
+        }
+        list.newest = node;
+        list.len += 1;
+        *self.list_mut(node) = list;
+    }
+
+    fn unlink(&mut self, node: usize) {
+        let Node { newer, older, .. } = self.nodes[node];
+        let mut list = *self.list_mut(node);
+        if newer != NIL {
+            self.nodes[newer].older = older;
+        } else {
+            list.newest = older;
+        }
+        if older != NIL {
+            self.nodes[older].newer = newer;
+        } else {
+            list.oldest = newer;
+        }
+        list.len -= 1;
+        *self.list_mut(node) = list;
+    }
+}
+
+/// Count-min sketch with 4-bit counters, as used by TinyLFU to estimate how
+/// often each key has been requested recently.
+#[derive(Debug)]
+struct Sketch {
+    counters: Vec<AtomicU8>,
+    mask: usize,
+    additions: AtomicUsize,
+    /// Once this many increments have been made, every counter is halved
+    sample_size: usize,
+}
+
+impl Sketch {
+    const DEPTH: usize = 4;
+    const MAX_COUNT: u8 = 15;
+    const SEEDS: [u64; Sketch::DEPTH] = [
+        0x9e37_79b9_7f4a_7c15,
+        0xc2b2_ae3d_27d4_eb4f,
+        0x1656_67b1_9e37_79f9,
+        0xd6e8_feb8_6659_fd93,
+    ];
+
+    fn new(capacity: usize) -> Sketch {
+        let width = capacity.max(16).next_power_of_two();
+        Sketch {
+            counters: (0..width * Sketch::DEPTH)
+                .map(|_| AtomicU8::new(0))
+                .collect(),
+            mask: width - 1,
+            additions: AtomicUsize::new(0),
+            sample_size: capacity.max(16) * 10,
+        }
+    }
+
+    /// HiddenKeys can come from weak hashers, so each row remixes them
+    /// with murmur3's finalizer
+    #[inline]
+    fn slot(&self, hidden_key: HiddenKey, row: usize) -> usize {
+        let mut hash = hidden_key.0 ^ Sketch::SEEDS[row];
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^= hash >> 33;
+        row * (self.mask + 1) + (hash as usize & self.mask)
+    }
+
+    fn increment(&self, hidden_key: HiddenKey) {
+        for row in 0..Sketch::DEPTH {
+            let counter = &self.counters[self.slot(hidden_key, row)];
+            // concurrent readers may lose an increment, which a sketch tolerates
+            let count = counter.load(Relaxed);
+            if count < Sketch::MAX_COUNT {
+                counter.store(count + 1, Relaxed);
+            }
+        }
+        self.additions.fetch_add(1, Relaxed);
+    }
+
+    fn frequency(&self, hidden_key: HiddenKey) -> u8 {
+        (0..Sketch::DEPTH)
+            .map(|row| self.counters[self.slot(hidden_key, row)].load(Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Halves every counter once enough increments have been made, so that
+    /// the sketch follows changes in popularity.
+    fn age(&mut self) {
+        if *self.additions.get_mut() < self.sample_size {
+            return;
+        }
+        for counter in &mut self.counters {
+            *counter.get_mut() /= 2;
+        }
+        *self.additions.get_mut() /= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CornerStore, Error};
+
+    /// xorshift64, to keep traces reproducible without a dependency
+    struct Rng(u64);
+
+    impl Rng {
+        fn next_f64(&mut self) -> f64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 11) as f64 / (1u64 << 53) as f64
+        }
+    }
+
+    /// Draws keys in `0..n` where key k has probability proportional to 1/(k+1)^s
+    struct Zipf {
+        cumulative: Vec<f64>,
+    }
+
+    impl Zipf {
+        fn new(n: usize, s: f64) -> Zipf {
+            let mut total = 0.0;
+            let mut cumulative = Vec::with_capacity(n);
+            for k in 0..n {
+                total += 1.0 / ((k + 1) as f64).powf(s);
+                cumulative.push(total);
+            }
+            for c in &mut cumulative {
+                *c /= total;
+            }
+            Zipf { cumulative }
+        }
+
+        fn sample(&self, rng: &mut Rng) -> usize {
+            let u = rng.next_f64();
+            self.cumulative.partition_point(|&c| c < u)
+        }
+    }
+
+    /// Replays a Zipf trace against a store bounded to `capacity` entries,
+    /// inserting on every miss. Returns the hit ratio.
+    ///
+    /// With `scan`, every request is followed by one for a key that is never
+    /// requested again. Those requests don't count towards the hit ratio.
+    fn hit_ratio(
+        policy: EvictionPolicy,
+        capacity: usize,
+        keys: usize,
+        seed: u64,
+        scan: bool,
+    ) -> f64 {
+        let store = CornerStore::builder()
+            .max_entries(capacity)
+            .eviction_policy(policy)
+            .build();
+        let zipf = Zipf::new(keys, 0.9);
+        let mut rng = Rng(seed);
+
+        let requests = 200_000;
+        let mut hits = 0;
+        for i in 0..requests {
+            let key = (zipf.sample(&mut rng) as u64).to_le_bytes();
+            if store.get(&key).unwrap().is_some() {
+                hits += 1;
+            } else {
+                store.set(&key, b"value", None).unwrap();
+            }
+            if scan {
+                let once = format!("scan-{}", i);
+                store.get(once.as_bytes()).unwrap();
+                store.set(once.as_bytes(), b"value", None).unwrap();
+            }
+        }
+        store.debug_assert_invariants();
+        assert!(store.len().unwrap() <= capacity + crate::DEFAULT_SHARDS);
+        hits as f64 / requests as f64
+    }
+
+    #[test]
+    fn test_hit_ratios_on_a_zipf_trace() {
+        let capacity = 4_096;
+        let keys = 100_000;
+
+        let lru = hit_ratio(EvictionPolicy::Lru, capacity, keys, 1, false);
+        let lfu = hit_ratio(EvictionPolicy::Lfu, capacity, keys, 1, false);
+        let tiny_lfu = hit_ratio(EvictionPolicy::TinyLfu, capacity, keys, 1, false);
+        let sieve = hit_ratio(EvictionPolicy::Sieve, capacity, keys, 1, false);
+
+        // Roughly the share of requests that go to the `capacity` most popular keys.
+        // With 4% of the keys, every policy should capture well over a third of them.
+        for (name, ratio) in [
+            ("lru", lru),
+            ("lfu", lfu),
+            ("tiny_lfu", tiny_lfu),
+            ("sieve", sieve),
+        ] {
+            assert!(ratio > 0.35, "{} hit ratio was {:.3}", name, ratio);
+        }
+        // frequency-aware policies beat recency on a skewed, static trace
+        assert!(lfu > lru, "lfu {:.3} <= lru {:.3}", lfu, lru);
+        assert!(tiny_lfu > lru, "tiny_lfu {:.3} <= lru {:.3}", tiny_lfu, lru);
+        assert!(sieve > lru, "sieve {:.3} <= lru {:.3}", sieve, lru);
+    }
+
+    #[test]
+    fn test_tiny_lfu_resists_scans() {
+        let capacity = 4_096;
+        let keys = 100_000;
+
+        let lru = hit_ratio(EvictionPolicy::Lru, capacity, keys, 2, true);
+        let tiny_lfu = hit_ratio(EvictionPolicy::TinyLfu, capacity, keys, 2, true);
+
+        // keys that are only ever seen once should rarely displace popular ones
+        assert!(
+            tiny_lfu > lru + 0.05,
+            "tiny_lfu {:.3}, lru {:.3}",
+            tiny_lfu,
+            lru
+        );
+    }
+
+    #[test]
+    fn test_entry_limit_is_enforced_per_shard() {
+        for policy in [
+            EvictionPolicy::Lru,
+            EvictionPolicy::Lfu,
+            EvictionPolicy::TinyLfu,
+            EvictionPolicy::Sieve,
+        ] {
+            let store = CornerStore::builder()
+                .max_entries(crate::DEFAULT_SHARDS * 2)
+                .eviction_policy(policy)
+                .build();
+            for i in 0..10_000u32 {
+                store.set(&i.to_le_bytes(), b"value", None).unwrap();
+                store.get(&(i / 2).to_le_bytes()).unwrap();
+            }
+            assert!(store.len().unwrap() <= crate::DEFAULT_SHARDS * 2, "{:?}", policy);
+            store.debug_assert_invariants();
+        }
+    }
+
+    #[test]
+    fn test_byte_limit_is_enforced() {
+        let store = CornerStore::builder()
+            .max_bytes(crate::DEFAULT_SHARDS * 100)
+            .eviction_policy(EvictionPolicy::Sieve)
+            .build();
+        for i in 0..10_000u32 {
+            store.set(&i.to_le_bytes(), &[0; 46], None).unwrap();
+        }
+        // 4 byte keys + 46 byte values: no more than two pairs fit in each shard
+        assert!(store.len().unwrap() <= crate::DEFAULT_SHARDS * 2);
+
+        let result = store.set(b"too big", &[0; 100], None);
+        assert!(matches!(result, Err(Error::ValueTooLarge)));
+    }
+
+    #[test]
+    fn test_memory_limit_is_enforced() {
+        let limit = 1024 * 1024;
+        let store = CornerStore::builder()
+            .max_memory(limit)
+            .eviction_policy(EvictionPolicy::Lfu)
+            .build();
+        for i in 0..10_000u32 {
+            store.set(&i.to_le_bytes(), &[0; 400], None).unwrap();
+        }
+        assert!(store.len().unwrap() < 10_000);
+        assert!(store.memory_usage().unwrap() <= limit + crate::DEFAULT_SHARDS);
+        store.debug_assert_invariants();
+
+        let result = store.set(b"too big", &[0; 10_000], None);
+        assert!(matches!(result, Err(Error::ValueTooLarge)));
+    }
+
+    #[test]
+    fn test_no_eviction_reports_out_of_memory() {
+        let store = CornerStore::builder()
+            .max_memory(1024 * 1024)
+            .eviction_policy(EvictionPolicy::NoEviction)
+            .build();
+
+        let mut result = Ok(0);
+        for i in 0..10_000u32 {
+            result = store.set(&i.to_le_bytes(), &[0; 400], None);
+            if result.is_err() {
+                break;
+            }
+        }
+        assert!(matches!(result, Err(Error::OutOfMemory)));
+        assert!(store.memory_usage().unwrap() <= 1024 * 1024 + crate::DEFAULT_SHARDS);
+    }
+
+    #[test]
+    fn test_no_eviction_refuses_writes_when_full() {
+        let store = CornerStore::builder()
+            .max_entries(crate::DEFAULT_SHARDS)
+            .eviction_policy(EvictionPolicy::NoEviction)
+            .build();
+
+        let mut refused = None;
+        for i in 0..10_000u32 {
+            if let Err(err) = store.set(&i.to_le_bytes(), b"value", None) {
+                refused = Some((i, err));
+                break;
+            }
+        }
+
+        let (i, err) = refused.expect("a shard should have filled up");
+        assert!(matches!(err, Error::CapacityExceeded));
+        assert_eq!(store.get(&i.to_le_bytes()).unwrap(), None);
+        // overwriting an existing key doesn't need more room
+        store.set(&0u32.to_le_bytes(), b"other", None).unwrap();
+    }
+}
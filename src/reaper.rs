@@ -0,0 +1,264 @@
+//! Background eviction of expired items
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::BoH;
+
+/// What the background reaper has done so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReaperStats {
+    /// Number of times the reaper has run
+    pub runs: u64,
+
+    /// Key/value pairs removed by the most recent run
+    pub last_reclaimed: usize,
+
+    /// Key/value pairs removed by every run so far
+    pub total_reclaimed: u64,
+
+    /// How long the most recent run took
+    pub last_duration: Duration,
+
+    /// How long the reaper waits between runs. Only changes for an adaptive reaper.
+    pub interval: Duration,
+}
+
+/// When the reaper runs
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Schedule {
+    Fixed(Duration),
+    Adaptive { min: Duration, max: Duration },
+}
+
+impl Schedule {
+    fn initial(&self) -> Duration {
+        match *self {
+            Schedule::Fixed(interval) => interval,
+            Schedule::Adaptive { min, .. } => min,
+        }
+    }
+
+    /// Like Redis' active expiry cycle, works harder while a large share of
+    /// the items that it looks at have expired.
+    fn next(&self, interval: Duration, reclaimed: usize, remaining: usize) -> Duration {
+        match *self {
+            Schedule::Fixed(interval) => interval,
+            Schedule::Adaptive { min, max } => {
+                if reclaimed * 4 > reclaimed + remaining {
+                    (interval / 2).max(min)
+                } else if reclaimed == 0 {
+                    (interval * 2).min(max)
+                } else {
+                    interval
+                }
+            }
+        }
+    }
+}
+
+/// Handle to the reaper thread. The thread only holds a weak reference to the
+/// store, and stops once the store's last handle is dropped.
+#[derive(Debug)]
+pub(crate) struct Reaper {
+    /// Never sent on. Dropping it wakes the thread up so that it can exit.
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    stats: Arc<Mutex<ReaperStats>>,
+}
+
+impl Reaper {
+    pub(crate) fn start(boh: &Arc<BoH>, schedule: Schedule) -> Reaper {
+        let (stop, stopped) = mpsc::channel();
+        let stats = Arc::new(Mutex::new(ReaperStats {
+            interval: schedule.initial(),
+            ..ReaperStats::default()
+        }));
+
+        let thread = {
+            let boh = Arc::downgrade(boh);
+            let stats = stats.clone();
+            thread::Builder::new()
+                .name("cornerstore-reaper".to_string())
+                .spawn(move || run(boh, schedule, stopped, stats))
+                .expect("failed to spawn reaper thread")
+        };
+
+        Reaper {
+            stop: Some(stop),
+            thread: Some(thread),
+            stats,
+        }
+    }
+
+    pub(crate) fn stats(&self) -> ReaperStats {
+        *self.stats.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            // the reaper itself drops the last handle if every other handle
+            // went away while it was running
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+fn run(boh: Weak<BoH>, schedule: Schedule, stopped: Receiver<()>, stats: Arc<Mutex<ReaperStats>>) {
+    let mut interval = schedule.initial();
+
+    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+        let boh = match boh.upgrade() {
+            Some(boh) => boh,
+            None => break,
+        };
+
+        let started = Instant::now();
+        let now = boh.clock.now();
+        let mut reclaimed = 0;
+        let mut remaining = 0;
+        for shard in 0..boh.data.len() {
+            // poisoned shards are left alone until CornerStore::recover is called
+            if let Ok((evicted, left)) = boh.evict_shard(shard, now) {
+                reclaimed += evicted;
+                remaining += left;
+            }
+        }
+        drop(boh);
+
+        interval = schedule.next(interval, reclaimed, remaining);
+
+        let mut stats = stats.lock().unwrap_or_else(PoisonError::into_inner);
+        stats.runs += 1;
+        stats.last_reclaimed = reclaimed;
+        stats.total_reclaimed += reclaimed as u64;
+        stats.last_duration = started.elapsed();
+        stats.interval = interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CornerStore, ManualClock};
+
+    /// Polls until the reaper has finished at least `runs` runs
+    fn wait_for_runs(store: &CornerStore, runs: u64) -> ReaperStats {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let stats = store.reaper_stats().unwrap();
+            if stats.runs >= runs || Instant::now() > deadline {
+                return stats;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_reaper_evicts_expired_items() {
+        let store = CornerStore::builder()
+            .reaper(Duration::from_millis(10))
+            .build();
+        let past = Instant::now() - Duration::from_secs(1);
+        for i in 0..100u32 {
+            store.set(&i.to_le_bytes(), b"value", Some(past)).unwrap();
+        }
+        store.set(b"greeting", b"hello", None).unwrap();
+
+        let stats = wait_for_runs(&store, 2);
+
+        assert_eq!(stats.total_reclaimed, 100);
+        assert_eq!(store.get_unchecked(&0u32.to_le_bytes()).unwrap(), None);
+        assert_eq!(store.get(b"greeting").unwrap().as_deref(), Some(&b"hello"[..]));
+        store.debug_assert_invariants();
+    }
+
+    #[test]
+    fn test_reaper_follows_the_store_clock() {
+        let clock = ManualClock::new();
+        let store = CornerStore::builder()
+            .clock(clock.clone())
+            .reaper(Duration::from_millis(10))
+            .build();
+        store.set_with_ttl(b"greeting", b"hello", Duration::from_secs(60)).unwrap();
+
+        let stats = wait_for_runs(&store, 2);
+        assert_eq!(stats.total_reclaimed, 0);
+
+        clock.advance(Duration::from_secs(61));
+        let stats = wait_for_runs(&store, stats.runs + 2);
+        assert_eq!(stats.total_reclaimed, 1);
+        assert!(store.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_adaptive_reaper_backs_off_when_nothing_expires() {
+        let min = Duration::from_millis(1);
+        let max = Duration::from_millis(8);
+        let store = CornerStore::builder().adaptive_reaper(min, max).build();
+
+        let stats = wait_for_runs(&store, 5);
+
+        assert_eq!(stats.interval, max);
+    }
+
+    #[test]
+    #[should_panic(expected = "must not be zero")]
+    fn test_reaper_intervals_must_not_be_zero() {
+        CornerStore::builder().reaper(Duration::ZERO);
+    }
+
+    #[test]
+    #[should_panic(expected = "must not be zero")]
+    fn test_adaptive_reaper_intervals_must_not_be_zero() {
+        CornerStore::builder().adaptive_reaper(Duration::ZERO, Duration::from_secs(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "longer than its maximum")]
+    fn test_adaptive_reaper_intervals_must_be_in_order() {
+        CornerStore::builder().adaptive_reaper(Duration::from_secs(2), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_adaptive_schedule_speeds_up_while_items_expire() {
+        let schedule = Schedule::Adaptive {
+            min: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+        };
+        let interval = Duration::from_millis(100);
+
+        assert_eq!(schedule.next(interval, 50, 50), Duration::from_millis(50));
+        assert_eq!(schedule.next(interval, 1, 99), interval);
+        assert_eq!(schedule.next(interval, 0, 99), Duration::from_millis(200));
+        assert_eq!(
+            schedule.next(Duration::from_millis(10), 99, 1),
+            Duration::from_millis(10)
+        );
+    }
+
+    #[test]
+    fn test_reaper_stops_when_last_handle_is_dropped() {
+        let store = CornerStore::builder()
+            .reaper(Duration::from_secs(3600))
+            .build();
+        let clone = store.clone();
+        let boh = Arc::downgrade(&store.0);
+
+        drop(store);
+        assert!(boh.upgrade().is_some());
+
+        // would block for an hour if the reaper only noticed on its next run
+        let started = Instant::now();
+        drop(clone);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(boh.upgrade().is_none());
+    }
+}
@@ -0,0 +1,222 @@
+//! Serving stale values while they are refreshed in the background
+
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+
+use crate::{BoH, Value};
+
+/// Whether a value returned by
+/// [`CornerStore::get_with_freshness`](crate::CornerStore::get_with_freshness)
+/// is within its soft TTL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// The value has not reached its soft TTL
+    Fresh,
+
+    /// The value is past its soft TTL, but has not expired. It can still be
+    /// served, but should be replaced soon.
+    Stale,
+}
+
+type Hook = dyn Fn(&[u8]) -> Option<Value> + Send + Sync;
+
+/// Produces a new value for a stale key, or `None` to keep serving the stale one
+#[derive(Clone)]
+pub(crate) struct RefreshHook(Arc<Hook>);
+
+impl RefreshHook {
+    pub(crate) fn new<V: Into<Value>>(
+        hook: impl Fn(&[u8]) -> Option<V> + Send + Sync + 'static,
+    ) -> Self {
+        RefreshHook(Arc::new(move |key| hook(key).map(Into::into)))
+    }
+}
+
+impl fmt::Debug for RefreshHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RefreshHook")
+    }
+}
+
+/// Handle to the refresh thread. Like the reaper, the thread only holds a
+/// weak reference to the store, and stops once the store is dropped.
+#[derive(Debug)]
+pub(crate) struct Refresher {
+    /// Keys to refresh. Dropping it lets the thread exit.
+    queue: Option<Sender<Value>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Refresher {
+    pub(crate) fn start(boh: &Arc<BoH>, hook: RefreshHook) -> Refresher {
+        let (queue, requests) = mpsc::channel();
+
+        let thread = {
+            let boh = Arc::downgrade(boh);
+            thread::Builder::new()
+                .name("cornerstore-refresh".to_string())
+                .spawn(move || run(boh, hook, requests))
+                .expect("failed to spawn refresh thread")
+        };
+
+        Refresher {
+            queue: Some(queue),
+            thread: Some(thread),
+        }
+    }
+
+    /// Asks for key to be refreshed. Callers make sure that each stale pair
+    /// is only requested once.
+    pub(crate) fn request(&self, key: Value) {
+        if let Some(queue) = &self.queue {
+            // only fails if the thread has exited, in which case the store is going away
+            let _ = queue.send(key);
+        }
+    }
+}
+
+impl Drop for Refresher {
+    fn drop(&mut self) {
+        self.queue.take();
+        if let Some(thread) = self.thread.take() {
+            // the refresh thread drops the last handle if every other handle
+            // went away while it was refreshing
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+fn run(boh: Weak<BoH>, hook: RefreshHook, requests: Receiver<Value>) {
+    for key in requests {
+        if boh.strong_count() == 0 {
+            break;
+        }
+        // the hook runs without holding the store, so that it can't keep it alive
+        let value = (hook.0)(&key);
+
+        let boh = match boh.upgrade() {
+            Some(boh) => boh,
+            None => break,
+        };
+        // poisoned shards are left alone until CornerStore::recover is called
+        let _ = boh.finish_refresh(&key, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CornerStore;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_values_go_stale_before_they_expire() {
+        let store = CornerStore::new();
+        store
+            .set_with_soft_ttl(
+                b"greeting",
+                b"hello",
+                Duration::ZERO,
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        store
+            .set_with_soft_ttl(
+                b"farewell",
+                b"goodbye",
+                Duration::from_secs(60),
+                Duration::from_secs(120),
+            )
+            .unwrap();
+        store
+            .set_with_soft_ttl(b"question", b"why?", Duration::ZERO, Duration::ZERO)
+            .unwrap();
+
+        let (value, freshness) = store.get_with_freshness(b"greeting").unwrap().unwrap();
+        assert_eq!((&*value, freshness), (&b"hello"[..], Freshness::Stale));
+        let (_, freshness) = store.get_with_freshness(b"farewell").unwrap().unwrap();
+        assert_eq!(freshness, Freshness::Fresh);
+        assert_eq!(store.get_with_freshness(b"question").unwrap(), None);
+
+        // plain reads keep serving stale values
+        assert_eq!(
+            store.get(b"greeting").unwrap().as_deref(),
+            Some(&b"hello"[..])
+        );
+        store.debug_assert_invariants();
+    }
+
+    #[test]
+    fn test_stale_reads_trigger_a_single_refresh() {
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let store = CornerStore::builder()
+            .refresh_ahead({
+                let refreshes = refreshes.clone();
+                move |key| {
+                    thread::sleep(Duration::from_millis(50));
+                    let n = refreshes.fetch_add(1, Ordering::SeqCst);
+                    Some(format!("{}-{}", String::from_utf8_lossy(key), n).into_bytes())
+                }
+            })
+            .build();
+        store
+            .set_with_soft_ttl(
+                b"greeting",
+                b"hello",
+                Duration::from_millis(500),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        thread::sleep(Duration::from_millis(500));
+
+        // every read while the refresh is running gets the stale value
+        for _ in 0..100 {
+            let (value, freshness) = store.get_with_freshness(b"greeting").unwrap().unwrap();
+            assert_eq!((&*value, freshness), (&b"hello"[..], Freshness::Stale));
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while store.get(b"greeting").unwrap().as_deref() == Some(&b"hello"[..]) {
+            assert!(Instant::now() < deadline, "value was never refreshed");
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+        let (value, freshness) = store.get_with_freshness(b"greeting").unwrap().unwrap();
+        assert_eq!((&*value, freshness), (&b"greeting-0"[..], Freshness::Fresh));
+        assert!(store.ttl(b"greeting").unwrap().unwrap() > Duration::from_secs(50));
+        store.debug_assert_invariants();
+    }
+
+    #[test]
+    fn test_refresh_does_not_overwrite_newer_writes() {
+        let store = CornerStore::builder()
+            .refresh_ahead(|_: &[u8]| {
+                thread::sleep(Duration::from_millis(50));
+                Some(b"refreshed".to_vec())
+            })
+            .build();
+        store
+            .set_with_soft_ttl(
+                b"greeting",
+                b"hello",
+                Duration::ZERO,
+                Duration::from_secs(60),
+            )
+            .unwrap();
+
+        store.get(b"greeting").unwrap();
+        store.set(b"greeting", b"kia ora", None).unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        assert_eq!(
+            store.get(b"greeting").unwrap().as_deref(),
+            Some(&b"kia ora"[..])
+        );
+    }
+}
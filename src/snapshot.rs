@@ -0,0 +1,532 @@
+//! Saving the store to a file, and loading it back
+//!
+//! A snapshot is a header, a record for each live pair, and a trailer.
+//! Integers are little-endian.
+//!
+//! ```text
+//! header  = "CNRSNAP\0" version:u32
+//! record  = kind:u8 key_len:u64 value_len:u64
+//!           [expires_at:u64 ttl:u64]     if kind & 1
+//!           [stale_at:u64 soft_ttl:u64]  if kind & 2
+//!           key value
+//! trailer = 0xff count:u64 crc:u32
+//! ```
+//!
+//! Times are milliseconds since the Unix epoch, so that they mean the same
+//! thing to the process that loads the snapshot. TTLs are milliseconds, or
+//! all ones for none. The CRC-32 covers everything before it.
+
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::clock::Epoch;
+use crate::{BoH, Error, KeyValuePair, Result, Value};
+
+/// Identifies snapshot files
+const MAGIC: &[u8; 8] = b"CNRSNAP\0";
+
+/// Changes whenever the format does. Snapshots in other formats are refused.
+const VERSION: u32 = 1;
+
+/// Record kinds. A record's kind says which optional fields it has.
+const EXPIRES: u8 = 1;
+const GOES_STALE: u8 = 2;
+const END: u8 = 0xff;
+
+/// Stands in for a missing TTL
+const NO_TTL: u64 = u64::MAX;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+/// CRC-32, as used by zlib and PNG
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub(crate) fn new() -> Self {
+        Crc32(!0)
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = CRC_TABLE[((self.0 ^ u32::from(byte)) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub(crate) fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+/// Passes reads or writes through, keeping a checksum of the bytes
+struct Checked<T> {
+    inner: T,
+    crc: Crc32,
+}
+
+impl<T> Checked<T> {
+    fn new(inner: T) -> Self {
+        Checked {
+            inner,
+            crc: Crc32::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for Checked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Milliseconds since the Unix epoch
+fn wall_millis(epoch: Epoch, instant: Instant) -> u64 {
+    millis(epoch.to_wall(instant).duration_since(UNIX_EPOCH).unwrap_or_default())
+}
+
+/// `None` if the time has passed, and `Some(None)` if it is too far off to represent
+fn wall_instant(epoch: Epoch, since_unix_epoch: u64) -> Option<Option<Instant>> {
+    match UNIX_EPOCH.checked_add(Duration::from_millis(since_unix_epoch)) {
+        Some(wall) => epoch.to_instant(wall),
+        None => Some(None),
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+fn ttl_millis(ttl: Option<Duration>) -> u64 {
+    ttl.map_or(NO_TTL, |ttl| millis(ttl).min(NO_TTL - 1))
+}
+
+fn ttl_duration(ttl: u64) -> Option<Duration> {
+    Some(ttl)
+        .filter(|&ttl| ttl != NO_TTL)
+        .map(Duration::from_millis)
+}
+
+/// A pair as it is saved, with wall-clock times
+pub(crate) struct Record {
+    pub(crate) key: Value,
+    value: Value,
+    /// When the pair expires, and its TTL
+    expires: Option<(u64, u64)>,
+    /// When the pair goes stale, and its soft TTL
+    goes_stale: Option<(u64, u64)>,
+}
+
+impl Record {
+    pub(crate) fn new(kv_pair: &KeyValuePair, epoch: Epoch) -> Record {
+        Record {
+            key: kv_pair.key.clone(),
+            value: kv_pair.value.clone(),
+            expires: kv_pair
+                .expiry
+                .map(|expiry| (wall_millis(epoch, expiry), ttl_millis(kv_pair.ttl))),
+            goes_stale: kv_pair
+                .stale_at
+                .map(|stale_at| (wall_millis(epoch, stale_at), ttl_millis(kv_pair.soft_ttl))),
+        }
+    }
+
+    /// Just the pair's key and lifetimes, with an empty value
+    pub(crate) fn schedule(kv_pair: &KeyValuePair, epoch: Epoch) -> Record {
+        Record {
+            value: Value::from(&[][..]),
+            ..Record::new(kv_pair, epoch)
+        }
+    }
+
+    /// The pair to store, or `None` if it has expired since it was saved
+    pub(crate) fn into_pair(self, epoch: Epoch) -> Option<KeyValuePair> {
+        // times too far off to represent never come
+        let (expiry, ttl) = match self.expires {
+            Some((expires_at, ttl)) => match wall_instant(epoch, expires_at)? {
+                Some(expiry) => (Some(expiry), ttl_duration(ttl)),
+                None => (None, None),
+            },
+            None => (None, None),
+        };
+        let kv_pair = match self.goes_stale {
+            // stale times that have passed are kept as now, so that the pair is still stale
+            Some((stale_at, soft_ttl)) => match wall_instant(epoch, stale_at).unwrap_or(Some(epoch.instant)) {
+                Some(stale_at) => KeyValuePair {
+                    stale_at: Some(stale_at),
+                    soft_ttl: ttl_duration(soft_ttl),
+                    ..KeyValuePair::new(self.key, self.value, expiry, ttl)
+                },
+                None => KeyValuePair::new(self.key, self.value, expiry, ttl),
+            },
+            None => KeyValuePair::new(self.key, self.value, expiry, ttl),
+        };
+        Some(kv_pair).filter(|kv_pair| !kv_pair.is_expired(epoch.instant))
+    }
+
+    pub(crate) fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut kind = 0;
+        if self.expires.is_some() {
+            kind |= EXPIRES;
+        }
+        if self.goes_stale.is_some() {
+            kind |= GOES_STALE;
+        }
+        out.write_all(&[kind])?;
+        out.write_all(&(self.key.len() as u64).to_le_bytes())?;
+        out.write_all(&(self.value.len() as u64).to_le_bytes())?;
+        for (at, ttl) in self.expires.iter().chain(&self.goes_stale) {
+            out.write_all(&at.to_le_bytes())?;
+            out.write_all(&ttl.to_le_bytes())?;
+        }
+        out.write_all(&self.key)?;
+        out.write_all(&self.value)
+    }
+
+    /// Reads the rest of a record, after its kind
+    pub(crate) fn read(kind: u8, input: &mut impl Read) -> Result<Record> {
+        if kind & !(EXPIRES | GOES_STALE) != 0 {
+            return Err(Error::Corrupt("unknown record kind"));
+        }
+        let key_len = read_u64(input)?;
+        let value_len = read_u64(input)?;
+        let mut times = || -> io::Result<(u64, u64)> { Ok((read_u64(input)?, read_u64(input)?)) };
+        let expires = if kind & EXPIRES != 0 {
+            Some(times()?)
+        } else {
+            None
+        };
+        let goes_stale = if kind & GOES_STALE != 0 {
+            Some(times()?)
+        } else {
+            None
+        };
+        Ok(Record {
+            key: read_bytes(input, key_len)?,
+            value: read_bytes(input, value_len)?,
+            expires,
+            goes_stale,
+        })
+    }
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Reads `len` bytes, without trusting `len` enough to allocate it up front
+fn read_bytes(input: &mut impl Read, len: u64) -> io::Result<Value> {
+    let mut buf = Vec::new();
+    input.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Value::from(buf))
+}
+
+/// `path` with `suffix` added to its file name
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Writes a snapshot to a temporary file beside `path`, then moves it into place
+pub(crate) fn save(boh: &BoH, path: &Path) -> Result<usize> {
+    let temporary = with_suffix(path, ".tmp");
+
+    let saved = write(boh, &temporary).and_then(|count| {
+        fs::rename(&temporary, path)?;
+        sync_parent(path)?;
+        Ok(count)
+    });
+    if saved.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    saved
+}
+
+fn write(boh: &BoH, path: &Path) -> Result<usize> {
+    let mut out = Checked::new(BufWriter::new(File::create(path)?));
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+
+    let mut count: u64 = 0;
+    let mut records = Vec::new();
+    for shard in 0..boh.data.len() {
+        // copy the shard's pairs out first, so that it is only locked for as long as that takes
+        let epoch = Epoch::of(&*boh.clock);
+        records.extend(
+            boh.read_shard(shard)?
+                .entries
+                .values()
+                .flatten()
+                .filter(|kv_pair| !kv_pair.is_expired(epoch.instant))
+                .map(|kv_pair| Record::new(kv_pair, epoch)),
+        );
+        for record in records.drain(..) {
+            record.write(&mut out)?;
+            count += 1;
+        }
+    }
+
+    out.write_all(&[END])?;
+    out.write_all(&count.to_le_bytes())?;
+    let crc = out.crc.finish();
+    let mut file = out
+        .inner
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    file.write_all(&crc.to_le_bytes())?;
+    file.sync_all()?;
+    Ok(count as usize)
+}
+
+/// Makes a rename into the directory that holds `path` durable
+#[cfg(unix)]
+pub(crate) fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Reads and checks the whole snapshot at `path`, then stores its pairs
+pub(crate) fn load(boh: &BoH, path: &Path) -> Result<usize> {
+    let records = read(BufReader::new(File::open(path)?)).map_err(|err| match err {
+        Error::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            Error::Corrupt("snapshot is truncated")
+        }
+        err => err,
+    })?;
+
+    let epoch = Epoch::of(&*boh.clock);
+    let mut loaded = 0;
+    for kv_pair in records
+        .into_iter()
+        .filter_map(|record| record.into_pair(epoch))
+    {
+        let hidden_key = boh.hidden_key(&kv_pair.key);
+        let mut lock = boh.write_shard(boh.shard(hidden_key))?;
+        boh.store(&mut lock, hidden_key, kv_pair)?;
+        loaded += 1;
+    }
+    Ok(loaded)
+}
+
+fn read(input: impl Read) -> Result<Vec<Record>> {
+    let mut input = Checked::new(input);
+
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::Corrupt("not a snapshot"));
+    }
+    if read_u32(&mut input)? != VERSION {
+        return Err(Error::Corrupt("unsupported snapshot version"));
+    }
+
+    let mut records = Vec::new();
+    loop {
+        let mut kind = [0];
+        input.read_exact(&mut kind)?;
+        if kind[0] == END {
+            break;
+        }
+        records.push(Record::read(kind[0], &mut input)?);
+    }
+
+    let count = read_u64(&mut input)?;
+    let crc = input.crc.finish();
+    if read_u32(&mut input.inner)? != crc {
+        return Err(Error::Corrupt("checksum mismatch"));
+    }
+    if count != records.len() as u64 {
+        return Err(Error::Corrupt("record count mismatch"));
+    }
+    if input.inner.read(&mut [0])? != 0 {
+        return Err(Error::Corrupt("unexpected data after the trailer"));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CornerStore, Freshness};
+    use std::thread;
+
+    /// A path in the temporary directory that no other test uses
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "cornerstore-{}-{}.snapshot",
+            std::process::id(),
+            name
+        ))
+    }
+
+    #[test]
+    fn test_crc_matches_the_standard_check_value() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_snapshots_keep_values_and_lifetimes() {
+        let path = temp_path("round-trip");
+        let store = CornerStore::new();
+        let minute = Duration::from_secs(60);
+        store.set(b"greeting", b"hello", None).unwrap();
+        store.set_with_ttl(b"farewell", b"goodbye", minute).unwrap();
+        store
+            .set_with_soft_ttl(b"question", b"why?", Duration::from_secs(10), minute)
+            .unwrap();
+        store.set(b"stale", b"old news", None).unwrap();
+        store.invalidate(b"stale").unwrap();
+        store
+            .set(
+                b"expired",
+                b"gone",
+                Some(Instant::now() - Duration::from_secs(1)),
+            )
+            .unwrap();
+        assert_eq!(store.save_snapshot(&path).unwrap(), 4);
+
+        let loaded = CornerStore::new();
+        assert_eq!(loaded.load_snapshot(&path).unwrap(), 4);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(&*loaded.get(b"greeting").unwrap().unwrap(), b"hello");
+        assert_eq!(loaded.ttl(b"greeting").unwrap(), None);
+        let ttl = loaded.ttl(b"farewell").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(58) && ttl <= minute, "{:?}", ttl);
+        let (_, freshness) = loaded.get_with_freshness(b"question").unwrap().unwrap();
+        assert_eq!(freshness, Freshness::Fresh);
+        let (_, freshness) = loaded.get_with_freshness(b"stale").unwrap().unwrap();
+        assert_eq!(freshness, Freshness::Stale);
+        assert_eq!(loaded.get(b"expired").unwrap(), None);
+        loaded.debug_assert_invariants();
+
+        // touch still knows the original TTL
+        thread::sleep(Duration::from_millis(20));
+        assert!(loaded.touch(b"farewell").unwrap());
+        assert!(loaded.ttl(b"farewell").unwrap().unwrap() > ttl - Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_pairs_that_expire_before_loading_are_skipped() {
+        let path = temp_path("expiring");
+        let store = CornerStore::new();
+        store.set(b"greeting", b"hello", None).unwrap();
+        store
+            .set_with_ttl(b"farewell", b"goodbye", Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(store.save_snapshot(&path).unwrap(), 2);
+
+        thread::sleep(Duration::from_millis(100));
+        let loaded = CornerStore::new();
+        assert_eq!(loaded.load_snapshot(&path).unwrap(), 1);
+        assert_eq!(loaded.len().unwrap(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_damaged_snapshots_are_refused_without_loading_anything() {
+        let path = temp_path("damaged");
+        let store = CornerStore::new();
+        for i in 0..20 {
+            store
+                .set(format!("key:{}", i).as_bytes(), b"value", None)
+                .unwrap();
+        }
+        store.save_snapshot(&path).unwrap();
+        let snapshot = fs::read(&path).unwrap();
+
+        let mut damaged = Vec::new();
+        for len in [0, 4, 12, 13, 50, snapshot.len() - 5, snapshot.len() - 1] {
+            damaged.push(snapshot[..len].to_vec());
+        }
+        for at in [0, 9, 40, snapshot.len() / 2, snapshot.len() - 1] {
+            let mut flipped = snapshot.clone();
+            flipped[at] ^= 0x10;
+            damaged.push(flipped);
+        }
+        let mut longer = snapshot.clone();
+        longer.push(0);
+        damaged.push(longer);
+
+        for bytes in damaged {
+            fs::write(&path, &bytes).unwrap();
+            let loaded = CornerStore::new();
+            match loaded.load_snapshot(&path) {
+                Err(Error::Corrupt(_)) => {}
+                other => panic!("loaded {} bytes as {:?}", bytes.len(), other),
+            }
+            assert!(loaded.is_empty().unwrap());
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_saves_leave_nothing_behind() {
+        let path = temp_path("missing-directory").join("snapshot");
+        match CornerStore::new().save_snapshot(&path) {
+            Err(Error::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            CornerStore::new().load_snapshot(&path),
+            Err(Error::Io(_))
+        ));
+    }
+}
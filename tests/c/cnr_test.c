@@ -0,0 +1,65 @@
+/* Exercises the C API of libcornerstore. Built and run by `make ctest`. */
+
+#include <assert.h>
+#include <errno.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "cornerstore.h"
+
+#define STR(s) (const uint8_t *)(s), strlen(s)
+
+static void test_round_trip(void) {
+    CornerStore *store = cnr_init();
+    assert(store != NULL);
+
+    assert(cnr_set(store, STR("greeting"), STR("hello"), NULL) == CNR_OK);
+
+    uint8_t *buf = NULL;
+    size_t len = 0;
+    assert(cnr_get(store, STR("greeting"), &buf, &len) == CNR_OK);
+    assert(len == 5);
+    assert(memcmp(buf, "hello", len) == 0);
+    cnr_buf_free(buf, len);
+
+    assert(cnr_remove(store, STR("greeting")) == CNR_OK);
+    assert(cnr_get(store, STR("greeting"), &buf, &len) == ENOENT);
+
+    cnr_free(store);
+}
+
+static void test_expiry(void) {
+    CornerStore *store = cnr_init();
+    int64_t ttl_ms = 0;
+
+    assert(cnr_set(store, STR("greeting"), STR("hello"), &ttl_ms) == CNR_OK);
+    assert(cnr_evict(store) == CNR_OK);
+
+    uint8_t *buf = NULL;
+    size_t len = 0;
+    assert(cnr_get(store, STR("greeting"), &buf, &len) == ENOENT);
+
+    cnr_free(store);
+}
+
+static void test_invalid_arguments(void) {
+    CornerStore *store = cnr_init();
+    int64_t negative_ttl = -1;
+
+    assert(cnr_set(store, NULL, 0, STR("hello"), NULL) == EINVAL);
+    assert(cnr_set(store, STR("greeting"), NULL, 5, NULL) == EINVAL);
+    assert(cnr_set(store, STR("greeting"), STR("hello"), &negative_ttl) == EINVAL);
+    assert(cnr_get(store, STR("greeting"), NULL, NULL) == EINVAL);
+    assert(cnr_evict(NULL) == EINVAL);
+
+    cnr_free(store);
+    cnr_free(NULL);
+}
+
+int main(void) {
+    test_round_trip();
+    test_expiry();
+    test_invalid_arguments();
+    printf("cnr_test: ok\n");
+    return 0;
+}
@@ -0,0 +1,31 @@
+//! Compares CornerStore's own memory accounting with what jemalloc reports
+#![cfg(feature = "jemalloc")]
+
+use cornerstore::CornerStore;
+use jemalloc_ctl::{epoch, stats};
+
+fn allocated() -> usize {
+    epoch::advance().unwrap();
+    stats::allocated::read().unwrap()
+}
+
+#[test]
+fn memory_usage_agrees_with_the_allocator() {
+    let store = CornerStore::with_capacity(50_000);
+    let before = (allocated(), store.memory_usage().unwrap());
+
+    for i in 0..50_000u32 {
+        store.set(&i.to_le_bytes(), &[0; 1000], None).unwrap();
+    }
+
+    let allocated = allocated() - before.0;
+    let counted = store.memory_usage().unwrap() - before.1;
+    // jemalloc rounds allocations up to its size classes, which the store doesn't count
+    let ratio = counted as f64 / allocated as f64;
+    assert!(
+        (0.8..1.1).contains(&ratio),
+        "counted {} bytes, jemalloc allocated {}",
+        counted,
+        allocated
+    );
+}
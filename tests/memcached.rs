@@ -0,0 +1,181 @@
+//! Talks to cornerstore-server over loopback, the way a memcached client does.
+
+use std::io::{Read, Write};
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+use common::{Client, Server};
+
+impl Client {
+    fn call(&mut self, request: &str) -> String {
+        self.send(request);
+        self.line()
+    }
+
+    /// Sends a get or gets, and returns the VALUE lines and data blocks
+    fn get(&mut self, request: &str) -> Vec<(String, Vec<u8>)> {
+        self.send(request);
+        let mut values = Vec::new();
+        loop {
+            let header = self.line();
+            if header == "END" {
+                return values;
+            }
+            let len: usize = header.split(' ').nth(3).unwrap().parse().unwrap();
+            let mut data = vec![0; len + 2];
+            self.reader.read_exact(&mut data).unwrap();
+            data.truncate(len);
+            values.push((header, data));
+        }
+    }
+}
+
+#[test]
+fn test_storage_and_retrieval() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+
+    assert_eq!(client.call("set greeting 42 0 5\r\nhello\r\n"), "STORED");
+    assert_eq!(client.call("add greeting 0 0 3\r\nhey\r\n"), "NOT_STORED");
+    assert_eq!(client.call("append greeting 0 0 6\r\n world\r\n"), "STORED");
+    assert_eq!(
+        client.get("get greeting missing\r\n"),
+        vec![("VALUE greeting 42 11".to_string(), b"hello world".to_vec())]
+    );
+
+    let values = client.get("gets greeting\r\n");
+    let cas = values[0].0.split(' ').nth(4).unwrap().to_string();
+    assert_eq!(
+        client.call(&format!("cas greeting 0 0 7 {}\r\nkia ora\r\n", cas)),
+        "STORED"
+    );
+    assert_eq!(
+        client.call(&format!("cas greeting 0 0 3 {}\r\nhey\r\n", cas)),
+        "EXISTS"
+    );
+
+    assert_eq!(client.call("delete greeting\r\n"), "DELETED");
+    assert_eq!(client.call("delete greeting\r\n"), "NOT_FOUND");
+}
+
+#[test]
+fn test_exptime_expires_items() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+
+    assert_eq!(client.call("set greeting 0 1 5\r\nhello\r\n"), "STORED");
+    assert_eq!(client.call("set farewell 0 0 7\r\ngoodbye\r\n"), "STORED");
+    assert_eq!(client.call("touch farewell 1\r\n"), "TOUCHED");
+    assert_eq!(client.get("get greeting farewell\r\n").len(), 2);
+
+    thread::sleep(Duration::from_millis(1100));
+    assert!(client.get("get greeting farewell\r\n").is_empty());
+
+    assert_eq!(client.call("set greeting 0 0 5\r\nhello\r\n"), "STORED");
+    assert_eq!(client.call("flush_all\r\n"), "OK");
+    assert!(client.get("get greeting\r\n").is_empty());
+}
+
+#[test]
+fn test_concurrent_increments_are_not_lost() {
+    let server = Server::start(&[]);
+    assert_eq!(
+        server.connect().call("set counter 0 0 1\r\n0\r\n"),
+        "STORED"
+    );
+
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let mut client = server.connect();
+            thread::spawn(move || {
+                for _ in 0..250 {
+                    client.call("incr counter 1\r\n");
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(server.connect().call("incr counter 0\r\n"), "1000");
+}
+
+#[test]
+fn test_stats_count_commands() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+    client.call("set greeting 0 0 5\r\nhello\r\n");
+    client.get("get greeting missing\r\n");
+
+    client.send("stats\r\n");
+    let mut stats = Vec::new();
+    loop {
+        let line = client.line();
+        if line == "END" {
+            break;
+        }
+        stats.push(line);
+    }
+    for expected in [
+        "STAT cmd_get 2",
+        "STAT get_hits 1",
+        "STAT get_misses 1",
+        "STAT curr_items 1",
+    ] {
+        assert!(
+            stats.iter().any(|line| line == expected),
+            "{:?} not in {:?}",
+            expected,
+            stats
+        );
+    }
+}
+
+#[test]
+fn test_meta_commands_share_the_connection() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+
+    assert_eq!(client.call("ms greeting 5 T60 O1\r\nhello\r\n"), "HD O1");
+    assert_eq!(client.call("md greeting I\r\n"), "HD");
+    assert_eq!(client.call("mg greeting s\r\n"), "HD s5 W X");
+    assert_eq!(client.call("mg greeting s\r\n"), "HD s5 Z X");
+    assert_eq!(
+        client.get("get greeting\r\n"),
+        vec![("VALUE greeting 0 5".to_string(), b"hello".to_vec())]
+    );
+}
+
+#[test]
+fn test_binary_clients_are_recognised() {
+    let server = Server::start(&[]);
+    let mut client = server.connect();
+
+    // a binary protocol noop, then a version request
+    let request = |opcode: u8| {
+        let mut header = [0; 24];
+        header[0] = 0x80;
+        header[1] = opcode;
+        header
+    };
+    client.writer.write_all(&request(0x0a)).unwrap();
+    client.writer.write_all(&request(0x0b)).unwrap();
+
+    let mut noop = [0; 24];
+    client.reader.read_exact(&mut noop).unwrap();
+    assert_eq!((noop[0], noop[1]), (0x81, 0x0a));
+
+    let mut version = [0; 24];
+    client.reader.read_exact(&mut version).unwrap();
+    assert_eq!(
+        (version[0], version[1], &version[6..8]),
+        (0x81, 0x0b, &[0, 0][..])
+    );
+    let len = u32::from_be_bytes([version[8], version[9], version[10], version[11]]);
+    let mut body = vec![0; len as usize];
+    client.reader.read_exact(&mut body).unwrap();
+    assert_eq!(body, env!("CARGO_PKG_VERSION").as_bytes());
+}
@@ -0,0 +1,192 @@
+//! Talks to cornerstore-server over loopback, the way a Redis client does.
+
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+use common::{Client, Server};
+
+/// A decoded reply
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Client {
+    fn command(&mut self, args: &[&str]) -> Reply {
+        self.send(&encode(args));
+        self.reply()
+    }
+
+    fn reply(&mut self) -> Reply {
+        let line = self.line();
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Reply::Simple(rest.to_string()),
+            "-" => Reply::Error(rest.to_string()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "_" => Reply::Null,
+            "$" if rest == "-1" => Reply::Null,
+            "$" => {
+                let mut data = vec![0; rest.parse::<usize>().unwrap() + 2];
+                self.reader.read_exact(&mut data).unwrap();
+                data.truncate(data.len() - 2);
+                Reply::Bulk(data)
+            }
+            "*" => Reply::Array((0..rest.parse().unwrap()).map(|_| self.reply()).collect()),
+            "%" => Reply::Map(
+                (0..rest.parse().unwrap())
+                    .map(|_| (self.reply(), self.reply()))
+                    .collect(),
+            ),
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    }
+}
+
+fn encode(args: &[&str]) -> String {
+    let mut out = format!("*{}\r\n", args.len());
+    for arg in args {
+        out.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    out
+}
+
+fn bulk(text: &str) -> Reply {
+    Reply::Bulk(text.as_bytes().to_vec())
+}
+
+fn ok() -> Reply {
+    Reply::Simple("OK".to_string())
+}
+
+#[test]
+fn test_string_commands() {
+    let server = Server::start(&["--protocol", "resp"]);
+    let mut client = server.connect();
+
+    assert_eq!(client.command(&["PING"]), Reply::Simple("PONG".into()));
+    assert_eq!(client.command(&["SET", "greeting", "hello"]), ok());
+    assert_eq!(
+        client.command(&["SET", "greeting", "hey", "NX"]),
+        Reply::Null
+    );
+    assert_eq!(client.command(&["GET", "greeting"]), bulk("hello"));
+    assert_eq!(client.command(&["MSET", "a", "1", "b", "2"]), ok());
+    assert_eq!(
+        client.command(&["MGET", "a", "missing", "b"]),
+        Reply::Array(vec![bulk("1"), Reply::Null, bulk("2")])
+    );
+    assert_eq!(client.command(&["INCRBY", "a", "41"]), Reply::Integer(42));
+    assert_eq!(
+        client.command(&["EXISTS", "a", "b", "missing"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(client.command(&["DEL", "a", "missing"]), Reply::Integer(1));
+    assert_eq!(client.command(&["FLUSHDB"]), ok());
+    assert_eq!(client.command(&["GET", "greeting"]), Reply::Null);
+}
+
+#[test]
+fn test_expiry_commands() {
+    let server = Server::start(&["--protocol", "resp"]);
+    let mut client = server.connect();
+
+    assert_eq!(
+        client.command(&["SET", "greeting", "hello", "PX", "500"]),
+        ok()
+    );
+    assert_eq!(
+        client.command(&["SET", "farewell", "goodbye", "EX", "60"]),
+        ok()
+    );
+    assert_eq!(client.command(&["TTL", "farewell"]), Reply::Integer(60));
+    assert_eq!(client.command(&["PERSIST", "farewell"]), Reply::Integer(1));
+    assert_eq!(client.command(&["TTL", "farewell"]), Reply::Integer(-1));
+    assert_eq!(
+        client.command(&["EXPIRE", "farewell", "100"]),
+        Reply::Integer(1)
+    );
+    assert_eq!(client.command(&["DBSIZE"]), Reply::Integer(2));
+
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(client.command(&["GET", "greeting"]), Reply::Null);
+    assert_eq!(client.command(&["TTL", "greeting"]), Reply::Integer(-2));
+}
+
+#[test]
+fn test_pipelined_commands_are_answered_in_order() {
+    let server = Server::start(&["--protocol", "resp"]);
+    let mut client = server.connect();
+
+    let batch: String = (0..100)
+        .map(|i| encode(&["SET", &format!("key:{}", i), &i.to_string()]))
+        .chain((0..100).map(|i| encode(&["GET", &format!("key:{}", i)])))
+        .collect();
+    client.send(&batch);
+
+    for _ in 0..100 {
+        assert_eq!(client.reply(), ok());
+    }
+    for i in 0..100 {
+        assert_eq!(client.reply(), bulk(&i.to_string()));
+    }
+}
+
+#[test]
+fn test_scan_visits_every_key() {
+    let server = Server::start(&["--protocol", "resp"]);
+    let mut client = server.connect();
+    for i in 0..500 {
+        client.command(&["SET", &format!("user:{}", i), "x"]);
+    }
+    client.command(&["SET", "other", "x"]);
+
+    let mut keys = Vec::new();
+    let mut cursor = "0".to_string();
+    loop {
+        let reply = client.command(&["SCAN", &cursor, "MATCH", "user:*", "COUNT", "50"]);
+        let mut parts = match reply {
+            Reply::Array(parts) => parts,
+            other => panic!("{:?}", other),
+        };
+        match (parts.remove(0), parts.remove(0)) {
+            (Reply::Bulk(next), Reply::Array(batch)) => {
+                keys.extend(batch);
+                cursor = String::from_utf8(next).unwrap();
+            }
+            other => panic!("{:?}", other),
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(keys.len(), 500);
+}
+
+#[test]
+fn test_hello_negotiates_resp3() {
+    let server = Server::start(&["--protocol", "resp"]);
+    let mut client = server.connect();
+
+    let hello = match client.command(&["HELLO", "3"]) {
+        Reply::Map(hello) => hello,
+        other => panic!("{:?}", other),
+    };
+    assert!(hello.contains(&(bulk("proto"), Reply::Integer(3))));
+    assert_eq!(client.command(&["GET", "missing"]), Reply::Null);
+
+    let info = match client.command(&["INFO", "keyspace"]) {
+        Reply::Bulk(info) => String::from_utf8(info).unwrap(),
+        other => panic!("{:?}", other),
+    };
+    assert!(info.contains("db0:keys=0"), "{}", info);
+}
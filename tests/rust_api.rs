@@ -0,0 +1,11 @@
+//! Uses cornerstore the way a downstream crate does, through the rlib.
+
+use cornerstore::CornerStore;
+
+#[test]
+fn test_store_is_usable_from_another_crate() {
+    let store = CornerStore::new();
+    store.set(b"greeting", b"hello", None).unwrap();
+
+    assert_eq!(store.get(b"greeting").unwrap().as_deref(), Some(&b"hello"[..]));
+}